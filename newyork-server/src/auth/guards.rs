@@ -1,14 +1,44 @@
+use std::fmt;
+
 use rocket::http::Status;
 use rocket::outcome::Outcome;
 use rocket::request::{self, FromRequest, Request};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::telemetry::redact::REDACTED;
+use crate::telemetry::request_id::RequestId;
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct AuthPayload {
     pub token: String,
     pub user_id: String,
+    #[serde(default)]
+    pub request_id: String,
+}
+
+impl fmt::Debug for AuthPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AuthPayload")
+            .field("token", &REDACTED)
+            .field("user_id", &self.user_id)
+            .field("request_id", &self.request_id)
+            .finish()
+    }
 }
 const TOKEN_TYPE: &str = "Bearer";
 
+/// Error returned when the token of a request is not valid for its user.
+/// Such requests are not audited, their `user_id` is not trusted.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Unauthenticated(pub String);
+
+impl fmt::Display for Unauthenticated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Unauthenticated: {}", self.0)
+    }
+}
+
+impl std::error::Error for Unauthenticated {}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for AuthPayload {
     type Error = ();
@@ -31,7 +61,7 @@ impl<'r> FromRequest<'r> for AuthPayload {
         let token = header_parts.next().unwrap_or("");
         let user_id: &str = request.headers().get_one("user_id").unwrap_or("");
 
-        debug!("Auth token - user id: {} - {}", token, user_id);
+        debug!("Auth request from user id {}", user_id);
 
         if token.is_empty() || user_id.is_empty() {
             return Outcome::Failure((Status::Unauthorized, ()));
@@ -40,6 +70,7 @@ impl<'r> FromRequest<'r> for AuthPayload {
         Outcome::Success(AuthPayload {
             token: token.to_owned(),
             user_id: user_id.to_owned(),
+            request_id: RequestId::of(request).0,
         })
     }
 }
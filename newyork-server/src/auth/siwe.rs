@@ -0,0 +1,487 @@
+//! Sign-In with Ethereum (EIP-4361), for wallet logins without HCMC.
+//!
+//! The server hands out a single-use nonce, the client signs a SIWE message
+//! carrying it with `personal_sign`, and the server checks the message and
+//! its signature before issuing a session token of its own. Session tokens
+//! start with [`SESSION_PREFIX`] and `validate_auth_token` checks them
+//! against the sessions stored here instead of asking HCMC.
+//!
+//! Nonces carry their expiry and a MAC with `siwe.nonce_key`, so handing
+//! them out stores nothing. Only the nonces of correctly signed messages are
+//! recorded, to be used once. Used nonces and sessions are purged once
+//! expired.
+
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::sha2::Sha256;
+use rocket::fairing::AdHoc;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use uuid::Uuid;
+use web3::signing::keccak256;
+use web3::types::Address;
+
+use crate::eth::messages;
+use crate::storage::db::{self, MPCStruct, DB};
+use crate::utils::settings::SiweSettings;
+use crate::AppConfig;
+
+pub const SESSION_PREFIX: &str = "siwe.";
+/// User id of a wallet login, followed by its address
+pub const USER_PREFIX: &str = "siwe:";
+/// Sessions are kept apart from the data of every user
+const SESSIONS_USER: &str = "siwe";
+const HEADER: &str = " wants you to sign in with your Ethereum account:";
+const VERSION: &str = "1";
+/// Hex length of the random part and of the expiry of a nonce, before its MAC
+const NONCE_RANDOM_LEN: usize = 16;
+const NONCE_EXPIRY_LEN: usize = 16;
+const EXPIRIES_ID: &str = "expiries";
+
+lazy_static! {
+    /// Serializes changes to the expiries, which are read, changed and written back
+    static ref EXPIRIES_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Debug)]
+pub enum SiweStruct {
+    Session,
+    /// Expiry of a nonce signed in with
+    UsedNonce,
+    /// Every used nonce and session not yet purged, with its expiry
+    Expiries,
+}
+
+impl MPCStruct for SiweStruct {
+    fn to_string(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+/// A parsed EIP-4361 message.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SiweMessage {
+    /// Without the optional scheme
+    pub domain: String,
+    pub address: Address,
+    pub statement: Option<String>,
+    pub uri: String,
+    pub version: String,
+    pub chain_id: u64,
+    pub nonce: String,
+    pub issued_at: OffsetDateTime,
+    pub expiration_time: Option<OffsetDateTime>,
+    pub not_before: Option<OffsetDateTime>,
+    pub request_id: Option<String>,
+    pub resources: Vec<String>,
+}
+
+/// Takes the value of the first line of `lines` when it starts with `tag`.
+fn field<'a>(lines: &mut &[&'a str], tag: &str) -> Option<&'a str> {
+    let value = lines.first()?.strip_prefix(tag)?;
+    *lines = &lines[1..];
+    Some(value)
+}
+
+fn required<'a>(lines: &mut &[&'a str], tag: &str) -> Result<&'a str> {
+    field(lines, tag).ok_or_else(|| anyhow!("{} is missing", tag.trim_end_matches(": ")))
+}
+
+fn parse_time(text: &str) -> Result<OffsetDateTime> {
+    OffsetDateTime::parse(text, &Rfc3339).map_err(|e| anyhow!("Invalid time {} ({})", text, e))
+}
+
+/// `address` in EIP-55 mixed case.
+pub fn checksum(address: Address) -> String {
+    let hex = hex::encode(address.as_bytes());
+    let hash = keccak256(hex.as_bytes());
+    let mut checksummed = String::from("0x");
+    for (i, c) in hex.chars().enumerate() {
+        let nibble = if i % 2 == 0 {
+            hash[i / 2] >> 4
+        } else {
+            hash[i / 2] & 0x0f
+        };
+        checksummed.push(if nibble >= 8 {
+            c.to_ascii_uppercase()
+        } else {
+            c
+        });
+    }
+    checksummed
+}
+
+fn parse_address(text: &str) -> Result<Address> {
+    let bytes = text
+        .strip_prefix("0x")
+        .and_then(|hex| hex::decode(hex).ok())
+        .filter(|bytes| bytes.len() == 20)
+        .ok_or_else(|| anyhow!("Invalid address {}", text))?;
+    let address = Address::from_slice(&bytes);
+    if checksum(address) != text {
+        bail!("Address {} is not EIP-55 checksummed", text);
+    }
+    Ok(address)
+}
+
+impl FromStr for SiweMessage {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let lines: Vec<&str> = text.split('\n').collect();
+        let mut lines = lines.as_slice();
+
+        let domain = lines
+            .first()
+            .and_then(|line| line.strip_suffix(HEADER))
+            .ok_or_else(|| anyhow!("Not a Sign-In with Ethereum message"))?;
+        let domain = match domain.split_once("://") {
+            Some((_, domain)) => domain,
+            None => domain,
+        };
+        let address = parse_address(lines.get(1).copied().unwrap_or_default())?;
+        lines = &lines[2.min(lines.len())..];
+
+        let mut before_uri = Vec::new();
+        let uri = loop {
+            match lines.first() {
+                Some(line) => {
+                    lines = &lines[1..];
+                    match line.strip_prefix("URI: ") {
+                        Some(uri) => break uri,
+                        None => before_uri.push(*line),
+                    }
+                }
+                None => bail!("URI is missing"),
+            }
+        };
+        let statement = match before_uri.as_slice() {
+            [] | ["", ""] => None,
+            ["", statement, ""] if !statement.is_empty() => Some(statement.to_string()),
+            _ => bail!("Invalid statement"),
+        };
+
+        let version = required(&mut lines, "Version: ")?;
+        if version != VERSION {
+            bail!("Version {} is not supported", version);
+        }
+        let chain_id = required(&mut lines, "Chain ID: ")?
+            .parse()
+            .map_err(|_| anyhow!("Invalid chain id"))?;
+        let nonce = required(&mut lines, "Nonce: ")?;
+        if nonce.len() < 8 || !nonce.chars().all(|c| c.is_ascii_alphanumeric()) {
+            bail!("Invalid nonce {}", nonce);
+        }
+        let issued_at = parse_time(required(&mut lines, "Issued At: ")?)?;
+        let expiration_time = field(&mut lines, "Expiration Time: ")
+            .map(parse_time)
+            .transpose()?;
+        let not_before = field(&mut lines, "Not Before: ")
+            .map(parse_time)
+            .transpose()?;
+        let request_id = field(&mut lines, "Request ID: ").map(String::from);
+        let mut resources = Vec::new();
+        if field(&mut lines, "Resources:") == Some("") {
+            while let Some(resource) = field(&mut lines, "- ") {
+                resources.push(resource.to_string());
+            }
+        }
+        if let Some(line) = lines.first() {
+            bail!("Unexpected line '{}'", line);
+        }
+
+        Ok(SiweMessage {
+            domain: domain.to_string(),
+            address,
+            statement,
+            uri: uri.to_string(),
+            version: version.to_string(),
+            chain_id,
+            nonce: nonce.to_string(),
+            issued_at,
+            expiration_time,
+            not_before,
+            request_id,
+            resources,
+        })
+    }
+}
+
+impl SiweMessage {
+    /// Checks the message is meant for `domain` and valid at `now`, in Unix
+    /// seconds.
+    pub fn check(&self, domain: &str, now: u64) -> Result<()> {
+        if self.domain != domain {
+            bail!("The message is for {}, not {}", self.domain, domain);
+        }
+        let now = now as i64;
+        if matches!(self.expiration_time, Some(t) if t.unix_timestamp() <= now) {
+            bail!("The message has expired");
+        }
+        if matches!(self.not_before, Some(t) if t.unix_timestamp() > now) {
+            bail!("The message is not valid yet");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Session {
+    pub user_id: String,
+    pub address: Address,
+    /// Unix time in seconds
+    pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+struct Expiries {
+    nonces: Vec<(String, u64)>,
+    /// By hashed token
+    sessions: Vec<(String, u64)>,
+}
+
+fn update_expiries<T>(db: &DB, update: impl FnOnce(&mut Expiries) -> T) -> Result<T> {
+    let _guard = EXPIRIES_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut expiries: Expiries =
+        db::get(db, SESSIONS_USER, EXPIRIES_ID, &SiweStruct::Expiries)?.unwrap_or_default();
+    let result = update(&mut expiries);
+    db::insert(
+        db,
+        SESSIONS_USER,
+        EXPIRIES_ID,
+        &SiweStruct::Expiries,
+        &expiries,
+    )?;
+    Ok(result)
+}
+
+/// Takes the ids of `entries` expired at `now`.
+fn take_expired(entries: &mut Vec<(String, u64)>, now: u64) -> Vec<String> {
+    let (expired, live) = entries
+        .drain(..)
+        .partition::<Vec<_>, _>(|(_, expires_at)| *expires_at <= now);
+    *entries = live;
+    expired.into_iter().map(|(id, _)| id).collect()
+}
+
+/// Removes the used nonces and the sessions expired at `now`. An expired
+/// nonce is refused anyway, so it no longer needs to be recorded.
+pub fn purge_expired(db: &DB, now: u64) -> Result<usize> {
+    let (nonces, sessions) = update_expiries(db, |expiries| {
+        (
+            take_expired(&mut expiries.nonces, now),
+            take_expired(&mut expiries.sessions, now),
+        )
+    })?;
+    for nonce in &nonces {
+        db::remove(db, SESSIONS_USER, nonce, &SiweStruct::UsedNonce)?;
+    }
+    for key in &sessions {
+        db::remove(db, SESSIONS_USER, key, &SiweStruct::Session)?;
+    }
+    Ok(nonces.len() + sessions.len())
+}
+
+/// Purges expired nonces and sessions every `interval`.
+pub fn fairing(interval: Duration) -> AdHoc {
+    AdHoc::on_liftoff("Sign-In with Ethereum cleanup", move |rocket| {
+        Box::pin(async move {
+            let db: Arc<DB> = match rocket.state::<AppConfig>() {
+                Some(config) => config.db.clone(),
+                None => return,
+            };
+
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    match now().and_then(|now| purge_expired(&db, now)) {
+                        Ok(0) => {}
+                        Ok(purged) => debug!("Purged {} expired SIWE nonces and sessions", purged),
+                        Err(e) => warn!("Failed to purge SIWE nonces and sessions: {:#}", e),
+                    }
+                }
+            });
+        })
+    })
+}
+
+pub fn user_id(address: Address) -> String {
+    format!("{}{:?}", USER_PREFIX, address)
+}
+
+pub fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Tokens are stored hashed, so the database does not hold usable tokens.
+fn token_key(token: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(token);
+    hasher.result_str()
+}
+
+/// A message whose signature [`SiweAuth::verify`] checked.
+pub struct Verified<'a> {
+    message: &'a SiweMessage,
+}
+
+impl Verified<'_> {
+    pub fn address(&self) -> Address {
+        self.message.address
+    }
+}
+
+/// Hands out signed nonces, and keeps the sessions of signed in wallets in
+/// `storage::db`.
+pub struct SiweAuth {
+    nonce_key: Vec<u8>,
+    /// Held while a nonce is checked and recorded as used
+    used: Mutex<()>,
+}
+
+impl SiweAuth {
+    /// Signs nonces with `siwe.nonce_key`, or with a random key when it is
+    /// not set. Nonces of a random key do not survive a restart and are
+    /// refused by the other instances.
+    pub fn new(settings: &SiweSettings) -> Result<Self> {
+        let nonce_key = match &settings.nonce_key {
+            Some(key) => hex::decode(key).map_err(|e| anyhow!("Invalid siwe.nonce_key ({})", e))?,
+            None => [*Uuid::new_v4().as_bytes(), *Uuid::new_v4().as_bytes()].concat(),
+        };
+        Ok(SiweAuth {
+            nonce_key,
+            used: Mutex::new(()),
+        })
+    }
+
+    fn nonce_mac(&self, payload: &str) -> MacResult {
+        let mut mac = Hmac::new(Sha256::new(), &self.nonce_key);
+        mac.input(payload.as_bytes());
+        mac.result()
+    }
+
+    /// A nonce valid for `ttl`, and when it expires.
+    pub fn issue_nonce(&self, ttl: Duration, now: u64) -> (String, u64) {
+        let expires_at = now + ttl.as_secs();
+        let random = Uuid::new_v4().to_simple().to_string();
+        let payload = format!("{}{:016x}", &random[..NONCE_RANDOM_LEN], expires_at);
+        let nonce = format!(
+            "{}{}",
+            payload,
+            hex::encode(self.nonce_mac(&payload).code())
+        );
+        (nonce, expires_at)
+    }
+
+    /// Checks `nonce` was issued here and is not expired at `now`, returns
+    /// its expiry.
+    fn check_nonce(&self, nonce: &str, now: u64) -> Result<u64> {
+        let payload_len = NONCE_RANDOM_LEN + NONCE_EXPIRY_LEN;
+        let (payload, mac) = match (nonce.get(..payload_len), nonce.get(payload_len..)) {
+            (Some(payload), Some(mac)) => (payload, hex::decode(mac).unwrap_or_default()),
+            _ => bail!("Unknown nonce"),
+        };
+        if self.nonce_mac(payload) != MacResult::new(&mac) {
+            bail!("Unknown nonce");
+        }
+        let expires_at = u64::from_str_radix(&payload[NONCE_RANDOM_LEN..], 16)?;
+        if expires_at <= now {
+            bail!("The nonce has expired");
+        }
+        Ok(expires_at)
+    }
+
+    /// Uses up `nonce`, which must be valid and not used before.
+    pub fn take_nonce(&self, db: &DB, nonce: &str, now: u64) -> Result<()> {
+        let expires_at = self.check_nonce(nonce, now)?;
+        let _used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        let used: Option<u64> = db::get(db, SESSIONS_USER, nonce, &SiweStruct::UsedNonce)?;
+        if used.is_some() {
+            bail!("The nonce is already used");
+        }
+        db::insert(db, SESSIONS_USER, nonce, &SiweStruct::UsedNonce, expires_at)?;
+        update_expiries(db, |expiries| {
+            expiries.nonces.push((nonce.to_string(), expires_at))
+        })
+    }
+
+    /// Checks `message`, parsed from `text`, its nonce, and that `signature`
+    /// of `text` is from its address. Stores nothing.
+    pub fn verify<'a>(
+        &self,
+        settings: &SiweSettings,
+        message: &'a SiweMessage,
+        text: &str,
+        signature: &[u8],
+        now: u64,
+    ) -> Result<Verified<'a>> {
+        let domain = settings
+            .domain
+            .as_deref()
+            .ok_or_else(|| anyhow!("Sign-In with Ethereum is disabled"))?;
+        message.check(domain, now)?;
+        self.check_nonce(&message.nonce, now)?;
+        let signer = messages::recover(messages::personal_digest(text.as_bytes()), signature)?;
+        if signer != message.address {
+            bail!(
+                "The message is signed by {:?}, not {:?}",
+                signer,
+                message.address
+            );
+        }
+        Ok(Verified { message })
+    }
+
+    /// Uses up the nonce of a `verified` message and starts a session for
+    /// its address. Returns the session token.
+    pub fn sign_in(
+        &self,
+        db: &DB,
+        settings: &SiweSettings,
+        verified: &Verified,
+        now: u64,
+    ) -> Result<(String, Session)> {
+        self.take_nonce(db, &verified.message.nonce, now)?;
+
+        let token = format!(
+            "{}{}{}",
+            SESSION_PREFIX,
+            Uuid::new_v4().to_simple(),
+            Uuid::new_v4().to_simple()
+        );
+        let session = Session {
+            user_id: user_id(verified.address()),
+            address: verified.address(),
+            expires_at: now + settings.session_ttl_secs,
+        };
+        let key = token_key(&token);
+        db::insert(db, SESSIONS_USER, &key, &SiweStruct::Session, &session)?;
+        update_expiries(db, |expiries| {
+            expiries.sessions.push((key, session.expires_at))
+        })?;
+        Ok((token, session))
+    }
+
+    /// The session of `token`, which must not be expired at `now`.
+    pub fn session(&self, db: &DB, token: &str, now: u64) -> Result<Session> {
+        let key = token_key(token);
+        let session: Session = db::get(db, SESSIONS_USER, &key, &SiweStruct::Session)?
+            .ok_or_else(|| anyhow!("Unknown session"))?;
+        if session.expires_at <= now {
+            db::remove(db, SESSIONS_USER, &key, &SiweStruct::Session)?;
+            bail!("The session has expired");
+        }
+        Ok(session)
+    }
+
+    pub fn sign_out(&self, db: &DB, token: &str) -> Result<()> {
+        db::remove(db, SESSIONS_USER, &token_key(token), &SiweStruct::Session)
+    }
+}
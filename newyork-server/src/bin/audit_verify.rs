@@ -0,0 +1,55 @@
+//! Checks the audit log hash chain for tampering.
+//!
+//! ```bash
+//! audit_verify --db-path ./db
+//! ```
+//!
+//! Prints the hash of the newest event, which can be compared with a copy
+//! kept outside the server, and exits with an error if any event was
+//! modified, removed or reordered.
+
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use rocksdb::Options;
+use server_lib::storage::audit::{self, AUDIT_CF};
+
+fn parse_args() -> Result<PathBuf> {
+    let mut db_path = PathBuf::from("./db");
+    let mut it = env::args().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--db-path" => {
+                db_path = PathBuf::from(
+                    it.next()
+                        .ok_or_else(|| anyhow!("--db-path needs a value"))?,
+                )
+            }
+            _ => return Err(anyhow!("Unknown argument '{}'", arg)),
+        }
+    }
+    Ok(db_path)
+}
+
+fn main() -> Result<()> {
+    let db_path = parse_args()?;
+    // Read-only, so it can run next to the server
+    let db = rocksdb::DB::open_cf_for_read_only(&Options::default(), &db_path, [AUDIT_CF], false)?;
+    let report = audit::verify(&db)?;
+
+    for problem in &report.problems {
+        eprintln!("{}", problem);
+    }
+    println!(
+        "Checked {} audit events, head {}",
+        report.events, report.head_hash
+    );
+    if !report.is_ok() {
+        return Err(anyhow!(
+            "Audit log is inconsistent: {} problems",
+            report.problems.len()
+        ));
+    }
+    Ok(())
+}
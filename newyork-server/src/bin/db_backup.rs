@@ -0,0 +1,106 @@
+//! Admin command for RocksDB backups.
+//!
+//! ```bash
+//! db_backup create  --db-path ./db --backup-dir ./backups
+//! db_backup list    --backup-dir ./backups
+//! db_backup verify  --backup-dir ./backups --id 3
+//! db_backup restore --backup-dir ./backups --db-path ./db [--id 3]
+//! db_backup purge   --backup-dir ./backups --keep 7
+//! ```
+//!
+//! `create` and `restore` need the server to be stopped, RocksDB only allows
+//! a single process to open the database for writing.
+
+use std::env;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use server_lib::storage::{backup, db};
+
+struct Args {
+    command: String,
+    db_path: PathBuf,
+    backup_dir: PathBuf,
+    id: Option<u32>,
+    keep: Option<usize>,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut it = env::args().skip(1);
+    let mut args = Args {
+        command: it.next().ok_or_else(|| {
+            anyhow!("Usage: db_backup <create|list|verify|restore|purge> [options]")
+        })?,
+        db_path: PathBuf::from("./db"),
+        backup_dir: PathBuf::from("./backups"),
+        id: None,
+        keep: None,
+    };
+
+    while let Some(arg) = it.next() {
+        let value = it.next().ok_or_else(|| anyhow!("{} needs a value", arg))?;
+        match arg.as_str() {
+            "--db-path" => args.db_path = PathBuf::from(value),
+            "--backup-dir" => args.backup_dir = PathBuf::from(value),
+            "--id" => args.id = Some(value.parse()?),
+            "--keep" => args.keep = Some(value.parse()?),
+            _ => return Err(anyhow!("Unknown argument '{}'", arg)),
+        }
+    }
+    Ok(args)
+}
+
+fn main() -> Result<()> {
+    let args = parse_args()?;
+
+    match args.command.as_str() {
+        "create" => {
+            let db = db::open(&args.db_path)?;
+            let manifest = backup::create(&db, &args.backup_dir)?;
+            println!(
+                "Created backup {} ({} records, {} bytes)",
+                manifest.backup_id, manifest.records, manifest.size
+            );
+        }
+        "list" => {
+            for manifest in backup::list(&args.backup_dir)? {
+                println!(
+                    "{}\ttimestamp={}\trecords={}\tsize={}",
+                    manifest.backup_id, manifest.timestamp, manifest.records, manifest.size
+                );
+            }
+        }
+        "verify" => {
+            let id = args.id.ok_or_else(|| anyhow!("verify needs --id"))?;
+            let report = backup::verify(&args.backup_dir, id)?;
+            println!(
+                "Backup {}: expected {:?} records, found {}",
+                report.backup_id, report.expected_records, report.actual_records
+            );
+            if !report.is_ok() {
+                return Err(anyhow!("Record count mismatch for backup {}", id));
+            }
+        }
+        "restore" => {
+            backup::restore(&args.backup_dir, args.id, &args.db_path)?;
+            println!(
+                "Restored backup {} into {}",
+                args.id
+                    .map_or_else(|| "latest".to_string(), |id| id.to_string()),
+                args.db_path.display()
+            );
+            println!(
+                "{} records in restored database",
+                backup::count_records(&args.db_path)?
+            );
+        }
+        "purge" => {
+            let keep = args.keep.ok_or_else(|| anyhow!("purge needs --keep"))?;
+            backup::purge(&args.backup_dir, keep)?;
+            println!("Kept the {} most recent backups", keep);
+        }
+        other => return Err(anyhow!("Unknown command '{}'", other)),
+    }
+
+    Ok(())
+}
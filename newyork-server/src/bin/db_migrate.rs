@@ -0,0 +1,145 @@
+//! Rewrites every record of a RocksDB store into the current storage format.
+//!
+//! ```bash
+//! cargo run --bin db_migrate -- --db-path ./db [--encoding cbor|json] [--dry-run] [--verify]
+//! ```
+//!
+//! `--verify` only checks that every record decodes and survives an
+//! encode/decode round-trip; nothing is written. Stop the server before
+//! migrating, RocksDB only allows a single writer process.
+
+use std::env;
+
+use anyhow::{anyhow, Result};
+use rocksdb::{IteratorMode, WriteBatch};
+use server_lib::storage::codec::{self, Encoding, Header, SCHEMA_VERSION};
+use server_lib::storage::db;
+
+const BATCH_SIZE: usize = 1000;
+
+struct Args {
+    db_path: String,
+    encoding: Encoding,
+    dry_run: bool,
+    verify: bool,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut args = Args {
+        db_path: "./db".to_string(),
+        encoding: codec::DEFAULT_ENCODING,
+        dry_run: false,
+        verify: false,
+    };
+
+    let mut it = env::args().skip(1);
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--db-path" => {
+                args.db_path = it
+                    .next()
+                    .ok_or_else(|| anyhow!("--db-path needs a value"))?
+            }
+            "--encoding" => {
+                args.encoding = it
+                    .next()
+                    .ok_or_else(|| anyhow!("--encoding needs a value"))?
+                    .parse()?
+            }
+            "--dry-run" => args.dry_run = true,
+            "--verify" => args.verify = true,
+            _ => return Err(anyhow!("Unknown argument '{}'", arg)),
+        }
+    }
+    Ok(args)
+}
+
+/// Re-encodes `bytes` and checks the result decodes to the same value.
+fn round_trip(bytes: &[u8], encoding: Encoding) -> Result<Vec<u8>> {
+    let value = codec::decode_value(bytes)?;
+    let encoded = codec::encode(&value, encoding)?;
+    if codec::decode_value(&encoded)? != value {
+        return Err(anyhow!("Value changed during {:?} round-trip", encoding));
+    }
+    Ok(encoded)
+}
+
+fn main() -> Result<()> {
+    let args = parse_args()?;
+    let db = db::open(&args.db_path)?;
+    let target = Header {
+        version: SCHEMA_VERSION,
+        encoding: args.encoding,
+    };
+
+    let (mut total, mut rewritten, mut failed) = (0usize, 0usize, 0usize);
+    let mut batch = WriteBatch::default();
+
+    for (key, value) in db.iterator(IteratorMode::Start) {
+        total += 1;
+        let key_str = String::from_utf8_lossy(&key);
+        let header = match codec::header(&value) {
+            Ok(header) => header,
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}: {}", key_str, e);
+                continue;
+            }
+        };
+
+        if args.verify {
+            let encoding = header.map(|h| h.encoding).unwrap_or(Encoding::Json);
+            if let Err(e) = round_trip(&value, encoding) {
+                failed += 1;
+                eprintln!("{}: {}", key_str, e);
+            }
+            continue;
+        }
+
+        if header == Some(target) {
+            continue;
+        }
+
+        match round_trip(&value, args.encoding) {
+            Ok(encoded) => {
+                batch.put(&key, encoded);
+                rewritten += 1;
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}: {}", key_str, e);
+            }
+        }
+
+        if batch.len() >= BATCH_SIZE && !args.dry_run {
+            db.write(std::mem::take(&mut batch))?;
+        }
+    }
+
+    if !args.dry_run && !batch.is_empty() {
+        db.write(batch)?;
+    }
+
+    if args.verify {
+        println!("Verified {} records, {} failed", total, failed);
+    } else {
+        println!(
+            "Scanned {} records, {} {} to {:?} v{}, {} failed",
+            total,
+            rewritten,
+            if args.dry_run {
+                "would be migrated"
+            } else {
+                "migrated"
+            },
+            args.encoding,
+            SCHEMA_VERSION,
+            failed
+        );
+    }
+
+    if failed > 0 {
+        return Err(anyhow!("{} records could not be processed", failed));
+    }
+    Ok(())
+}
@@ -0,0 +1,81 @@
+//! Exact conversion between decimal amounts and base units such as wei.
+
+use anyhow::{anyhow, bail, Result};
+use web3::types::U256;
+
+pub const ETHER_DECIMALS: u32 = 18;
+
+/// Units accepted after an amount of the native currency.
+const ETH_UNITS: &[(&str, u32)] = &[
+    ("wei", 0),
+    ("gwei", 9),
+    ("ether", ETHER_DECIMALS),
+    ("eth", ETHER_DECIMALS),
+];
+
+/// Parses a decimal `amount` of a currency with `decimals` decimals, e.g.
+/// `"1.5"` with 6 decimals is 1500000 base units.
+pub fn parse_units(amount: &str, decimals: u32) -> Result<U256> {
+    let amount = amount.trim();
+    if amount.starts_with('-') {
+        bail!("Amount {} is negative", amount);
+    }
+    let (whole, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    if (whole.is_empty() && fraction.is_empty())
+        || !whole.bytes().all(|c| c.is_ascii_digit())
+        || !fraction.bytes().all(|c| c.is_ascii_digit())
+    {
+        bail!("Amount '{}' is not a decimal number", amount);
+    }
+
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.len() > decimals as usize {
+        bail!("Amount {} has more than {} decimals", amount, decimals);
+    }
+    let digits = format!("{}{:0<width$}", whole, fraction, width = decimals as usize);
+    U256::from_dec_str(&digits).map_err(|_| anyhow!("Amount {} is too large", amount))
+}
+
+/// Formats base units as a decimal amount, the inverse of [`parse_units`].
+pub fn format_units(value: U256, decimals: u32) -> String {
+    let digits = format!(
+        "{:0>width$}",
+        value.to_string(),
+        width = decimals as usize + 1
+    );
+    let (whole, fraction) = digits.split_at(digits.len() - decimals as usize);
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        whole.to_string()
+    } else {
+        format!("{}.{}", whole, fraction)
+    }
+}
+
+/// Parses an amount of the native currency into wei, e.g. `"0.5 ether"`,
+/// `"30 gwei"` or `"1000"`. Amounts without a unit are in wei.
+pub fn parse_eth(amount: &str) -> Result<U256> {
+    parse_native(amount, "eth")
+}
+
+/// Like [`parse_eth`], also accepting the chain's `symbol` for whole units,
+/// e.g. `"2 MATIC"`.
+pub fn parse_native(amount: &str, symbol: &str) -> Result<U256> {
+    let amount = amount.trim();
+    let (number, unit) = amount.split_at(
+        amount
+            .find(|c: char| c.is_ascii_alphabetic())
+            .unwrap_or(amount.len()),
+    );
+    let unit = unit.to_ascii_lowercase();
+    let decimals = match unit.as_str() {
+        "" => 0,
+        unit if unit.eq_ignore_ascii_case(symbol) => ETHER_DECIMALS,
+        unit => ETH_UNITS
+            .iter()
+            .find(|(name, _)| *name == unit)
+            .map(|(_, decimals)| *decimals)
+            .ok_or_else(|| anyhow!("Unknown unit '{}' in amount {}", unit, amount))?,
+    };
+    parse_units(number, decimals)
+}
@@ -0,0 +1,88 @@
+//! Sends signed transactions to every RPC provider of a chain, so one
+//! provider rejecting or dropping a transaction does not lose it.
+
+use anyhow::{anyhow, Result};
+use web3::signing::keccak256;
+use web3::types::{Bytes, H256};
+
+use super::pool::RpcPool;
+
+/// Messages of nodes that already have the transaction in their pool or
+/// chain, which means it was accepted.
+const ALREADY_KNOWN: &[&str] = &[
+    "already known",
+    "known transaction",
+    "already imported",
+    "alreadyknown",
+    "transaction already exists",
+];
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct ProviderResult {
+    /// Scheme and host of the RPC URL
+    pub provider: String,
+    pub accepted: bool,
+    /// The provider already had the transaction
+    pub already_known: bool,
+    pub error: Option<String>,
+}
+
+impl ProviderResult {
+    pub fn new(provider: String, result: Result<H256>) -> Self {
+        match result {
+            Ok(_) => ProviderResult {
+                provider,
+                accepted: true,
+                already_known: false,
+                error: None,
+            },
+            Err(e) => {
+                let error = format!("{:#}", e);
+                let already_known = is_already_known(&error);
+                ProviderResult {
+                    provider,
+                    accepted: already_known,
+                    already_known,
+                    error: Some(error),
+                }
+            }
+        }
+    }
+}
+
+pub fn is_already_known(error: &str) -> bool {
+    let error = error.to_lowercase();
+    ALREADY_KNOWN.iter().any(|known| error.contains(known))
+}
+
+/// Sends `raw_tx` to every endpoint of `chain_id`. Succeeds with the result
+/// of each provider when at least one accepted the transaction.
+pub async fn send(pool: &RpcPool, chain_id: u64, raw_tx: &Bytes) -> Result<Vec<ProviderResult>> {
+    let results: Vec<ProviderResult> = pool
+        .on_every_endpoint(chain_id, |web3| async move {
+            Ok(web3.eth().send_raw_transaction(raw_tx.clone()).await?)
+        })
+        .await?
+        .into_iter()
+        .map(|(provider, result)| ProviderResult::new(provider, result))
+        .collect();
+
+    if results.iter().any(|result| result.accepted) {
+        return Ok(results);
+    }
+    let errors: Vec<String> = results
+        .iter()
+        .map(|result| {
+            format!(
+                "{}: {}",
+                result.provider,
+                result.error.as_deref().unwrap_or("no answer")
+            )
+        })
+        .collect();
+    Err(anyhow!(
+        "No provider accepted transaction {:?} ({})",
+        H256::from(keccak256(&raw_tx.0)),
+        errors.join("; ")
+    ))
+}
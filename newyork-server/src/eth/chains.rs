@@ -0,0 +1,57 @@
+//! The EVM networks the server serves, from `eth.chains`.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+
+use crate::utils::settings::{ChainSettings, EthSettings};
+
+/// Confirmations of the chain described by `eth.rpc_url` alone.
+const DEFAULT_CONFIRMATIONS: u64 = 12;
+
+pub struct ChainRegistry {
+    default_chain_id: u64,
+    chains: BTreeMap<u64, ChainSettings>,
+}
+
+impl ChainRegistry {
+    /// Without `eth.chains`, `eth.rpc_url` is the only chain.
+    pub fn new(settings: &EthSettings) -> Self {
+        let chains = if settings.chains.is_empty() {
+            vec![ChainSettings {
+                chain_id: settings.default_chain_id,
+                name: "default".to_string(),
+                rpc_urls: vec![settings.rpc_url.clone()],
+                broadcast_urls: Vec::new(),
+                native_symbol: "ETH".to_string(),
+                eip1559: true,
+                confirmations: DEFAULT_CONFIRMATIONS,
+            }]
+        } else {
+            settings.chains.clone()
+        };
+        ChainRegistry {
+            default_chain_id: settings.default_chain_id,
+            chains: chains
+                .into_iter()
+                .map(|chain| (chain.chain_id, chain))
+                .collect(),
+        }
+    }
+
+    /// Chain `chain_id`, or the default chain when it is `None`.
+    pub fn get(&self, chain_id: Option<u64>) -> Result<&ChainSettings> {
+        let chain_id = chain_id.unwrap_or(self.default_chain_id);
+        self.chains
+            .get(&chain_id)
+            .ok_or_else(|| anyhow!("Chain {} is not supported", chain_id))
+    }
+
+    pub fn default_chain(&self) -> &ChainSettings {
+        &self.chains[&self.default_chain_id]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ChainSettings> {
+        self.chains.values()
+    }
+}
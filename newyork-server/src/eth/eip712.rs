@@ -0,0 +1,276 @@
+//! EIP-712 typed structured data, hashed by the server so the digest it
+//! co-signs is the one of the document it was shown.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+use web3::signing::keccak256;
+use web3::types::{Address, H256, U256};
+
+use crate::policy::MessageSummary;
+
+const DOMAIN_TYPE: &str = "EIP712Domain";
+/// Fields a domain can have, in the order of the standard, used when the
+/// document does not list the `EIP712Domain` type.
+const DOMAIN_FIELDS: [(&str, &str); 5] = [
+    ("name", "string"),
+    ("version", "string"),
+    ("chainId", "uint256"),
+    ("verifyingContract", "address"),
+    ("salt", "bytes32"),
+];
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Field {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+/// The JSON document of `eth_signTypedData_v4`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct TypedData {
+    pub types: BTreeMap<String, Vec<Field>>,
+    pub primary_type: String,
+    pub domain: Value,
+    pub message: Value,
+}
+
+impl TypedData {
+    /// `keccak256(0x19 0x01 || domainSeparator || hashStruct(message))`
+    pub fn digest(&self) -> Result<H256> {
+        let domain_separator = self.hash_struct(DOMAIN_TYPE, &self.domain)?;
+        let mut encoded = vec![0x19, 0x01];
+        encoded.extend_from_slice(domain_separator.as_bytes());
+        // A domain alone is signed without a message hash
+        if self.primary_type != DOMAIN_TYPE {
+            encoded.extend_from_slice(
+                self.hash_struct(&self.primary_type, &self.message)?
+                    .as_bytes(),
+            );
+        }
+        Ok(keccak256(&encoded).into())
+    }
+
+    pub fn summary(&self) -> Result<MessageSummary> {
+        let field = |name: &str| match &self.domain[name] {
+            Value::Null => None,
+            value => Some(value),
+        };
+        Ok(MessageSummary::TypedData {
+            primary_type: self.primary_type.clone(),
+            name: field("name").and_then(Value::as_str).map(String::from),
+            version: field("version").and_then(Value::as_str).map(String::from),
+            chain_id: field("chainId")
+                .map(|chain_id| {
+                    let chain_id = parse_uint(chain_id, 64)?;
+                    Ok::<_, anyhow::Error>(chain_id.as_u64())
+                })
+                .transpose()?,
+            verifying_contract: field("verifyingContract").map(parse_address).transpose()?,
+        })
+    }
+
+    pub fn hash_struct(&self, name: &str, value: &Value) -> Result<H256> {
+        Ok(keccak256(&self.encode_data(name, value)?).into())
+    }
+
+    /// The type with its dependencies, e.g.
+    /// `Mail(Person from,Person to,string contents)Person(string name,address wallet)`
+    pub fn encode_type(&self, name: &str) -> Result<String> {
+        let mut dependencies = BTreeSet::new();
+        self.dependencies(name, &mut dependencies)?;
+        dependencies.remove(name);
+        let mut encoded = String::new();
+        for name in std::iter::once(name).chain(dependencies.iter().map(String::as_str)) {
+            let fields: Vec<String> = self
+                .fields(name)?
+                .iter()
+                .map(|field| format!("{} {}", field.kind, field.name))
+                .collect();
+            encoded.push_str(&format!("{}({})", name, fields.join(",")));
+        }
+        Ok(encoded)
+    }
+
+    fn fields(&self, name: &str) -> Result<Vec<Field>> {
+        match self.types.get(name) {
+            Some(fields) => Ok(fields.clone()),
+            None if name == DOMAIN_TYPE => Ok(DOMAIN_FIELDS
+                .iter()
+                .filter(|(field, _)| !self.domain[*field].is_null())
+                .map(|(field, kind)| Field {
+                    name: field.to_string(),
+                    kind: kind.to_string(),
+                })
+                .collect()),
+            None => Err(anyhow!("Type {} is not defined", name)),
+        }
+    }
+
+    fn is_struct(&self, kind: &str) -> bool {
+        self.types.contains_key(kind) || kind == DOMAIN_TYPE
+    }
+
+    fn dependencies(&self, name: &str, found: &mut BTreeSet<String>) -> Result<()> {
+        if !found.insert(name.to_string()) {
+            return Ok(());
+        }
+        for field in self.fields(name)? {
+            let base = field.kind.split('[').next().unwrap_or_default();
+            if self.is_struct(base) {
+                self.dependencies(base, found)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_data(&self, name: &str, value: &Value) -> Result<Vec<u8>> {
+        if !value.is_object() {
+            bail!("{} must be an object", name);
+        }
+        let mut encoded = keccak256(self.encode_type(name)?.as_bytes()).to_vec();
+        for field in self.fields(name)? {
+            let field_value = &value[field.name.as_str()];
+            if field_value.is_null() {
+                bail!("{}.{} is missing", name, field.name);
+            }
+            let word = self
+                .encode_value(&field.kind, field_value)
+                .map_err(|e| anyhow!("{}.{}: {:#}", name, field.name, e))?;
+            encoded.extend_from_slice(&word);
+        }
+        Ok(encoded)
+    }
+
+    fn encode_value(&self, kind: &str, value: &Value) -> Result<[u8; 32]> {
+        if let Some(item_kind) = kind.strip_suffix(']') {
+            let (item_kind, length) = item_kind
+                .rsplit_once('[')
+                .ok_or_else(|| anyhow!("Invalid array type {}", kind))?;
+            let items = value
+                .as_array()
+                .ok_or_else(|| anyhow!("{} must be an array", kind))?;
+            if !length.is_empty() && length.parse::<usize>().ok() != Some(items.len()) {
+                bail!("{} has {} items", kind, items.len());
+            }
+            let mut encoded = Vec::with_capacity(32 * items.len());
+            for item in items {
+                encoded.extend_from_slice(&self.encode_value(item_kind, item)?);
+            }
+            return Ok(keccak256(&encoded));
+        }
+        if self.is_struct(kind) {
+            return Ok(self.hash_struct(kind, value)?.0);
+        }
+
+        let mut word = [0u8; 32];
+        match kind {
+            "string" => {
+                let text = value.as_str().ok_or_else(|| anyhow!("Expected a string"))?;
+                word = keccak256(text.as_bytes());
+            }
+            "bytes" => word = keccak256(&parse_bytes(value)?),
+            "bool" => {
+                word[31] = value
+                    .as_bool()
+                    .ok_or_else(|| anyhow!("Expected a boolean"))? as u8;
+            }
+            "address" => word[12..].copy_from_slice(parse_address(value)?.as_bytes()),
+            _ => {
+                if let Some(size) = kind.strip_prefix("bytes") {
+                    let size = parse_size(kind, size, 32)?;
+                    let bytes = parse_bytes(value)?;
+                    if bytes.len() != size {
+                        bail!("Expected {} bytes, got {}", size, bytes.len());
+                    }
+                    word[..size].copy_from_slice(&bytes);
+                } else if let Some(bits) = kind.strip_prefix("uint") {
+                    parse_uint(value, parse_size(kind, bits, 256)?)?.to_big_endian(&mut word);
+                } else if let Some(bits) = kind.strip_prefix("int") {
+                    parse_int(value, parse_size(kind, bits, 256)?)?.to_big_endian(&mut word);
+                } else {
+                    bail!("Unknown type {}", kind);
+                }
+            }
+        }
+        Ok(word)
+    }
+}
+
+/// The size of `bytes<N>`, `uint<N>` and `int<N>`, `max` when not set.
+fn parse_size(kind: &str, size: &str, max: usize) -> Result<usize> {
+    if size.is_empty() && max == 256 {
+        return Ok(max);
+    }
+    match size.parse::<usize>() {
+        Ok(size) if size > 0 && size <= max && (max == 32 || size % 8 == 0) => Ok(size),
+        _ => Err(anyhow!("Unknown type {}", kind)),
+    }
+}
+
+fn parse_bytes(value: &Value) -> Result<Vec<u8>> {
+    let hex = value
+        .as_str()
+        .and_then(|hex| hex.strip_prefix("0x"))
+        .ok_or_else(|| anyhow!("Expected 0x prefixed hex"))?;
+    Ok(hex::decode(hex)?)
+}
+
+fn parse_address(value: &Value) -> Result<Address> {
+    let bytes = parse_bytes(value)?;
+    if bytes.len() != 20 {
+        bail!("Expected a 20 bytes address");
+    }
+    Ok(Address::from_slice(&bytes))
+}
+
+/// A JSON number, or a decimal or `0x` hexadecimal string, of at most `bits`
+/// bits.
+fn parse_uint(value: &Value, bits: usize) -> Result<U256> {
+    let n = match value {
+        Value::Number(n) => n
+            .as_u64()
+            .map(U256::from)
+            .ok_or_else(|| anyhow!("{} is not an unsigned integer", n))?,
+        Value::String(s) => match s.strip_prefix("0x") {
+            Some(hex) => U256::from_str_radix(hex, 16).ok(),
+            None => U256::from_dec_str(s).ok(),
+        }
+        .ok_or_else(|| anyhow!("{} is not an unsigned integer", s))?,
+        _ => bail!("Expected an integer"),
+    };
+    if n.bits() > bits {
+        bail!("{} does not fit {} bits", n, bits);
+    }
+    Ok(n)
+}
+
+/// A signed integer of at most `bits` bits, in two's complement.
+fn parse_int(value: &Value, bits: usize) -> Result<U256> {
+    let (negative, magnitude) = match value {
+        Value::Number(n) => match n.as_i64() {
+            Some(n) => (n < 0, U256::from(n.unsigned_abs())),
+            None => bail!("{} is not an integer", n),
+        },
+        Value::String(s) => match s.strip_prefix('-') {
+            Some(abs) => (true, parse_uint(&Value::String(abs.to_string()), 256)?),
+            None => (false, parse_uint(value, 256)?),
+        },
+        _ => bail!("Expected an integer"),
+    };
+    let limit = U256::one() << (bits - 1);
+    if negative {
+        if magnitude > limit {
+            bail!("-{} does not fit {} bits", magnitude, bits);
+        }
+        Ok((!magnitude).overflowing_add(U256::one()).0)
+    } else {
+        if magnitude >= limit {
+            bail!("{} does not fit {} bits", magnitude, bits);
+        }
+        Ok(magnitude)
+    }
+}
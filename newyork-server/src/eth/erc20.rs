@@ -0,0 +1,106 @@
+//! Call data of the ERC-20 functions the server uses.
+
+use anyhow::{anyhow, bail, Result};
+use web3::ethabi::{self, ParamType, Token};
+use web3::types::{Address, Bytes, U256};
+
+/// `transfer(address,uint256)`
+const TRANSFER: [u8; 4] = [0xa9, 0x05, 0x9c, 0xbb];
+/// `transferFrom(address,address,uint256)`
+const TRANSFER_FROM: [u8; 4] = [0x23, 0xb8, 0x72, 0xdd];
+/// `balanceOf(address)`
+const BALANCE_OF: [u8; 4] = [0x70, 0xa0, 0x82, 0x31];
+/// `decimals()`
+const DECIMALS: [u8; 4] = [0x31, 0x3c, 0xe5, 0x67];
+
+/// A token transfer decoded from call data.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Transfer {
+    /// `None` when the caller sends its own tokens
+    pub from: Option<Address>,
+    pub to: Address,
+    pub amount: U256,
+}
+
+fn call(selector: [u8; 4], args: &[Token]) -> Bytes {
+    Bytes([&selector[..], &ethabi::encode(args)].concat())
+}
+
+pub fn transfer(to: Address, amount: U256) -> Bytes {
+    call(TRANSFER, &[Token::Address(to), Token::Uint(amount)])
+}
+
+pub fn balance_of(owner: Address) -> Bytes {
+    call(BALANCE_OF, &[Token::Address(owner)])
+}
+
+pub fn decimals() -> Bytes {
+    call(DECIMALS, &[])
+}
+
+/// Decodes the `uint256` returned by `balanceOf` or `decimals`.
+pub fn decode_uint(output: &[u8]) -> Result<U256> {
+    if output.is_empty() {
+        bail!("Empty output, the address is not an ERC-20 contract");
+    }
+    match ethabi::decode(&[ParamType::Uint(256)], output)?.pop() {
+        Some(Token::Uint(value)) => Ok(value),
+        _ => Err(anyhow!("Output 0x{} is not a uint", hex::encode(output))),
+    }
+}
+
+/// Decodes the output of `decimals`, a `uint8`.
+pub fn decode_decimals(output: &[u8]) -> Result<u32> {
+    let decimals = decode_uint(output)?;
+    if decimals > U256::from(u8::MAX) {
+        bail!("Token decimals {} do not fit a uint8", decimals);
+    }
+    Ok(decimals.as_u32())
+}
+
+/// Decodes `transfer` and `transferFrom` call data, `None` for other calls.
+pub fn decode_transfer(data: &[u8]) -> Option<Transfer> {
+    if data.len() < 4 {
+        return None;
+    }
+    let (selector, args) = data.split_at(4);
+    if selector == TRANSFER {
+        match ethabi::decode(&[ParamType::Address, ParamType::Uint(256)], args)
+            .ok()?
+            .as_slice()
+        {
+            [Token::Address(to), Token::Uint(amount)] => Some(Transfer {
+                from: None,
+                to: *to,
+                amount: *amount,
+            }),
+            _ => None,
+        }
+    } else if selector == TRANSFER_FROM {
+        match ethabi::decode(
+            &[ParamType::Address, ParamType::Address, ParamType::Uint(256)],
+            args,
+        )
+        .ok()?
+        .as_slice()
+        {
+            [Token::Address(from), Token::Address(to), Token::Uint(amount)] => Some(Transfer {
+                from: Some(*from),
+                to: *to,
+                amount: *amount,
+            }),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+/// Whether `output` is a `false` returned by a token that reports failed
+/// transfers instead of reverting.
+pub fn returned_false(output: &[u8]) -> bool {
+    matches!(
+        ethabi::decode(&[ParamType::Bool], output).ok().as_deref(),
+        Some([Token::Bool(false)])
+    )
+}
@@ -0,0 +1,90 @@
+//! EIP-1559 fee estimation from `eth_feeHistory`.
+
+use web3::types::{FeeHistory, U256};
+
+/// Number of recent blocks the estimate looks at.
+pub const FEE_HISTORY_BLOCKS: u64 = 20;
+/// Priority fee percentiles of each block for the slow, normal and fast tiers.
+pub const FEE_PERCENTILES: [f64; 3] = [10.0, 50.0, 90.0];
+/// Used when every recent block was empty. 1 gwei
+const MIN_PRIORITY_FEE: u64 = 1_000_000_000;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeTier {
+    Slow,
+    Normal,
+    Fast,
+}
+
+impl Default for FeeTier {
+    fn default() -> Self {
+        FeeTier::Normal
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct FeeEstimate {
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct FeeEstimates {
+    /// Base fee of the next block
+    pub base_fee_per_gas: U256,
+    pub slow: FeeEstimate,
+    pub normal: FeeEstimate,
+    pub fast: FeeEstimate,
+}
+
+impl FeeEstimates {
+    pub fn tier(&self, tier: FeeTier) -> &FeeEstimate {
+        match tier {
+            FeeTier::Slow => &self.slow,
+            FeeTier::Normal => &self.normal,
+            FeeTier::Fast => &self.fast,
+        }
+    }
+}
+
+/// Estimates fees from a history queried with [`FEE_PERCENTILES`], `None` on
+/// chains without a base fee, which only take legacy gas prices.
+///
+/// Each tier tips the median of its percentile over the non-empty blocks, and
+/// allows for the base fee to double before the transaction is mined.
+pub fn estimate(history: &FeeHistory) -> Option<FeeEstimates> {
+    let base_fee_per_gas = *history.base_fee_per_gas.last()?;
+    if base_fee_per_gas.is_zero() {
+        return None;
+    }
+    let rewards: Vec<&Vec<U256>> = history
+        .reward
+        .as_ref()?
+        .iter()
+        .filter(|block| block.len() == FEE_PERCENTILES.len())
+        .filter(|block| block.iter().any(|reward| !reward.is_zero()))
+        .collect();
+
+    let tier = |percentile: usize| {
+        let mut tips: Vec<U256> = rewards.iter().map(|block| block[percentile]).collect();
+        tips.sort();
+        let max_priority_fee_per_gas = tips
+            .get(tips.len() / 2)
+            .copied()
+            .unwrap_or_else(|| U256::from(MIN_PRIORITY_FEE));
+        FeeEstimate {
+            max_fee_per_gas: base_fee_per_gas
+                .saturating_mul(U256::from(2))
+                .saturating_add(max_priority_fee_per_gas),
+            max_priority_fee_per_gas,
+        }
+    };
+
+    Some(FeeEstimates {
+        base_fee_per_gas,
+        slow: tier(0),
+        normal: tier(1),
+        fast: tier(2),
+    })
+}
@@ -0,0 +1,97 @@
+//! Gas limits from `eth_estimateGas`, and the revert reasons of calls that
+//! fail to estimate.
+
+use std::{error, fmt};
+
+use serde_json::Value;
+use web3::ethabi::{self, ParamType, Token};
+use web3::types::{Bytes, U256};
+
+/// Selector of `Error(string)`, raised by `require` and `revert`.
+const ERROR_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+/// Selector of `Panic(uint256)`, raised by failed assertions and arithmetic.
+const PANIC_SELECTOR: [u8; 4] = [0x4e, 0x48, 0x7b, 0x71];
+/// JSON-RPC error code of a reverted execution.
+const EXECUTION_REVERTED: i64 = 3;
+
+/// A call the node reports would revert.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Revert {
+    /// The message of the node
+    pub message: String,
+    /// Decoded `Error(string)` or `Panic(uint256)`, when there is one
+    pub reason: Option<String>,
+    /// Raw revert data
+    pub data: Option<Bytes>,
+}
+
+impl fmt::Display for Revert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.reason {
+            Some(reason) => write!(f, "Transaction would revert: {}", reason),
+            None => write!(f, "Transaction would revert ({})", self.message),
+        }
+    }
+}
+
+impl error::Error for Revert {}
+
+/// Adds `margin_percent` percent to a gas estimate.
+pub fn with_margin(gas: U256, margin_percent: u64) -> U256 {
+    gas.saturating_add(gas.saturating_mul(U256::from(margin_percent)) / 100)
+}
+
+/// The revert behind a failed `eth_estimateGas` or `eth_call`, `None` when
+/// the call failed for another reason.
+pub fn revert_of(error: &web3::Error) -> Option<Revert> {
+    let rpc = match error {
+        web3::Error::Rpc(rpc) => rpc,
+        _ => return None,
+    };
+    if rpc.code.code() != EXECUTION_REVERTED && !rpc.message.contains("revert") {
+        return None;
+    }
+    let data = rpc.data.as_ref().and_then(revert_data);
+    let reason = data
+        .as_ref()
+        .and_then(|data| decode_reason(&data.0))
+        .or_else(|| {
+            rpc.message
+                .split_once("reverted: ")
+                .map(|(_, reason)| reason.to_string())
+        });
+    Some(Revert {
+        message: rpc.message.clone(),
+        reason,
+        data,
+    })
+}
+
+/// Decodes the reason of `Error(string)` and `Panic(uint256)` revert data.
+pub fn decode_reason(data: &[u8]) -> Option<String> {
+    if data.len() < 4 {
+        return None;
+    }
+    let (selector, args) = data.split_at(4);
+    let kind = if selector == ERROR_SELECTOR {
+        ParamType::String
+    } else if selector == PANIC_SELECTOR {
+        ParamType::Uint(256)
+    } else {
+        return None;
+    };
+    match ethabi::decode(&[kind], args).ok()?.pop()? {
+        Token::String(reason) => Some(reason),
+        Token::Uint(code) => Some(format!("panic {:#x}", code)),
+        _ => None,
+    }
+}
+
+/// Nodes return the data as a hex string, some nested in an object.
+fn revert_data(data: &Value) -> Option<Bytes> {
+    match data {
+        Value::String(data) => hex::decode(data.trim_start_matches("0x")).ok().map(Bytes),
+        Value::Object(object) => object.get("data").and_then(revert_data),
+        _ => None,
+    }
+}
@@ -0,0 +1,216 @@
+//! Transaction intents tie the parameters returned by `/eth/tx/params` to the
+//! signing session that follows. `sign_first` binds an intent to the wallet's
+//! session, after which the server only signs the hash of that transaction,
+//! and only once. Expired intents are purged along with their bindings.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+use rocket::fairing::AdHoc;
+use uuid::Uuid;
+use web3::types::Address;
+
+use super::tx::UnsignedTx;
+use crate::storage::db::{self, MPCStruct, DB};
+use crate::AppConfig;
+
+/// The index of every intent is kept apart from the data of users
+const INDEX_USER: &str = "intents";
+const INDEX_ID: &str = "expiries";
+
+lazy_static! {
+    /// Serializes changes to the index, which is read, changed and written back
+    static ref INDEX_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Debug)]
+pub enum EthStruct {
+    TxIntent,
+    /// Id of the intent bound to a wallet's signing session
+    SignIntent,
+    /// Every intent not yet signed or removed, with its expiry
+    IntentIndex,
+}
+
+impl MPCStruct for EthStruct {
+    fn to_string(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TxIntent {
+    pub id: String,
+    pub from: Address,
+    pub tx: UnsignedTx,
+    /// Unix time in seconds
+    pub expires_at: u64,
+    /// The wallet whose signing session the intent was last bound to
+    #[serde(default)]
+    pub wallet: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+struct IndexEntry {
+    user_id: String,
+    intent_id: String,
+    expires_at: u64,
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+fn update_index<T>(db: &DB, update: impl FnOnce(&mut Vec<IndexEntry>) -> T) -> Result<T> {
+    let _guard = INDEX_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut index: Vec<IndexEntry> =
+        db::get(db, INDEX_USER, INDEX_ID, &EthStruct::IntentIndex)?.unwrap_or_default();
+    let result = update(&mut index);
+    db::insert(db, INDEX_USER, INDEX_ID, &EthStruct::IntentIndex, &index)?;
+    Ok(result)
+}
+
+pub fn create(
+    db: &DB,
+    user_id: &str,
+    from: Address,
+    tx: UnsignedTx,
+    ttl: Duration,
+) -> Result<TxIntent> {
+    let intent = TxIntent {
+        id: Uuid::new_v4().to_string(),
+        from,
+        tx,
+        expires_at: now()? + ttl.as_secs(),
+        wallet: None,
+    };
+    db::insert(db, user_id, &intent.id, &EthStruct::TxIntent, &intent)?;
+    update_index(db, |index| {
+        index.push(IndexEntry {
+            user_id: user_id.to_string(),
+            intent_id: intent.id.clone(),
+            expires_at: intent.expires_at,
+        })
+    })?;
+    Ok(intent)
+}
+
+/// Binds `intent_id` to the signing session of wallet `id`, or clears the
+/// binding left by a previous session.
+pub fn bind(db: &DB, user_id: &str, id: &str, intent_id: Option<&str>) -> Result<()> {
+    match intent_id {
+        Some(intent_id) => {
+            let mut intent = fetch(db, user_id, intent_id)?;
+            unbind(db, user_id, &intent)?;
+            intent.wallet = Some(id.to_string());
+            db::insert(db, user_id, intent_id, &EthStruct::TxIntent, &intent)?;
+            db::insert(db, user_id, id, &EthStruct::SignIntent, intent_id)
+        }
+        None => db::remove(db, user_id, id, &EthStruct::SignIntent),
+    }
+}
+
+/// The intent the signing session of wallet `id` is bound to. A binding to
+/// an intent that expired or was abandoned is cleared.
+pub fn bound(db: &DB, user_id: &str, id: &str) -> Result<Option<TxIntent>> {
+    let intent_id: Option<String> = db::get(db, user_id, id, &EthStruct::SignIntent)?;
+    match intent_id.map(|intent_id| fetch(db, user_id, &intent_id)) {
+        Some(Ok(intent)) => Ok(Some(intent)),
+        Some(Err(e)) => {
+            db::remove(db, user_id, id, &EthStruct::SignIntent)?;
+            Err(e)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Called once `intent` is signed, so it cannot be signed again.
+pub fn consume(db: &DB, user_id: &str, id: &str, intent: &TxIntent) -> Result<()> {
+    db::remove(db, user_id, id, &EthStruct::SignIntent)?;
+    remove(db, user_id, intent)
+}
+
+/// Removes an intent that will not be signed.
+pub fn abandon(db: &DB, user_id: &str, intent_id: &str) -> Result<TxIntent> {
+    let intent = fetch(db, user_id, intent_id)?;
+    remove(db, user_id, &intent)?;
+    Ok(intent)
+}
+
+/// The intent `intent_id`, which must not be expired.
+pub fn fetch(db: &DB, user_id: &str, intent_id: &str) -> Result<TxIntent> {
+    let intent: TxIntent = db::get(db, user_id, intent_id, &EthStruct::TxIntent)?
+        .ok_or_else(|| anyhow!("No transaction intent {}", intent_id))?;
+    if intent.expires_at <= now()? {
+        remove(db, user_id, &intent)?;
+        bail!("Transaction intent {} has expired", intent_id);
+    }
+    Ok(intent)
+}
+
+/// Clears the binding of the wallet `intent` was bound to, if still bound
+/// to it.
+fn unbind(db: &DB, user_id: &str, intent: &TxIntent) -> Result<()> {
+    if let Some(wallet) = &intent.wallet {
+        let bound: Option<String> = db::get(db, user_id, wallet, &EthStruct::SignIntent)?;
+        if bound.as_ref() == Some(&intent.id) {
+            db::remove(db, user_id, wallet, &EthStruct::SignIntent)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes `intent`, its binding and its index entry.
+fn remove(db: &DB, user_id: &str, intent: &TxIntent) -> Result<()> {
+    unbind(db, user_id, intent)?;
+    db::remove(db, user_id, &intent.id, &EthStruct::TxIntent)?;
+    update_index(db, |index| {
+        index.retain(|entry| !(entry.intent_id == intent.id && entry.user_id == user_id))
+    })
+}
+
+/// Removes the intents expired at `now`, in Unix seconds, with their
+/// bindings. Returns how many were removed.
+pub fn purge_expired(db: &DB, now: u64) -> Result<usize> {
+    let expired = update_index(db, |index| {
+        let (expired, live) = index
+            .drain(..)
+            .partition::<Vec<_>, _>(|entry| entry.expires_at <= now);
+        *index = live;
+        expired
+    })?;
+    for entry in &expired {
+        let intent: Option<TxIntent> =
+            db::get(db, &entry.user_id, &entry.intent_id, &EthStruct::TxIntent)?;
+        if let Some(intent) = intent {
+            unbind(db, &entry.user_id, &intent)?;
+            db::remove(db, &entry.user_id, &intent.id, &EthStruct::TxIntent)?;
+        }
+    }
+    Ok(expired.len())
+}
+
+/// Purges expired intents every `interval`.
+pub fn fairing(interval: Duration) -> AdHoc {
+    AdHoc::on_liftoff("Transaction intent cleanup", move |rocket| {
+        Box::pin(async move {
+            let db: Arc<DB> = match rocket.state::<AppConfig>() {
+                Some(config) => config.db.clone(),
+                None => return,
+            };
+
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    match now().and_then(|now| purge_expired(&db, now)) {
+                        Ok(0) => {}
+                        Ok(purged) => debug!("Purged {} expired transaction intents", purged),
+                        Err(e) => warn!("Failed to purge transaction intents: {:#}", e),
+                    }
+                }
+            });
+        })
+    })
+}
@@ -0,0 +1,55 @@
+//! Messages signed outside of transactions: EIP-191 personal messages, and
+//! signatures in the 65 bytes format wallets return for them.
+
+use anyhow::{anyhow, Result};
+use web3::signing::{self, keccak256};
+use web3::types::{Address, Bytes, H256};
+
+use super::tx::TxSignature;
+use crate::policy::MessageSummary;
+
+const PERSONAL_PREFIX: &str = "\x19Ethereum Signed Message:\n";
+
+/// `keccak256("\x19Ethereum Signed Message:\n" || len(message) || message)`
+pub fn personal_digest(message: &[u8]) -> H256 {
+    let mut prefixed = format!("{}{}", PERSONAL_PREFIX, message.len()).into_bytes();
+    prefixed.extend_from_slice(message);
+    keccak256(&prefixed).into()
+}
+
+pub fn personal_summary(message: &[u8]) -> MessageSummary {
+    MessageSummary::Personal {
+        text: String::from_utf8(message.to_vec()).ok(),
+        length: message.len(),
+    }
+}
+
+/// `r || s || v`, with `v` 27 or 28.
+pub fn signature_bytes(signature: &TxSignature) -> Result<Bytes> {
+    if signature.recid > 1 {
+        return Err(anyhow!(
+            "Recovery id {} cannot be used on Ethereum",
+            signature.recid
+        ));
+    }
+    let mut bytes = vec![0u8; 65];
+    signature.r.to_big_endian(&mut bytes[..32]);
+    signature.s.to_big_endian(&mut bytes[32..64]);
+    bytes[64] = 27 + signature.recid;
+    Ok(bytes.into())
+}
+
+/// The address a 65 bytes signature of `digest` recovers to, with `v` 27 or
+/// 28, or 0 or 1.
+pub fn recover(digest: H256, signature: &[u8]) -> Result<Address> {
+    if signature.len() != 65 {
+        return Err(anyhow!("A signature has 65 bytes, not {}", signature.len()));
+    }
+    let recid = match signature[64] {
+        v @ (0 | 1) => v,
+        v @ (27 | 28) => v - 27,
+        v => return Err(anyhow!("Invalid signature v {}", v)),
+    };
+    signing::recover(digest.as_bytes(), &signature[..64], recid as i32)
+        .map_err(|_| anyhow!("Invalid signature"))
+}
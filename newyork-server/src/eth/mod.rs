@@ -0,0 +1,16 @@
+pub mod amount;
+pub mod broadcast;
+pub mod chains;
+pub mod eip712;
+pub mod erc20;
+pub mod fees;
+pub mod gas;
+pub mod intent;
+pub mod messages;
+pub mod nonces;
+pub mod pool;
+pub mod replace;
+pub mod simulate;
+pub mod tracking;
+pub mod tx;
+pub mod wallets;
@@ -0,0 +1,165 @@
+//! Nonces handed out by `/eth/tx/params`. Each nonce is reserved for the
+//! intent it was prepared for, so transactions prepared back to back get
+//! consecutive nonces. A reservation ends when its transaction reaches the
+//! pending pool, when its intent expires or when the intent is abandoned.
+
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use web3::types::{Address, BlockNumber, U256};
+
+use super::intent::TxIntent;
+use super::pool::Connection;
+use crate::storage::db::{self, MPCStruct, DB};
+
+#[derive(Debug)]
+pub enum NonceStruct {
+    Reservations,
+}
+
+impl MPCStruct for NonceStruct {
+    fn to_string(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Reservation {
+    pub nonce: U256,
+    pub intent_id: String,
+    /// Unix time in seconds, the expiry of the intent
+    pub expires_at: u64,
+}
+
+/// Transactions of an address, mined and with those in the pending pool.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct NonceCounts {
+    pub mined: U256,
+    pub pending: U256,
+}
+
+pub async fn counts(web3: &Connection, address: Address) -> web3::Result<NonceCounts> {
+    let (mined, pending) = futures::future::try_join(
+        web3.eth()
+            .transaction_count(address, Some(BlockNumber::Latest)),
+        web3.eth()
+            .transaction_count(address, Some(BlockNumber::Pending)),
+    )
+    .await?;
+    Ok(NonceCounts { mined, pending })
+}
+
+/// Drops the reservations of expired intents, and of nonces the pending pool
+/// already has.
+pub fn reconcile(
+    reservations: Vec<Reservation>,
+    counts: NonceCounts,
+    now: u64,
+) -> Vec<Reservation> {
+    reservations
+        .into_iter()
+        .filter(|reservation| reservation.expires_at > now && reservation.nonce >= counts.pending)
+        .collect()
+}
+
+/// The lowest nonce neither in the pending pool nor reserved.
+pub fn next_free(reservations: &[Reservation], counts: NonceCounts) -> U256 {
+    let mut nonce = counts.pending;
+    while reservations
+        .iter()
+        .any(|reservation| reservation.nonce == nonce)
+    {
+        nonce += U256::one();
+    }
+    nonce
+}
+
+/// Free nonces below reserved ones. Transactions above a gap are not mined
+/// until it is filled.
+pub fn gaps(reservations: &[Reservation], counts: NonceCounts) -> Vec<U256> {
+    let highest = match reservations
+        .iter()
+        .map(|reservation| reservation.nonce)
+        .max()
+    {
+        Some(highest) => highest,
+        None => return Vec::new(),
+    };
+    let mut gaps = Vec::new();
+    let mut nonce = counts.pending;
+    while nonce < highest {
+        if !reservations
+            .iter()
+            .any(|reservation| reservation.nonce == nonce)
+        {
+            gaps.push(nonce);
+        }
+        nonce += U256::one();
+    }
+    gaps
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+fn key(chain_id: u64, address: Address) -> String {
+    format!("{}_{:?}", chain_id, address)
+}
+
+/// Serializes the reservations, which are read, changed and written back.
+#[derive(Default)]
+pub struct NonceManager {
+    lock: Mutex<()>,
+}
+
+impl NonceManager {
+    /// Creates the intent of the next free nonce of `from` with `create`, and
+    /// reserves the nonce for it. Also returns the gaps left below reserved
+    /// nonces.
+    pub fn reserve<F>(
+        &self,
+        db: &DB,
+        user_id: &str,
+        chain_id: u64,
+        from: Address,
+        counts: NonceCounts,
+        create: F,
+    ) -> Result<(TxIntent, Vec<U256>)>
+    where
+        F: FnOnce(U256) -> Result<TxIntent>,
+    {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let key = key(chain_id, from);
+        let reservations: Vec<Reservation> =
+            db::get(db, user_id, &key, &NonceStruct::Reservations)?.unwrap_or_default();
+        let mut reservations = reconcile(reservations, counts, now()?);
+
+        let intent = create(next_free(&reservations, counts))?;
+        reservations.push(Reservation {
+            nonce: intent.tx.nonce,
+            intent_id: intent.id.clone(),
+            expires_at: intent.expires_at,
+        });
+        db::insert(db, user_id, &key, &NonceStruct::Reservations, &reservations)?;
+        let gaps = gaps(&reservations, counts);
+        if !gaps.is_empty() {
+            warn!("Nonces {:?} of {:?} are not used", gaps, from);
+        }
+        Ok((intent, gaps))
+    }
+
+    /// Frees the nonce reserved for `intent`.
+    pub fn release(&self, db: &DB, user_id: &str, intent: &TxIntent) -> Result<()> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let key = key(intent.tx.chain_id, intent.from);
+        let reservations: Vec<Reservation> =
+            db::get(db, user_id, &key, &NonceStruct::Reservations)?.unwrap_or_default();
+        let reservations: Vec<Reservation> = reservations
+            .into_iter()
+            .filter(|reservation| reservation.intent_id != intent.id)
+            .collect();
+        db::insert(db, user_id, &key, &NonceStruct::Reservations, &reservations)
+    }
+}
@@ -0,0 +1,445 @@
+//! Long-lived RPC connections shared by every request, one set per chain.
+//!
+//! A chain's `rpc_urls` can mix `ws(s)` and `http(s)` URLs, so an HTTP URL
+//! listed after the WebSocket ones takes over when they fail. Requests use
+//! the first endpoint that is up. A call failing at the transport level
+//! marks its endpoint down and drops its connection, which is reopened on
+//! next use, and [`fairing`] probes every endpoint in the background so
+//! recovered ones are used again. A connection is only used once the
+//! endpoint's `eth_chainId` is the chain it is listed under, so a URL of
+//! another network never gets its transactions. The `broadcast_urls` of a
+//! chain are only used to send transactions, see
+//! [`RpcPool::on_every_endpoint`].
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use jsonrpc_core::{Call, Value};
+use reqwest::Url;
+use rocket::fairing::AdHoc;
+use web3::error::TransportError;
+use web3::transports::{Either, Http, WebSocket};
+use web3::types::U256;
+use web3::{RequestId, Transport, Web3};
+
+use super::chains::ChainRegistry;
+use crate::telemetry::metrics::Metrics;
+use crate::utils::settings::ChainSettings;
+use crate::AppConfig;
+
+pub type Connection = Web3<PooledTransport>;
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct EndpointHealth {
+    /// Position of the URL in the chain's `rpc_urls`, then `broadcast_urls`
+    pub endpoint: usize,
+    pub provider: String,
+    pub transport: &'static str,
+    pub broadcast_only: bool,
+    pub up: bool,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+}
+
+/// Health of an endpoint, shared with the transports connected to it.
+struct Tracker {
+    chain: String,
+    provider: String,
+    /// The URL as configured and as printed by `Url`, longest first
+    urls: Vec<String>,
+    health: Mutex<EndpointHealth>,
+    /// Set when a call fails, the connection is reopened on next use
+    stale: AtomicBool,
+    metrics: Arc<Metrics>,
+}
+
+impl Tracker {
+    fn is_up(&self) -> bool {
+        self.health.lock().unwrap_or_else(|e| e.into_inner()).up
+    }
+
+    /// Transport errors can quote the URL, which often holds an API key.
+    fn redact(&self, error: web3::Error) -> web3::Error {
+        let redact = |message: String| {
+            self.urls
+                .iter()
+                .fold(message, |message, url| message.replace(url, &self.provider))
+        };
+        match error {
+            web3::Error::Transport(TransportError::Message(message)) => {
+                web3::Error::Transport(TransportError::Message(redact(message)))
+            }
+            web3::Error::InvalidResponse(message) => web3::Error::InvalidResponse(redact(message)),
+            error => error,
+        }
+    }
+
+    /// RPC errors are answers of a working node, only transport failures
+    /// count against the endpoint.
+    fn record<T>(&self, result: &web3::Result<T>) {
+        match result {
+            Err(
+                e @ (web3::Error::Unreachable
+                | web3::Error::Transport(_)
+                | web3::Error::Io(_)
+                | web3::Error::InvalidResponse(_)),
+            ) => self.failed(e.to_string()),
+            _ => self.succeeded(),
+        }
+    }
+
+    fn failed(&self, error: String) {
+        self.stale.store(true, Ordering::SeqCst);
+        let mut health = self.health.lock().unwrap_or_else(|e| e.into_inner());
+        if health.up {
+            warn!(
+                "RPC endpoint {} of {} is down ({})",
+                health.endpoint, self.chain, error
+            );
+        }
+        health.up = false;
+        health.consecutive_failures += 1;
+        health.last_error = Some(error);
+        self.metrics
+            .rpc_endpoint_health(&self.chain, health.endpoint, health.transport, false);
+    }
+
+    fn succeeded(&self) {
+        let mut health = self.health.lock().unwrap_or_else(|e| e.into_inner());
+        if !health.up {
+            info!("RPC endpoint {} of {} is up", health.endpoint, self.chain);
+        }
+        health.up = true;
+        health.consecutive_failures = 0;
+        self.metrics
+            .rpc_endpoint_health(&self.chain, health.endpoint, health.transport, true);
+    }
+}
+
+/// A WebSocket or HTTP transport reporting the outcome of its calls to the
+/// endpoint's health, and failing calls that take longer than the RPC timeout.
+#[derive(Clone)]
+pub struct PooledTransport {
+    inner: Either<WebSocket, Http>,
+    tracker: Arc<Tracker>,
+    timeout: Duration,
+}
+
+impl fmt::Debug for PooledTransport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PooledTransport")
+            .field("chain", &self.tracker.chain)
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl Transport for PooledTransport {
+    type Out = BoxFuture<'static, web3::Result<Value>>;
+
+    fn prepare(&self, method: &str, params: Vec<Value>) -> (RequestId, Call) {
+        self.inner.prepare(method, params)
+    }
+
+    fn send(&self, id: RequestId, request: Call) -> Self::Out {
+        let call = self.inner.send(id, request);
+        let (tracker, timeout) = (self.tracker.clone(), self.timeout);
+        async move {
+            let result = tokio::time::timeout(timeout, call)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(web3::Error::Transport(TransportError::Message(format!(
+                        "No answer within {:?}",
+                        timeout
+                    ))))
+                })
+                .map_err(|e| tracker.redact(e));
+            tracker.record(&result);
+            result
+        }
+        .boxed()
+    }
+}
+
+struct Endpoint {
+    url: String,
+    chain_id: u64,
+    tracker: Arc<Tracker>,
+    connection: tokio::sync::Mutex<Option<Connection>>,
+}
+
+impl Endpoint {
+    fn new(
+        chain: &ChainSettings,
+        endpoint: usize,
+        url: &str,
+        broadcast_only: bool,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let provider = provider_of(url).unwrap_or_else(|| format!("endpoint {}", endpoint));
+        let mut urls = vec![url.to_string()];
+        if let Ok(parsed) = Url::parse(url) {
+            urls.push(parsed.to_string());
+        }
+        urls.sort_by_key(|url| std::cmp::Reverse(url.len()));
+        Endpoint {
+            url: url.to_string(),
+            chain_id: chain.chain_id,
+            tracker: Arc::new(Tracker {
+                chain: chain.name.clone(),
+                provider: provider.clone(),
+                urls,
+                health: Mutex::new(EndpointHealth {
+                    endpoint,
+                    provider,
+                    transport: transport_of(url),
+                    broadcast_only,
+                    up: true,
+                    consecutive_failures: 0,
+                    last_error: None,
+                }),
+                stale: AtomicBool::new(false),
+                metrics,
+            }),
+            connection: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    async fn connection(&self, timeout: Duration) -> Result<Connection> {
+        let mut connection = self.connection.lock().await;
+        if self.tracker.stale.swap(false, Ordering::SeqCst) {
+            *connection = None;
+        }
+        if let Some(web3) = &*connection {
+            return Ok(web3.clone());
+        }
+
+        let opened = async {
+            let inner = open(&self.url).await?;
+            let served = Web3::new(inner.clone()).eth().chain_id().await?;
+            if served != U256::from(self.chain_id) {
+                return Err(web3::Error::InvalidResponse(format!(
+                    "Endpoint serves chain {}, not {}",
+                    served, self.chain_id
+                )));
+            }
+            Ok(inner)
+        };
+        let inner = match tokio::time::timeout(timeout, opened).await {
+            Ok(Ok(inner)) => inner,
+            Ok(Err(e)) => {
+                let e = self.tracker.redact(e);
+                self.tracker.failed(e.to_string());
+                return Err(e.into());
+            }
+            Err(_) => {
+                let error = format!("No connection within {:?}", timeout);
+                self.tracker.failed(error.clone());
+                return Err(anyhow!(error));
+            }
+        };
+        {
+            let health = self
+                .tracker
+                .health
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            self.tracker.metrics.rpc_connected(
+                &self.tracker.chain,
+                health.endpoint,
+                health.transport,
+            );
+        }
+        let web3 = Web3::new(PooledTransport {
+            inner,
+            tracker: self.tracker.clone(),
+            timeout,
+        });
+        *connection = Some(web3.clone());
+        Ok(web3)
+    }
+}
+
+/// Scheme and host, leaving out the path and query where API keys go.
+fn provider_of(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    Some(format!("{}://{}", url.scheme(), url.host_str()?))
+}
+
+fn transport_of(url: &str) -> &'static str {
+    if url.starts_with("http") {
+        "http"
+    } else {
+        "ws"
+    }
+}
+
+async fn open(url: &str) -> web3::Result<Either<WebSocket, Http>> {
+    match transport_of(url) {
+        "http" => Ok(Either::Right(Http::new(url)?)),
+        _ => Ok(Either::Left(WebSocket::new(url).await?)),
+    }
+}
+
+struct ChainEndpoints {
+    name: String,
+    endpoints: Vec<Endpoint>,
+    /// Only used to send transactions
+    broadcast: Vec<Endpoint>,
+}
+
+pub struct RpcPool {
+    chains: BTreeMap<u64, ChainEndpoints>,
+    timeout: Duration,
+}
+
+impl RpcPool {
+    /// Connections are opened on first use, `timeout` bounds every call.
+    pub fn new(registry: &ChainRegistry, timeout: Duration, metrics: Arc<Metrics>) -> Self {
+        let chains = registry
+            .iter()
+            .map(|chain| {
+                let endpoints = chain
+                    .rpc_urls
+                    .iter()
+                    .enumerate()
+                    .map(|(i, url)| Endpoint::new(chain, i, url, false, metrics.clone()))
+                    .collect();
+                let broadcast = chain
+                    .broadcast_urls
+                    .iter()
+                    .enumerate()
+                    .map(|(i, url)| {
+                        let endpoint = chain.rpc_urls.len() + i;
+                        Endpoint::new(chain, endpoint, url, true, metrics.clone())
+                    })
+                    .collect();
+                (
+                    chain.chain_id,
+                    ChainEndpoints {
+                        name: chain.name.clone(),
+                        endpoints,
+                        broadcast,
+                    },
+                )
+            })
+            .collect();
+        RpcPool { chains, timeout }
+    }
+
+    /// A connection to the first endpoint of `chain_id` that is up, or to
+    /// the first one that reconnects when they are all down.
+    pub async fn connection(&self, chain_id: u64) -> Result<Connection> {
+        let chain = self.chain(chain_id)?;
+        let mut endpoints: Vec<&Endpoint> = chain.endpoints.iter().collect();
+        endpoints.sort_by_key(|endpoint| !endpoint.tracker.is_up());
+
+        let mut error = anyhow!("Chain {} has no RPC", chain.name);
+        for endpoint in endpoints {
+            match endpoint.connection(self.timeout).await {
+                Ok(web3) => return Ok(web3),
+                Err(e) => error = e,
+            }
+        }
+        Err(error)
+    }
+
+    /// Runs `call` concurrently on every endpoint of `chain_id`, including
+    /// its `broadcast_urls`, and returns the result of each provider.
+    pub async fn on_every_endpoint<T, F, Fut>(
+        &self,
+        chain_id: u64,
+        call: F,
+    ) -> Result<Vec<(String, Result<T>)>>
+    where
+        F: Fn(Connection) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let chain = self.chain(chain_id)?;
+        let call = &call;
+        let calls = chain
+            .endpoints
+            .iter()
+            .chain(&chain.broadcast)
+            .map(|endpoint| async move {
+                let result = match endpoint.connection(self.timeout).await {
+                    Ok(web3) => call(web3).await,
+                    Err(e) => Err(e),
+                };
+                (endpoint.tracker.provider.clone(), result)
+            });
+        Ok(futures::future::join_all(calls).await)
+    }
+
+    fn chain(&self, chain_id: u64) -> Result<&ChainEndpoints> {
+        self.chains
+            .get(&chain_id)
+            .ok_or_else(|| anyhow!("Chain {} is not supported", chain_id))
+    }
+
+    /// Endpoint health by chain name.
+    pub fn health(&self) -> BTreeMap<String, Vec<EndpointHealth>> {
+        self.chains
+            .values()
+            .map(|chain| {
+                let health = chain
+                    .endpoints
+                    .iter()
+                    .chain(&chain.broadcast)
+                    .map(|endpoint| {
+                        endpoint
+                            .tracker
+                            .health
+                            .lock()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .clone()
+                    })
+                    .collect();
+                (chain.name.clone(), health)
+            })
+            .collect()
+    }
+
+    /// Calls `eth_blockNumber` on every endpoint, reconnecting the ones that
+    /// failed. The outcome is recorded in their health.
+    pub async fn probe(&self) {
+        let probes = self
+            .chains
+            .values()
+            .flat_map(|chain| chain.endpoints.iter())
+            .map(|endpoint| async move {
+                if let Ok(web3) = endpoint.connection(self.timeout).await {
+                    let _ = web3.eth().block_number().await;
+                }
+            });
+        futures::future::join_all(probes).await;
+    }
+}
+
+/// Probes the RPC endpoints every `interval` for as long as the server runs,
+/// starting at launch so the first requests find their connections open.
+pub fn fairing(interval: Duration) -> AdHoc {
+    AdHoc::on_liftoff("RPC probes", move |rocket| {
+        Box::pin(async move {
+            let pool: Arc<RpcPool> = match rocket.state::<AppConfig>() {
+                Some(config) => config.rpc.clone(),
+                None => return,
+            };
+
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    pool.probe().await;
+                }
+            });
+        })
+    })
+}
@@ -0,0 +1,93 @@
+//! Replacements of pending transactions: the same nonce with higher fees,
+//! either carrying the same call to speed it up, or a zero-value transfer to
+//! the sender to cancel it.
+
+use web3::types::{Address, Bytes, U256};
+
+use super::fees::FeeEstimate;
+use super::tx::{Fees, UnsignedTx};
+
+/// Nodes only replace a pending transaction paying at least this much more,
+/// the default of geth and most clients.
+pub const PRICE_BUMP_PERCENT: u64 = 10;
+/// Gas of a plain transfer.
+const TRANSFER_GAS: u64 = 21_000;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Replacement {
+    SpeedUp,
+    Cancel,
+}
+
+/// `price` raised by [`PRICE_BUMP_PERCENT`], rounded up.
+pub fn bump(price: U256) -> U256 {
+    let bump = price.saturating_mul(U256::from(PRICE_BUMP_PERCENT));
+    price.saturating_add(bump.saturating_add(U256::from(99)) / 100)
+}
+
+/// Fees of a replacement of a transaction paying `fees`: bumped enough for
+/// nodes to accept it, and at least the current `estimate` or `gas_price`.
+pub fn bumped_fees(fees: &Fees, estimate: Option<&FeeEstimate>, gas_price: U256) -> Fees {
+    match fees {
+        Fees::Legacy { gas_price: old } => Fees::Legacy {
+            gas_price: bump(*old).max(gas_price),
+        },
+        Fees::Eip2930 {
+            gas_price: old,
+            access_list,
+        } => Fees::Eip2930 {
+            gas_price: bump(*old).max(gas_price),
+            access_list: access_list.clone(),
+        },
+        Fees::Eip1559 {
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+            access_list,
+        } => {
+            let (max_fee, priority_fee) = match estimate {
+                Some(estimate) => (estimate.max_fee_per_gas, estimate.max_priority_fee_per_gas),
+                None => (U256::zero(), U256::zero()),
+            };
+            Fees::Eip1559 {
+                max_fee_per_gas: bump(*max_fee_per_gas).max(max_fee),
+                max_priority_fee_per_gas: bump(*max_priority_fee_per_gas).max(priority_fee),
+                access_list: access_list.clone(),
+            }
+        }
+    }
+}
+
+/// The replacement of `original`, sent by `from`, paying `fees`.
+pub fn replace(original: &UnsignedTx, from: Address, kind: Replacement, fees: Fees) -> UnsignedTx {
+    match kind {
+        Replacement::SpeedUp => UnsignedTx {
+            fees,
+            ..original.clone()
+        },
+        Replacement::Cancel => UnsignedTx {
+            chain_id: original.chain_id,
+            nonce: original.nonce,
+            gas: U256::from(TRANSFER_GAS),
+            to: Some(from),
+            value: U256::zero(),
+            data: Bytes::default(),
+            fees: match fees {
+                Fees::Eip2930 { gas_price, .. } => Fees::Eip2930 {
+                    gas_price,
+                    access_list: Vec::new(),
+                },
+                Fees::Eip1559 {
+                    max_fee_per_gas,
+                    max_priority_fee_per_gas,
+                    ..
+                } => Fees::Eip1559 {
+                    max_fee_per_gas,
+                    max_priority_fee_per_gas,
+                    access_list: Vec::new(),
+                },
+                legacy => legacy,
+            },
+        },
+    }
+}
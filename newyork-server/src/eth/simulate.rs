@@ -0,0 +1,230 @@
+//! Simulation of a transaction with `eth_call` against the latest block,
+//! before the server co-signs it, to preview its outcome and the balances it
+//! moves.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use web3::types::{Address, BlockNumber, Bytes, CallRequest, H256, U256, U64};
+use web3::Transport;
+
+use super::erc20;
+use super::gas::{self, Revert};
+use super::pool::Connection;
+use super::tx::{Fees, UnsignedTx};
+use crate::policy::SimulationOutcome;
+
+/// State of an account replaced for the simulation only, the third
+/// parameter of geth's `eth_call`.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AccountOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance: Option<U256>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<U64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<Bytes>,
+    /// Replaces the whole storage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<BTreeMap<H256, H256>>,
+    /// Replaces these slots only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_diff: Option<BTreeMap<H256, H256>>,
+}
+
+pub type StateOverrides = BTreeMap<Address, AccountOverride>;
+
+/// What an address sends and receives, of the native currency or of a
+/// token.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct BalanceChange {
+    pub address: Address,
+    /// `None` for the native currency
+    pub token: Option<Address>,
+    pub sent: U256,
+    pub received: U256,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Simulation {
+    /// The block the transaction was simulated on top of
+    pub block_number: U64,
+    pub success: bool,
+    /// Why the transaction fails, a revert or an error of the node such as
+    /// insufficient funds
+    pub revert: Option<Revert>,
+    /// Return data of the call
+    pub output: Bytes,
+    /// Most the sender pays in fees, on top of `balance_changes`
+    pub max_fee: U256,
+    /// Value and ERC-20 transfers, empty when the transaction fails
+    pub balance_changes: Vec<BalanceChange>,
+}
+
+impl Simulation {
+    pub fn outcome(&self) -> SimulationOutcome {
+        match &self.revert {
+            None => SimulationOutcome::Success,
+            Some(revert) => SimulationOutcome::Revert {
+                reason: revert
+                    .reason
+                    .clone()
+                    .unwrap_or_else(|| revert.message.clone()),
+            },
+        }
+    }
+}
+
+pub fn check_overrides(overrides: &StateOverrides) -> Result<()> {
+    match overrides
+        .iter()
+        .find(|(_, account)| account.state.is_some() && account.state_diff.is_some())
+    {
+        Some((address, _)) => Err(anyhow!(
+            "Override of {:?} sets both state and stateDiff",
+            address
+        )),
+        None => Ok(()),
+    }
+}
+
+/// `tx` as sent by `from`, with its gas and fees so the node checks the
+/// sender can pay them.
+pub fn call_request(from: Address, tx: &UnsignedTx) -> CallRequest {
+    let mut request = CallRequest {
+        from: Some(from),
+        to: tx.to,
+        gas: Some(tx.gas),
+        value: Some(tx.value),
+        data: Some(tx.data.clone()),
+        ..Default::default()
+    };
+    match &tx.fees {
+        Fees::Legacy { gas_price } => request.gas_price = Some(*gas_price),
+        Fees::Eip2930 {
+            gas_price,
+            access_list,
+        } => {
+            request.gas_price = Some(*gas_price);
+            request.access_list = Some(access_list.clone());
+            request.transaction_type = Some(U64::from(1));
+        }
+        Fees::Eip1559 {
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+            access_list,
+        } => {
+            request.max_fee_per_gas = Some(*max_fee_per_gas);
+            request.max_priority_fee_per_gas = Some(*max_priority_fee_per_gas);
+            request.access_list = Some(access_list.clone());
+            request.transaction_type = Some(U64::from(2));
+        }
+    }
+    request
+}
+
+/// `gas` times the highest price per gas `tx` can pay.
+pub fn max_fee(tx: &UnsignedTx) -> U256 {
+    let price = match &tx.fees {
+        Fees::Legacy { gas_price } | Fees::Eip2930 { gas_price, .. } => *gas_price,
+        Fees::Eip1559 {
+            max_fee_per_gas, ..
+        } => *max_fee_per_gas,
+    };
+    tx.gas.saturating_mul(price)
+}
+
+/// The value `tx` sends and the ERC-20 transfer it calls, by address.
+pub fn balance_changes(from: Address, tx: &UnsignedTx) -> Vec<BalanceChange> {
+    let mut changes: BTreeMap<(Address, Option<Address>), (U256, U256)> = BTreeMap::new();
+    let mut add = |sender: Address, recipient: Option<Address>, token, amount: U256| {
+        if amount.is_zero() {
+            return;
+        }
+        let sent = &mut changes.entry((sender, token)).or_default().0;
+        *sent = sent.saturating_add(amount);
+        // The address of a created contract is not known here
+        if let Some(recipient) = recipient {
+            let received = &mut changes.entry((recipient, token)).or_default().1;
+            *received = received.saturating_add(amount);
+        }
+    };
+
+    add(from, tx.to, None, tx.value);
+    if let (Some(token), Some(transfer)) = (tx.to, erc20::decode_transfer(&tx.data.0)) {
+        add(
+            transfer.from.unwrap_or(from),
+            Some(transfer.to),
+            Some(token),
+            transfer.amount,
+        );
+    }
+    changes
+        .into_iter()
+        .map(|((address, token), (sent, received))| BalanceChange {
+            address,
+            token,
+            sent,
+            received,
+        })
+        .collect()
+}
+
+/// Runs `tx` from `from` with `eth_call` on the latest block, with the
+/// accounts of `overrides` replaced.
+pub async fn simulate(
+    web3: &Connection,
+    from: Address,
+    tx: &UnsignedTx,
+    overrides: &StateOverrides,
+) -> Result<Simulation> {
+    check_overrides(overrides)?;
+    let block_number = web3.eth().block_number().await?;
+    let mut params = vec![
+        serde_json::to_value(call_request(from, tx))?,
+        serde_json::to_value(BlockNumber::Number(block_number))?,
+    ];
+    // Nodes without overrides reject a third parameter
+    if !overrides.is_empty() {
+        params.push(serde_json::to_value(overrides)?);
+    }
+
+    let (output, mut revert) = match web3.transport().execute("eth_call", params).await {
+        Ok(output) => (serde_json::from_value::<Bytes>(output)?, None),
+        Err(e) => match (gas::revert_of(&e), e) {
+            (Some(revert), _) => (Bytes::default(), Some(revert)),
+            (None, web3::Error::Rpc(rpc)) => (
+                Bytes::default(),
+                Some(Revert {
+                    message: rpc.message,
+                    reason: None,
+                    data: None,
+                }),
+            ),
+            (None, e) => return Err(e.into()),
+        },
+    };
+    if revert.is_none()
+        && erc20::decode_transfer(&tx.data.0).is_some()
+        && erc20::returned_false(&output.0)
+    {
+        revert = Some(Revert {
+            message: "The token transfer returned false".to_string(),
+            reason: Some("transfer returned false".to_string()),
+            data: None,
+        });
+    }
+
+    Ok(Simulation {
+        block_number,
+        success: revert.is_none(),
+        balance_changes: match revert {
+            None => balance_changes(from, tx),
+            Some(_) => Vec::new(),
+        },
+        revert,
+        output,
+        max_fee: max_fee(tx),
+    })
+}
@@ -0,0 +1,438 @@
+//! Status of the transactions the server broadcast, followed on their chain
+//! until they are confirmed, dropped or replaced. Statuses are stored per
+//! user, and every change is published to the subscribers of `subscribe`.
+
+use std::collections::BTreeSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use rocket::fairing::AdHoc;
+use tokio::sync::broadcast;
+use web3::types::{Address, BlockNumber, TransactionId, TransactionReceipt, H256, U256};
+
+use super::pool::{Connection, RpcPool};
+use super::tx::{SignedTx, UnsignedTx};
+use crate::storage::db::{self, MPCStruct, DB};
+use crate::AppConfig;
+
+/// Updates kept for subscribers that fall behind.
+const UPDATES_CAPACITY: usize = 256;
+/// Key of the list of transactions still followed, shared by all users.
+const WATCHED_KEY: &str = "eth_watched";
+
+#[derive(Debug)]
+pub enum TrackingStruct {
+    TrackedTx,
+    /// Hashes of the tracked transactions of a sender's nonce
+    NonceTxs,
+    /// Transactions not yet confirmed, dropped or replaced
+    Watched,
+}
+
+impl MPCStruct for TrackingStruct {
+    fn to_string(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TxStatus {
+    /// Sent and not yet in a block
+    Pending,
+    /// In a block, with fewer confirmations than the chain requires
+    Mined,
+    Confirmed,
+    /// Left the pools of the nodes without being mined
+    Dropped,
+    /// Another transaction of the same nonce was mined
+    Replaced,
+}
+
+impl TxStatus {
+    /// The transaction is no longer followed.
+    pub fn is_final(self) -> bool {
+        matches!(
+            self,
+            TxStatus::Confirmed | TxStatus::Dropped | TxStatus::Replaced
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TxReceipt {
+    pub block_number: u64,
+    pub block_hash: H256,
+    /// `false` when the transaction reverted
+    pub success: Option<bool>,
+    pub gas_used: Option<U256>,
+    pub effective_gas_price: Option<U256>,
+    /// Set for contract creation
+    pub contract_address: Option<Address>,
+}
+
+impl TxReceipt {
+    /// `None` for receipts of pending blocks.
+    pub fn new(receipt: &TransactionReceipt) -> Option<Self> {
+        Some(TxReceipt {
+            block_number: receipt.block_number?.as_u64(),
+            block_hash: receipt.block_hash?,
+            success: receipt.status.map(|status| status.as_u64() == 1),
+            gas_used: receipt.gas_used,
+            effective_gas_price: receipt.effective_gas_price,
+            contract_address: receipt.contract_address,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TrackedTx {
+    pub hash: H256,
+    pub chain_id: u64,
+    pub from: Address,
+    pub nonce: U256,
+    /// The transaction as signed, to build replacements from
+    pub tx: UnsignedTx,
+    pub status: TxStatus,
+    /// Set while mined
+    pub receipt: Option<TxReceipt>,
+    /// Blocks from the transaction's to the head, both included
+    pub confirmations: u64,
+    /// Confirmations of the chain, after which the transaction is confirmed
+    pub required_confirmations: u64,
+    /// The tracked transaction of the same nonce mined instead
+    pub replaced_by: Option<H256>,
+    /// Unix times in seconds
+    pub submitted_at: u64,
+    /// Last time a node had the transaction
+    pub last_seen_at: u64,
+    pub updated_at: u64,
+}
+
+/// What the chain reports about a transaction.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Observation {
+    Mined {
+        receipt: TxReceipt,
+        head: u64,
+    },
+    /// In the pool of the node
+    Pending,
+    /// Unknown to the node. `nonce_used` when the sender's nonce is mined.
+    Unknown {
+        nonce_used: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct Watched {
+    pub user_id: String,
+    pub hash: H256,
+}
+
+fn now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+impl TrackedTx {
+    pub fn new(chain_id: u64, required_confirmations: u64, signed: &SignedTx, now: u64) -> Self {
+        TrackedTx {
+            hash: signed.hash,
+            chain_id,
+            from: signed.from,
+            nonce: signed.tx.nonce,
+            tx: signed.tx.clone(),
+            status: TxStatus::Pending,
+            receipt: None,
+            confirmations: 0,
+            required_confirmations,
+            replaced_by: None,
+            submitted_at: now,
+            last_seen_at: now,
+            updated_at: now,
+        }
+    }
+
+    /// Applies what the chain reports at `now`, returns whether the status,
+    /// receipt or confirmations changed. A transaction no node has seen for
+    /// `drop_after` is dropped.
+    pub fn update(&mut self, observation: Observation, drop_after: Duration, now: u64) -> bool {
+        let before = (self.status, self.receipt.clone(), self.confirmations);
+        match observation {
+            Observation::Mined { receipt, head } => {
+                self.confirmations = (head + 1).saturating_sub(receipt.block_number);
+                self.status = if self.confirmations >= self.required_confirmations {
+                    TxStatus::Confirmed
+                } else {
+                    TxStatus::Mined
+                };
+                self.receipt = Some(receipt);
+                self.last_seen_at = now;
+            }
+            // Also a mined transaction whose block was reorganized away
+            Observation::Pending => {
+                self.status = TxStatus::Pending;
+                self.receipt = None;
+                self.confirmations = 0;
+                self.last_seen_at = now;
+            }
+            Observation::Unknown { nonce_used } => {
+                self.receipt = None;
+                self.confirmations = 0;
+                self.status = if nonce_used {
+                    TxStatus::Replaced
+                } else if now >= self.last_seen_at + drop_after.as_secs() {
+                    TxStatus::Dropped
+                } else {
+                    TxStatus::Pending
+                };
+            }
+        }
+        let changed = before != (self.status, self.receipt.clone(), self.confirmations);
+        if changed {
+            self.updated_at = now;
+        }
+        changed
+    }
+}
+
+pub struct TxTracker {
+    watched: Mutex<BTreeSet<Watched>>,
+    /// Held while a transaction is added to the `NonceTxs` list of its nonce
+    tracking: Mutex<()>,
+    updates: broadcast::Sender<(String, TrackedTx)>,
+}
+
+impl TxTracker {
+    /// Resumes following the transactions watched before a restart.
+    pub fn new(db: &DB) -> Result<Self> {
+        let watched: Option<BTreeSet<Watched>> =
+            db::get(db, WATCHED_KEY, WATCHED_KEY, &TrackingStruct::Watched)?;
+        let (updates, _) = broadcast::channel(UPDATES_CAPACITY);
+        Ok(TxTracker {
+            watched: Mutex::new(watched.unwrap_or_default()),
+            tracking: Mutex::new(()),
+            updates,
+        })
+    }
+
+    /// Updates of every user's transactions, with the user id.
+    pub fn subscribe(&self) -> broadcast::Receiver<(String, TrackedTx)> {
+        self.updates.subscribe()
+    }
+
+    /// Starts following a transaction sent on `chain_id`. A transaction
+    /// already tracked keeps its status.
+    pub fn track(
+        &self,
+        db: &DB,
+        user_id: &str,
+        chain_id: u64,
+        required_confirmations: u64,
+        signed: &SignedTx,
+    ) -> Result<TrackedTx> {
+        let _tracking = self.tracking.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(tracked) = get(db, user_id, signed.hash)? {
+            return Ok(tracked);
+        }
+        let tracked = TrackedTx::new(chain_id, required_confirmations, signed, now()?);
+        let key = nonce_key(&tracked);
+        let mut hashes: Vec<H256> =
+            db::get(db, user_id, &key, &TrackingStruct::NonceTxs)?.unwrap_or_default();
+        hashes.push(tracked.hash);
+        db::insert(db, user_id, &key, &TrackingStruct::NonceTxs, &hashes)?;
+        self.save(db, user_id, &tracked)?;
+        Ok(tracked)
+    }
+
+    /// Refreshes every watched transaction.
+    pub async fn poll(&self, db: &DB, pool: &RpcPool, drop_after: Duration) {
+        let watched = self
+            .watched
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        for entry in watched {
+            if let Err(e) = self.refresh(db, pool, &entry, drop_after).await {
+                warn!("Transaction {:?} status not updated ({:#})", entry.hash, e);
+            }
+        }
+    }
+
+    async fn refresh(
+        &self,
+        db: &DB,
+        pool: &RpcPool,
+        entry: &Watched,
+        drop_after: Duration,
+    ) -> Result<()> {
+        let mut tracked = match get(db, &entry.user_id, entry.hash)? {
+            Some(tracked) => tracked,
+            None => return self.unwatch(db, entry),
+        };
+        let web3 = pool.connection(tracked.chain_id).await?;
+        let observation = observe(&web3, &tracked).await?;
+        if tracked.update(observation, drop_after, now()?) {
+            if tracked.status == TxStatus::Replaced {
+                tracked.replaced_by = replacement(db, &entry.user_id, &tracked)?;
+            }
+            self.save(db, &entry.user_id, &tracked)
+        } else {
+            db::insert(
+                db,
+                &entry.user_id,
+                &format!("{:?}", tracked.hash),
+                &TrackingStruct::TrackedTx,
+                &tracked,
+            )
+        }
+    }
+
+    /// Stores `tracked`, follows it until final and notifies subscribers.
+    fn save(&self, db: &DB, user_id: &str, tracked: &TrackedTx) -> Result<()> {
+        db::insert(
+            db,
+            user_id,
+            &format!("{:?}", tracked.hash),
+            &TrackingStruct::TrackedTx,
+            tracked,
+        )?;
+        let entry = Watched {
+            user_id: user_id.to_string(),
+            hash: tracked.hash,
+        };
+        if tracked.status.is_final() {
+            self.unwatch(db, &entry)?;
+        } else {
+            let mut watched = self.watched.lock().unwrap_or_else(|e| e.into_inner());
+            if watched.insert(entry) {
+                db::insert(
+                    db,
+                    WATCHED_KEY,
+                    WATCHED_KEY,
+                    &TrackingStruct::Watched,
+                    &*watched,
+                )?;
+            }
+        }
+        // No subscribers is not an error
+        let _ = self.updates.send((user_id.to_string(), tracked.clone()));
+        Ok(())
+    }
+
+    fn unwatch(&self, db: &DB, entry: &Watched) -> Result<()> {
+        let mut watched = self.watched.lock().unwrap_or_else(|e| e.into_inner());
+        if watched.remove(entry) {
+            db::insert(
+                db,
+                WATCHED_KEY,
+                WATCHED_KEY,
+                &TrackingStruct::Watched,
+                &*watched,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub fn get(db: &DB, user_id: &str, hash: H256) -> Result<Option<TrackedTx>> {
+    db::get(
+        db,
+        user_id,
+        &format!("{:?}", hash),
+        &TrackingStruct::TrackedTx,
+    )
+}
+
+/// The tracked transactions of the same sender and nonce as `tracked`,
+/// including it.
+pub fn same_nonce(db: &DB, user_id: &str, tracked: &TrackedTx) -> Result<Vec<TrackedTx>> {
+    let hashes: Vec<H256> =
+        db::get(db, user_id, &nonce_key(tracked), &TrackingStruct::NonceTxs)?.unwrap_or_default();
+    let mut txs = Vec::new();
+    for hash in hashes {
+        txs.extend(get(db, user_id, hash)?);
+    }
+    Ok(txs)
+}
+
+fn nonce_key(tracked: &TrackedTx) -> String {
+    format!("{}_{:?}_{}", tracked.chain_id, tracked.from, tracked.nonce)
+}
+
+/// The tracked transaction mined with the nonce of `replaced`.
+fn replacement(db: &DB, user_id: &str, replaced: &TrackedTx) -> Result<Option<H256>> {
+    Ok(same_nonce(db, user_id, replaced)?
+        .into_iter()
+        .find(|tx| tx.hash != replaced.hash && tx.receipt.is_some())
+        .map(|tx| tx.hash))
+}
+
+async fn receipt(web3: &Connection, hash: H256) -> Result<Option<TxReceipt>> {
+    Ok(web3
+        .eth()
+        .transaction_receipt(hash)
+        .await?
+        .as_ref()
+        .and_then(TxReceipt::new))
+}
+
+async fn mined(web3: &Connection, receipt: TxReceipt) -> Result<Observation> {
+    let head = web3.eth().block_number().await?.as_u64();
+    Ok(Observation::Mined { receipt, head })
+}
+
+async fn observe(web3: &Connection, tracked: &TrackedTx) -> Result<Observation> {
+    if let Some(receipt) = receipt(web3, tracked.hash).await? {
+        return mined(web3, receipt).await;
+    }
+    if web3
+        .eth()
+        .transaction(TransactionId::Hash(tracked.hash))
+        .await?
+        .is_some()
+    {
+        return Ok(Observation::Pending);
+    }
+    let mined_nonces = web3
+        .eth()
+        .transaction_count(tracked.from, Some(BlockNumber::Latest))
+        .await?;
+    if mined_nonces > tracked.nonce {
+        // Mined since the receipt was asked for
+        if let Some(receipt) = receipt(web3, tracked.hash).await? {
+            return mined(web3, receipt).await;
+        }
+        return Ok(Observation::Unknown { nonce_used: true });
+    }
+    Ok(Observation::Unknown { nonce_used: false })
+}
+
+/// Polls the watched transactions every `interval`, starting at liftoff.
+pub fn fairing(interval: Duration, drop_after: Duration) -> AdHoc {
+    AdHoc::on_liftoff("Transaction tracking", move |rocket| {
+        Box::pin(async move {
+            let (db, pool, tracker): (Arc<DB>, Arc<RpcPool>, Arc<TxTracker>) =
+                match rocket.state::<AppConfig>() {
+                    Some(config) => (config.db.clone(), config.rpc.clone(), config.txs.clone()),
+                    None => return,
+                };
+
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                loop {
+                    ticker.tick().await;
+                    tracker.poll(&db, &pool, drop_after).await;
+                }
+            });
+        })
+    })
+}
+
+/// Parses a transaction hash from a path.
+pub fn parse_hash(hash: &str) -> Result<H256> {
+    hash.trim_start_matches("0x")
+        .parse()
+        .map_err(|_| anyhow!("{} is not a transaction hash", hash))
+}
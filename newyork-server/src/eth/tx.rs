@@ -0,0 +1,360 @@
+//! Unsigned Ethereum transactions, RLP encoded and hashed by the server so
+//! the hash it co-signs is the one of the transaction it was shown, and the
+//! signed transactions clients hand back for broadcast.
+
+use anyhow::{anyhow, bail, Result};
+use rlp::{Rlp, RlpStream};
+use web3::signing::{self, keccak256};
+use web3::types::{AccessList, AccessListItem, Address, Bytes, H256, U256};
+
+use super::erc20;
+use crate::policy::{TransferSummary, TxSummary};
+
+const EIP2930_TX_TYPE: u8 = 1;
+const EIP1559_TX_TYPE: u8 = 2;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct UnsignedTx {
+    pub chain_id: u64,
+    pub nonce: U256,
+    pub gas: U256,
+    /// `None` for contract creation
+    pub to: Option<Address>,
+    pub value: U256,
+    #[serde(default)]
+    pub data: Bytes,
+    #[serde(flatten)]
+    pub fees: Fees,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Fees {
+    Legacy {
+        gas_price: U256,
+    },
+    Eip2930 {
+        gas_price: U256,
+        #[serde(default)]
+        access_list: AccessList,
+    },
+    Eip1559 {
+        max_fee_per_gas: U256,
+        max_priority_fee_per_gas: U256,
+        #[serde(default)]
+        access_list: AccessList,
+    },
+}
+
+/// A secp256k1 signature with its recovery id, as produced by two-party
+/// signing.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TxSignature {
+    pub r: U256,
+    pub s: U256,
+    pub recid: u8,
+}
+
+/// A transaction decoded from its raw bytes, with the sender its signature
+/// recovers to.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SignedTx {
+    pub tx: UnsignedTx,
+    pub signature: TxSignature,
+    pub from: Address,
+    pub hash: H256,
+}
+
+impl SignedTx {
+    /// Decodes a legacy, EIP-2930 or EIP-1559 transaction. Legacy
+    /// transactions must be replay protected by EIP-155.
+    pub fn decode(raw: &[u8]) -> Result<Self> {
+        let (tx, signature) = match raw.first() {
+            None => bail!("Empty raw transaction"),
+            Some(&EIP2930_TX_TYPE) => decode_typed(&raw[1..], EIP2930_TX_TYPE)?,
+            Some(&EIP1559_TX_TYPE) => decode_typed(&raw[1..], EIP1559_TX_TYPE)?,
+            // RLP lists start at 0xc0
+            Some(first) if *first >= 0xc0 => decode_legacy(raw)?,
+            Some(first) => bail!("Unsupported transaction type {}", first),
+        };
+        // Rejects trailing bytes and non canonical encodings, so the hash
+        // nodes compute is the one returned here
+        if tx.encode_signed(&signature)?.0 != raw {
+            bail!("Raw transaction is not canonically encoded");
+        }
+        let from = signature.recover(tx.sighash())?;
+        Ok(SignedTx {
+            tx,
+            signature,
+            from,
+            hash: keccak256(raw).into(),
+        })
+    }
+
+    /// Checks the nonce against the sender's `mined` and `pending`
+    /// transaction counts. Nonces of pending transactions are accepted, to
+    /// replace them.
+    pub fn check_nonce(&self, mined: U256, pending: U256) -> Result<()> {
+        if self.tx.nonce < mined {
+            bail!(
+                "Nonce {} of {:?} is already used, the next one is {}",
+                self.tx.nonce,
+                self.from,
+                pending
+            );
+        }
+        if self.tx.nonce > pending {
+            bail!(
+                "Nonce {} of {:?} leaves a gap, the next one is {}",
+                self.tx.nonce,
+                self.from,
+                pending
+            );
+        }
+        Ok(())
+    }
+}
+
+impl TxSignature {
+    /// The address that signed `hash`.
+    pub fn recover(&self, hash: H256) -> Result<Address> {
+        let mut signature = [0u8; 64];
+        self.r.to_big_endian(&mut signature[..32]);
+        self.s.to_big_endian(&mut signature[32..]);
+        signing::recover(hash.as_bytes(), &signature, self.recid as i32)
+            .map_err(|_| anyhow!("Invalid transaction signature"))
+    }
+}
+
+fn decode_legacy(raw: &[u8]) -> Result<(UnsignedTx, TxSignature)> {
+    let rlp = Rlp::new(raw);
+    if rlp.item_count()? != 9 {
+        bail!("A legacy transaction has 9 fields");
+    }
+    let v: u64 = rlp.val_at(6)?;
+    if v < 35 {
+        bail!("Transactions without EIP-155 replay protection are not accepted");
+    }
+    let tx = UnsignedTx {
+        chain_id: (v - 35) / 2,
+        nonce: rlp.val_at(0)?,
+        gas: rlp.val_at(2)?,
+        to: decode_to(&rlp, 3)?,
+        value: rlp.val_at(4)?,
+        data: Bytes(rlp.val_at(5)?),
+        fees: Fees::Legacy {
+            gas_price: rlp.val_at(1)?,
+        },
+    };
+    let signature = TxSignature {
+        r: rlp.val_at(7)?,
+        s: rlp.val_at(8)?,
+        recid: ((v - 35) % 2) as u8,
+    };
+    Ok((tx, signature))
+}
+
+fn decode_typed(payload: &[u8], tx_type: u8) -> Result<(UnsignedTx, TxSignature)> {
+    let rlp = Rlp::new(payload);
+    // EIP-1559 has one more fee field before the call
+    let fees_len = if tx_type == EIP1559_TX_TYPE { 2 } else { 1 };
+    if rlp.item_count()? != 10 + fees_len {
+        bail!(
+            "A type {} transaction has {} fields",
+            tx_type,
+            10 + fees_len
+        );
+    }
+    let call = 2 + fees_len;
+    let access_list = rlp
+        .at(call + 4)?
+        .iter()
+        .map(|item| {
+            Ok(AccessListItem {
+                address: item.val_at(0)?,
+                storage_keys: item.list_at(1)?,
+            })
+        })
+        .collect::<Result<AccessList>>()?;
+    let fees = if tx_type == EIP1559_TX_TYPE {
+        Fees::Eip1559 {
+            max_priority_fee_per_gas: rlp.val_at(2)?,
+            max_fee_per_gas: rlp.val_at(3)?,
+            access_list,
+        }
+    } else {
+        Fees::Eip2930 {
+            gas_price: rlp.val_at(2)?,
+            access_list,
+        }
+    };
+    let recid: u8 = rlp.val_at(call + 5)?;
+    if recid > 1 {
+        bail!("Signature parity {} is not 0 or 1", recid);
+    }
+    let tx = UnsignedTx {
+        chain_id: rlp.val_at(0)?,
+        nonce: rlp.val_at(1)?,
+        gas: rlp.val_at(call)?,
+        to: decode_to(&rlp, call + 1)?,
+        value: rlp.val_at(call + 2)?,
+        data: Bytes(rlp.val_at(call + 3)?),
+        fees,
+    };
+    let signature = TxSignature {
+        r: rlp.val_at(call + 6)?,
+        s: rlp.val_at(call + 7)?,
+        recid,
+    };
+    Ok((tx, signature))
+}
+
+/// The destination, empty for contract creation.
+fn decode_to(rlp: &Rlp, index: usize) -> Result<Option<Address>> {
+    let to = rlp.at(index)?;
+    if to.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(to.as_val()?))
+}
+
+impl UnsignedTx {
+    /// Rejects transactions no node would accept.
+    pub fn validate(&self) -> Result<()> {
+        if self.gas.is_zero() {
+            bail!("Transaction gas must be above zero");
+        }
+        if let Fees::Eip1559 {
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+            ..
+        } = &self.fees
+        {
+            if max_priority_fee_per_gas > max_fee_per_gas {
+                bail!(
+                    "Priority fee {} is above the max fee per gas {}",
+                    max_priority_fee_per_gas,
+                    max_fee_per_gas
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The hash signed by the sender.
+    pub fn sighash(&self) -> H256 {
+        keccak256(&self.encode(None)).into()
+    }
+
+    /// The raw transaction to broadcast.
+    pub fn encode_signed(&self, signature: &TxSignature) -> Result<Bytes> {
+        if signature.recid > 1 {
+            return Err(anyhow!(
+                "Recovery id {} cannot be used on Ethereum",
+                signature.recid
+            ));
+        }
+        Ok(Bytes(self.encode(Some(signature))))
+    }
+
+    pub fn summary(&self) -> TxSummary {
+        TxSummary {
+            chain_id: self.chain_id,
+            to: self.to,
+            value: self.value,
+            gas: self.gas,
+            token_transfer: self
+                .to
+                .and(erc20::decode_transfer(&self.data.0))
+                .map(|transfer| TransferSummary {
+                    recipient: transfer.to,
+                    amount: transfer.amount,
+                }),
+            simulation: None,
+            hashed_by_server: true,
+        }
+    }
+
+    fn encode(&self, signature: Option<&TxSignature>) -> Vec<u8> {
+        let mut stream = RlpStream::new();
+        match &self.fees {
+            Fees::Legacy { gas_price } => {
+                stream.begin_list(9);
+                stream.append(&self.nonce);
+                stream.append(gas_price);
+                self.append_call(&mut stream);
+                match signature {
+                    // EIP-155 replay protection
+                    Some(signature) => {
+                        stream.append(&(signature.recid as u64 + 35 + self.chain_id * 2));
+                        stream.append(&signature.r);
+                        stream.append(&signature.s);
+                    }
+                    None => {
+                        stream.append(&self.chain_id);
+                        stream.append(&0u8);
+                        stream.append(&0u8);
+                    }
+                }
+                stream.out().to_vec()
+            }
+            Fees::Eip2930 {
+                gas_price,
+                access_list,
+            } => {
+                stream.begin_list(if signature.is_some() { 11 } else { 8 });
+                stream.append(&self.chain_id);
+                stream.append(&self.nonce);
+                stream.append(gas_price);
+                self.append_call(&mut stream);
+                append_access_list(&mut stream, access_list);
+                append_parity(&mut stream, signature);
+                [&[EIP2930_TX_TYPE], stream.as_raw()].concat()
+            }
+            Fees::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+                access_list,
+            } => {
+                stream.begin_list(if signature.is_some() { 12 } else { 9 });
+                stream.append(&self.chain_id);
+                stream.append(&self.nonce);
+                stream.append(max_priority_fee_per_gas);
+                stream.append(max_fee_per_gas);
+                self.append_call(&mut stream);
+                append_access_list(&mut stream, access_list);
+                append_parity(&mut stream, signature);
+                [&[EIP1559_TX_TYPE], stream.as_raw()].concat()
+            }
+        }
+    }
+
+    /// Gas, destination, value and data, in the order every envelope uses.
+    fn append_call(&self, stream: &mut RlpStream) {
+        stream.append(&self.gas);
+        match &self.to {
+            Some(to) => stream.append(to),
+            None => stream.append(&""),
+        };
+        stream.append(&self.value);
+        stream.append(&self.data.0);
+    }
+}
+
+fn append_access_list(stream: &mut RlpStream, access_list: &AccessList) {
+    stream.begin_list(access_list.len());
+    for item in access_list {
+        stream.begin_list(2);
+        stream.append(&item.address);
+        stream.append_list(&item.storage_keys);
+    }
+}
+
+/// The signature of typed transactions, with the parity instead of `v`.
+fn append_parity(stream: &mut RlpStream, signature: Option<&TxSignature>) {
+    if let Some(signature) = signature {
+        stream.append(&signature.recid);
+        stream.append(&signature.r);
+        stream.append(&signature.s);
+    }
+}
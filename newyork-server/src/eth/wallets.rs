@@ -0,0 +1,56 @@
+//! Ethereum addresses of the caller's wallets. The server learns the address
+//! of a child key the first time it co-signs with it, so transactions handed
+//! back for broadcast can be traced to one of the caller's wallets.
+
+use anyhow::{bail, Result};
+use web3::signing::keccak256;
+use web3::types::Address;
+
+use crate::storage::db::{self, MPCStruct, DB};
+
+#[derive(Debug)]
+pub enum WalletStruct {
+    Address,
+}
+
+impl MPCStruct for WalletStruct {
+    fn to_string(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct WalletAddress {
+    pub address: Address,
+    pub wallet_id: String,
+    /// `x/y` position of the child key
+    pub derivation_path: String,
+}
+
+/// The address of an uncompressed secp256k1 public key, `0x04 || x || y`.
+pub fn address_of(public_key: &[u8]) -> Result<Address> {
+    if public_key.len() != 65 || public_key[0] != 4 {
+        bail!("Not an uncompressed secp256k1 public key");
+    }
+    Ok(Address::from_slice(&keccak256(&public_key[1..])[12..]))
+}
+
+pub fn record(db: &DB, user_id: &str, wallet: &WalletAddress) -> Result<()> {
+    db::insert(
+        db,
+        user_id,
+        &format!("{:?}", wallet.address),
+        &WalletStruct::Address,
+        wallet,
+    )
+}
+
+/// The wallet of `user_id` that `address` belongs to.
+pub fn lookup(db: &DB, user_id: &str, address: Address) -> Result<Option<WalletAddress>> {
+    db::get(
+        db,
+        user_id,
+        &format!("{:?}", address),
+        &WalletStruct::Address,
+    )
+}
@@ -6,23 +6,38 @@ extern crate rocket;
 extern crate serde_derive;
 
 #[macro_use]
-extern crate log;
+extern crate tracing;
+
+#[macro_use]
+extern crate lazy_static;
 
 #[cfg(test)]
 #[macro_use]
 extern crate time_test;
 
 pub mod auth;
+pub mod eth;
+pub mod policy;
 pub mod routes;
 pub mod server;
 pub mod storage;
+pub mod telemetry;
 pub mod tests;
 pub mod utils;
 
 pub struct AppConfig {
-    pub db: storage::db::DB,
+    pub db: std::sync::Arc<storage::db::DB>,
     pub hcmc_api: String,
-    pub alchemy_api: String,
+    pub chains: eth::chains::ChainRegistry,
+    pub rpc: std::sync::Arc<eth::pool::RpcPool>,
+    pub txs: std::sync::Arc<eth::tracking::TxTracker>,
+    pub nonces: eth::nonces::NonceManager,
+    pub settings: utils::settings::Settings,
+    pub metrics: std::sync::Arc<telemetry::metrics::Metrics>,
+    pub traces: telemetry::otel::SessionTraces,
+    pub audit: storage::audit::AuditLog,
+    pub policies: policy::Policies,
+    pub siwe: auth::siwe::SiweAuth,
 }
 
 pub type AnyhowError = rocket::response::Debug<anyhow::Error>;
@@ -1,9 +1,20 @@
-use log::info;
 use server_lib::server;
+use server_lib::telemetry::{logging, otel};
+use server_lib::utils::settings::{LogSettings, Settings};
+use tracing::error;
 
 #[rocket::main]
 async fn main() {
-    env_logger::init();
-    info!("Server starting up");
-    let _ = server::get_server().launch().await;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let rocket = match Settings::load(&args).and_then(server::build_server) {
+        Ok(rocket) => rocket,
+        Err(e) => {
+            // Settings may be what failed, so fall back to the default log setup
+            logging::init(&LogSettings::default(), None);
+            error!("Server startup failed: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let _ = rocket.launch().await;
+    otel::shutdown();
 }
@@ -0,0 +1,398 @@
+//! Server-enforced signing policy.
+//!
+//! A wallet can carry a [`Policy`], a list of declarative rules that
+//! `sign_second` evaluates before the server contributes its share of the
+//! signature. A wallet without a policy signs anything, as before. Rules are
+//! checked against a [`TxSummary`] of the transaction being signed, so a
+//! wallet with any rule refuses requests that do not describe one.
+//! Messages are checked against a [`MessageSummary`] instead, and a wallet
+//! with rules only signs them with the `allow_messages` rule.
+
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{error, fmt};
+
+use anyhow::Result;
+use web3::types::{Address, U256};
+
+use crate::storage::db::{self, MPCStruct, DB};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug)]
+pub enum PolicyStruct {
+    Policy,
+    DailySpend,
+}
+
+impl MPCStruct for PolicyStruct {
+    fn to_string(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "rule", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Rule {
+    /// Total value in wei signed per UTC day
+    DailyLimit {
+        wei: U256,
+    },
+    /// Only these destinations may be used
+    AllowDestinations {
+        addresses: Vec<Address>,
+    },
+    DenyDestinations {
+        addresses: Vec<Address>,
+    },
+    MaxGas {
+        gas: U256,
+    },
+    ChainIds {
+        ids: Vec<u64>,
+    },
+    /// Personal messages and typed data may be signed
+    AllowMessages,
+    /// Transactions are simulated before signing and refused if they revert
+    DenyReverts,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    pub rules: Vec<Rule>,
+}
+
+/// The transaction a signature is requested for.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TxSummary {
+    pub chain_id: u64,
+    /// `None` for contract creation
+    pub to: Option<Address>,
+    pub value: U256,
+    pub gas: U256,
+    /// The ERC-20 transfer in the call data, `to` being the token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_transfer: Option<TransferSummary>,
+    /// Set by the server when it simulated the transaction
+    #[serde(skip)]
+    pub simulation: Option<SimulationOutcome>,
+    /// Set when the server hashed the transaction itself, so the summary is
+    /// known to describe the signed hash. Never set for a client's summary.
+    #[serde(skip)]
+    pub hashed_by_server: bool,
+}
+
+/// A token transfer, checked by the destination rules like a transfer of
+/// value.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TransferSummary {
+    pub recipient: Address,
+    pub amount: U256,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SimulationOutcome {
+    Success,
+    Revert { reason: String },
+}
+
+/// The message a signature is requested for, its digest computed by the
+/// server.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MessageSummary {
+    /// EIP-191 `personal_sign`
+    Personal {
+        /// `None` when the message is not UTF-8
+        text: Option<String>,
+        length: usize,
+    },
+    /// EIP-712, described by its domain
+    TypedData {
+        primary_type: String,
+        name: Option<String>,
+        version: Option<String>,
+        chain_id: Option<u64>,
+        verifying_contract: Option<Address>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Decision {
+    pub allowed: bool,
+    pub violations: Vec<String>,
+}
+
+/// Error returned when a signature is refused, recorded as `denied` in the
+/// audit log.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PolicyDenied(pub Vec<String>);
+
+impl fmt::Display for PolicyDenied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Denied by signing policy: {}", self.0.join("; "))
+    }
+}
+
+impl error::Error for PolicyDenied {}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Clone)]
+struct DailySpend {
+    /// Days since the Unix epoch
+    day: u64,
+    spent: U256,
+}
+
+impl Policy {
+    /// Checks `tx` against every rule, `spent_today` being the value already
+    /// signed by the wallet today.
+    pub fn evaluate(&self, tx: Option<&TxSummary>, spent_today: U256) -> Decision {
+        let tx = match tx {
+            Some(tx) => tx,
+            None if self.rules.is_empty() => return Decision::new(Vec::new()),
+            None => {
+                return Decision::new(vec![
+                    "the wallet has a signing policy, the transaction must be described"
+                        .to_string(),
+                ])
+            }
+        };
+
+        let mut violations = Vec::new();
+        for rule in &self.rules {
+            match rule {
+                Rule::DailyLimit { wei } => {
+                    match spent_today.checked_add(tx.value) {
+                        Some(total) if total <= *wei => {}
+                        _ => violations.push(format!(
+                            "daily limit of {} wei exceeded, {} wei already signed today",
+                            wei, spent_today
+                        )),
+                    }
+                    // The limit is in wei, token amounts cannot be counted against it
+                    if tx.token_transfer.is_some() {
+                        violations.push(
+                            "token transfers are not allowed with a daily limit".to_string(),
+                        );
+                    }
+                }
+                Rule::AllowDestinations { addresses } => {
+                    match tx.to {
+                        Some(to) if addresses.contains(&to) => {}
+                        Some(to) => {
+                            violations.push(format!("destination {:?} is not allowed", to))
+                        }
+                        None => violations.push("contract creation is not allowed".to_string()),
+                    }
+                    if let Some(transfer) = tx
+                        .token_transfer
+                        .as_ref()
+                        .filter(|transfer| !addresses.contains(&transfer.recipient))
+                    {
+                        violations.push(format!(
+                            "token recipient {:?} is not allowed",
+                            transfer.recipient
+                        ));
+                    }
+                }
+                Rule::DenyDestinations { addresses } => {
+                    if let Some(to) = tx.to.filter(|to| addresses.contains(to)) {
+                        violations.push(format!("destination {:?} is denied", to));
+                    }
+                    if let Some(transfer) = tx
+                        .token_transfer
+                        .as_ref()
+                        .filter(|transfer| addresses.contains(&transfer.recipient))
+                    {
+                        violations.push(format!(
+                            "token recipient {:?} is denied",
+                            transfer.recipient
+                        ));
+                    }
+                }
+                Rule::MaxGas { gas } => {
+                    if tx.gas > *gas {
+                        violations.push(format!("gas {} is above the maximum of {}", tx.gas, gas));
+                    }
+                }
+                Rule::ChainIds { ids } => {
+                    if !ids.contains(&tx.chain_id) {
+                        violations.push(format!("chain id {} is not allowed", tx.chain_id));
+                    }
+                }
+                Rule::DenyReverts => match &tx.simulation {
+                    Some(SimulationOutcome::Success) => {}
+                    Some(SimulationOutcome::Revert { reason }) => {
+                        violations.push(format!("the transaction would revert: {}", reason))
+                    }
+                    None => violations.push(
+                        "the transaction must be simulated, sign it from an intent or with /eth/tx/<id>/sign"
+                            .to_string(),
+                    ),
+                },
+                Rule::AllowMessages => {}
+            }
+        }
+        Decision::new(violations)
+    }
+
+    /// Whether any rule restricts transactions.
+    pub fn has_tx_rules(&self) -> bool {
+        self.rules.iter().any(|rule| *rule != Rule::AllowMessages)
+    }
+
+    /// Checks `message` against the rules that apply to messages. Typed data
+    /// is checked against the chain and destination rules using its domain.
+    pub fn evaluate_message(&self, message: &MessageSummary) -> Decision {
+        if self.rules.is_empty() {
+            return Decision::new(Vec::new());
+        }
+
+        let mut violations = Vec::new();
+        if !self.rules.contains(&Rule::AllowMessages) {
+            violations.push("signing messages is not allowed".to_string());
+        }
+        if let MessageSummary::TypedData {
+            chain_id,
+            verifying_contract,
+            ..
+        } = message
+        {
+            for rule in &self.rules {
+                match rule {
+                    Rule::ChainIds { ids } => match chain_id {
+                        Some(chain_id) if ids.contains(chain_id) => {}
+                        Some(chain_id) => {
+                            violations.push(format!("chain id {} is not allowed", chain_id))
+                        }
+                        None => violations.push("typed data must have a chain id".to_string()),
+                    },
+                    Rule::AllowDestinations { addresses } => {
+                        if let Some(contract) =
+                            verifying_contract.filter(|contract| !addresses.contains(contract))
+                        {
+                            violations.push(format!("contract {:?} is not allowed", contract));
+                        }
+                    }
+                    Rule::DenyDestinations { addresses } => {
+                        if let Some(contract) =
+                            verifying_contract.filter(|contract| addresses.contains(contract))
+                        {
+                            violations.push(format!("contract {:?} is denied", contract));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Decision::new(violations)
+    }
+}
+
+impl Decision {
+    fn new(violations: Vec<String>) -> Self {
+        Decision {
+            allowed: violations.is_empty(),
+            violations,
+        }
+    }
+}
+
+fn today() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() / SECONDS_PER_DAY)
+}
+
+/// Policies and daily spend of every wallet, kept in `storage::db`.
+#[derive(Default)]
+pub struct Policies {
+    /// Serializes evaluations so concurrent signatures cannot both pass
+    /// the daily limit
+    spend: Mutex<()>,
+}
+
+impl Policies {
+    pub fn new() -> Self {
+        Policies::default()
+    }
+
+    pub fn get(&self, db: &DB, user_id: &str, id: &str) -> Result<Policy> {
+        Ok(db::get(db, user_id, id, &PolicyStruct::Policy)?.unwrap_or_default())
+    }
+
+    pub fn set(&self, db: &DB, user_id: &str, id: &str, policy: &Policy) -> Result<()> {
+        db::insert(db, user_id, id, &PolicyStruct::Policy, policy)
+    }
+
+    fn spent_today(&self, db: &DB, user_id: &str, id: &str) -> Result<U256> {
+        let spend: Option<DailySpend> = db::get(db, user_id, id, &PolicyStruct::DailySpend)?;
+        let today = today()?;
+        Ok(spend
+            .filter(|spend| spend.day == today)
+            .map(|spend| spend.spent)
+            .unwrap_or_default())
+    }
+
+    /// Evaluates `tx` without signing anything or counting its value.
+    pub fn dry_run(
+        &self,
+        db: &DB,
+        user_id: &str,
+        id: &str,
+        tx: Option<&TxSummary>,
+    ) -> Result<Decision> {
+        let policy = self.get(db, user_id, id)?;
+        Ok(policy.evaluate(tx, self.spent_today(db, user_id, id)?))
+    }
+
+    /// Evaluates `tx` and, when allowed, counts its value against today's
+    /// limit right away. The value stays counted if signing then fails.
+    /// A wallet with transaction rules only signs transactions the server
+    /// hashed, a summary sent next to a hash says nothing of what is signed.
+    pub fn authorize(
+        &self,
+        db: &DB,
+        user_id: &str,
+        id: &str,
+        tx: Option<&TxSummary>,
+    ) -> Result<Decision> {
+        let _spend = self.spend.lock().unwrap_or_else(|e| e.into_inner());
+        let policy = self.get(db, user_id, id)?;
+        if matches!(tx, Some(tx) if !tx.hashed_by_server) && policy.has_tx_rules() {
+            return Err(PolicyDenied(vec![
+                "the transaction must be hashed by the server, sign it from an intent or with /eth/tx/<id>/sign"
+                    .to_string(),
+            ])
+            .into());
+        }
+        let spent = self.spent_today(db, user_id, id)?;
+        let decision = policy.evaluate(tx, spent);
+        if !decision.allowed {
+            return Err(PolicyDenied(decision.violations).into());
+        }
+
+        if let Some(tx) = tx.filter(|_| !policy.rules.is_empty()) {
+            let spend = DailySpend {
+                day: today()?,
+                spent: spent.saturating_add(tx.value),
+            };
+            db::insert(db, user_id, id, &PolicyStruct::DailySpend, &spend)?;
+        }
+        Ok(decision)
+    }
+
+    /// Evaluates `message`, which spends nothing.
+    pub fn authorize_message(
+        &self,
+        db: &DB,
+        user_id: &str,
+        id: &str,
+        message: &MessageSummary,
+    ) -> Result<Decision> {
+        let decision = self.get(db, user_id, id)?.evaluate_message(message);
+        if !decision.allowed {
+            return Err(PolicyDenied(decision.violations).into());
+        }
+        Ok(decision)
+    }
+}
@@ -0,0 +1,72 @@
+use std::future::Future;
+
+use rocket::serde::json::Json;
+use rocket::State;
+
+use super::super::auth::guards::{AuthPayload, Unauthenticated};
+use super::super::policy::PolicyDenied;
+use super::super::storage::audit::{AuditEvent, AuditRecord, Outcome};
+use super::super::AppConfig;
+use crate::utils::requests::validate_auth_token;
+use crate::AnyhowError;
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct AuditPage {
+    pub events: Vec<AuditEvent>,
+    /// Pass as `after` to fetch the next page, `None` on the last page
+    pub next: Option<u64>,
+}
+
+/// Runs `handler` and appends its outcome to the audit log. The event is
+/// written after the operation, so its effects (stored keys, vault uploads,
+/// broadcasts) stay in place when the append fails: the event is then
+/// missing and the client gets an error. Handlers must authenticate the
+/// request first, as failed authentications are not recorded: their user id
+/// is only claimed, and anyone could otherwise write to any user's log.
+pub async fn audited<T, F>(
+    state: &State<AppConfig>,
+    record: AuditRecord,
+    handler: F,
+) -> Result<T, AnyhowError>
+where
+    F: Future<Output = Result<T, AnyhowError>>,
+{
+    let result = handler.await;
+    let (outcome, error) = match &result {
+        Err(e) if e.0.is::<Unauthenticated>() => return result,
+        Ok(_) => (Outcome::Success, None),
+        Err(e) if e.0.is::<PolicyDenied>() => (Outcome::Denied, Some(format!("{:#}", e.0))),
+        Err(e) => (Outcome::Failure, Some(format!("{:#}", e.0))),
+    };
+    if let Err(e) = state.audit.append(record, outcome, error) {
+        error!("Failed to write audit event: {:#}", e);
+        return Err(AnyhowError::from(e));
+    }
+    result
+}
+
+#[get("/audit/events?<after>&<limit>")]
+pub async fn events(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    after: Option<u64>,
+    limit: Option<usize>,
+) -> Result<Json<AuditPage>, AnyhowError> {
+    validate_auth_token(state, &auth_payload).await?;
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+
+    let mut events = state
+        .audit
+        .user_events(&auth_payload.user_id, after, limit + 1)?;
+    let next = if events.len() > limit {
+        events.truncate(limit);
+        events.last().map(|event| event.seq)
+    } else {
+        None
+    };
+
+    Ok(Json(AuditPage { events, next }))
+}
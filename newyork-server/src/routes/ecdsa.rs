@@ -6,6 +6,7 @@ use crate::utils::requests::{get, post, validate_auth_token, HttpClient};
 use crate::AnyhowError;
 
 use anyhow::{anyhow, Result};
+use curv::arithmetic::traits::Converter;
 use curv::cryptographic_primitives::proofs::sigma_dlog::*;
 use curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
 use curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::{
@@ -13,6 +14,7 @@ use curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_c
 };
 use curv::elliptic::curves::secp256_k1::Secp256k1Scalar;
 use curv::elliptic::curves::secp256_k1::GE;
+use curv::elliptic::curves::traits::ECPoint;
 use curv::BigInt;
 use kms::chain_code::two_party as chain_code;
 use kms::ecdsa::two_party::*;
@@ -20,11 +22,21 @@ use kms::rotation::two_party::party1::Rotation1;
 use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::*;
 use rocket::serde::json::Json;
 use rocket::State;
+use tracing::instrument;
 use uuid::Uuid;
 
 use super::super::auth::guards::AuthPayload;
+use super::super::eth::intent;
+use super::super::eth::simulate::StateOverrides;
+use super::super::eth::tx::UnsignedTx;
+use super::super::eth::wallets::{self, WalletAddress};
+use super::super::policy::{MessageSummary, Rule, TxSummary};
+use super::super::storage::audit::{AuditRecord, Operation};
 use super::super::storage::db;
+use super::super::telemetry::otel::{SessionNonce, Step};
 use super::super::AppConfig;
+use super::audit::audited;
+use super::eth::simulate_tx;
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 struct HDPos {
     pos: u32,
@@ -87,134 +99,163 @@ pub struct HcmcMasterKey<'a> {
 }
 
 #[post("/ecdsa/keygen/first", format = "json")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
 pub async fn first_message(
     state: &State<AppConfig>,
     auth_payload: AuthPayload,
+    session_nonce: SessionNonce,
 ) -> Result<Json<(String, party_one::KeyGenFirstMsg)>, AnyhowError> {
-    validate_auth_token(state, &auth_payload).await?;
     let id = Uuid::new_v4().to_string();
-    let (key_gen_first_msg, comm_witness, ec_key_pair) = MasterKey1::key_gen_first_message();
-    let user_id = &auth_payload.user_id;
-
-    //save pos 0
-    db::insert(
-        &state.db, // current DB connection state
-        user_id,   // user id in supabase
-        &id,       // uuid to unify DB column key
-        &EcdsaStruct::POS,
-        &HDPos { pos: 0u32 }, // Initial HD position
-    )?;
+    let record = AuditRecord::new(Operation::KeygenFirst, &auth_payload).wallet(&id);
+    audited(state, record, async {
+        validate_auth_token(state, &auth_payload).await?;
+        let _session = state
+            .traces
+            .step("keygen", &id, &session_nonce, Step::First);
+        let (key_gen_first_msg, comm_witness, ec_key_pair) =
+            info_span!("crypto.keygen_first").in_scope(MasterKey1::key_gen_first_message);
+        let user_id = &auth_payload.user_id;
+
+        //save pos 0
+        db::insert(
+            &state.db, // current DB connection state
+            user_id,   // user id in supabase
+            &id,       // uuid to unify DB column key
+            &EcdsaStruct::POS,
+            &HDPos { pos: 0u32 }, // Initial HD position
+        )?;
 
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::KeyGenFirstMsg,
-        &key_gen_first_msg,
-    )?;
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::KeyGenFirstMsg,
+            &key_gen_first_msg,
+        )?;
 
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::CommWitness,
-        &comm_witness,
-    )?;
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::CommWitness,
+            &comm_witness,
+        )?;
 
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::EcKeyPair,
-        &ec_key_pair,
-    )?;
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::EcKeyPair,
+            &ec_key_pair,
+        )?;
 
-    Ok(Json((id, key_gen_first_msg)))
+        Ok(Json((id, key_gen_first_msg)))
+    })
+    .await
 }
 
 #[post("/ecdsa/keygen/<id>/second", format = "json", data = "<dlog_proof>")]
-pub fn second_message(
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn second_message(
     state: &State<AppConfig>,
     auth_payload: AuthPayload,
+    session_nonce: SessionNonce,
     id: String,
     dlog_proof: Json<DLogProof<GE>>,
 ) -> Result<Json<party1::KeyGenParty1Message2>, AnyhowError> {
-    let party2_public: GE = dlog_proof.0.pk;
-    let user_id = &auth_payload.user_id;
-
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::Party2Public,
-        &party2_public,
-    )?;
-
-    let comm_witness: party_one::CommWitness =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::CommWitness)?
-            .ok_or_else(|| anyhow!("No CommWitness for such userId {} - id {}", user_id, id))?;
-
-    let ec_key_pair: party_one::EcKeyPair =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::EcKeyPair)?
-            .ok_or_else(|| anyhow!("No EcKeyPair for such userId {} - id {}", user_id, id))?;
-
-    let (kg_party_one_second_message, paillier_key_pair, party_one_private) =
-        MasterKey1::key_gen_second_message(comm_witness, &ec_key_pair, &dlog_proof.0);
+    let record = AuditRecord::new(Operation::KeygenSecond, &auth_payload).wallet(&id);
+    audited(state, record, async {
+        validate_auth_token(state, &auth_payload).await?;
+        let _session = state.traces.step("keygen", &id, &session_nonce, Step::Next);
+        let party2_public: GE = dlog_proof.0.pk;
+        let user_id = &auth_payload.user_id;
+
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::Party2Public,
+            &party2_public,
+        )?;
 
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::PaillierKeyPair,
-        &paillier_key_pair,
-    )?;
+        let comm_witness: party_one::CommWitness =
+            db::get(&state.db, user_id, &id, &EcdsaStruct::CommWitness)?
+                .ok_or_else(|| anyhow!("No CommWitness for such userId {} - id {}", user_id, id))?;
+
+        let ec_key_pair: party_one::EcKeyPair =
+            db::get(&state.db, user_id, &id, &EcdsaStruct::EcKeyPair)?
+                .ok_or_else(|| anyhow!("No EcKeyPair for such userId {} - id {}", user_id, id))?;
+
+        let (kg_party_one_second_message, paillier_key_pair, party_one_private) =
+            info_span!("crypto.keygen_second").in_scope(|| {
+                MasterKey1::key_gen_second_message(comm_witness, &ec_key_pair, &dlog_proof.0)
+            });
+
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::PaillierKeyPair,
+            &paillier_key_pair,
+        )?;
 
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::Party1Private,
-        &party_one_private,
-    )?;
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::Party1Private,
+            &party_one_private,
+        )?;
 
-    Ok(Json(kg_party_one_second_message))
+        Ok(Json(kg_party_one_second_message))
+    })
+    .await
 }
 
 #[post("/ecdsa/keygen/<id>/chaincode/first", format = "json")]
-pub fn chain_code_first_message(
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn chain_code_first_message(
     state: &State<AppConfig>,
     auth_payload: AuthPayload,
+    session_nonce: SessionNonce,
     id: String,
 ) -> Result<Json<Party1FirstMessage>, AnyhowError> {
-    let (cc_party_one_first_message, cc_comm_witness, cc_ec_key_pair1) =
-        chain_code::party1::ChainCode1::chain_code_first_message();
-    let user_id = &auth_payload.user_id;
-
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::CCKeyGenFirstMsg,
-        &cc_party_one_first_message,
-    )?;
+    let record = AuditRecord::new(Operation::ChainCodeFirst, &auth_payload).wallet(&id);
+    audited(state, record, async {
+        validate_auth_token(state, &auth_payload).await?;
+        let _session = state.traces.step("keygen", &id, &session_nonce, Step::Next);
+        let (cc_party_one_first_message, cc_comm_witness, cc_ec_key_pair1) =
+            info_span!("crypto.chain_code_first")
+                .in_scope(chain_code::party1::ChainCode1::chain_code_first_message);
+        let user_id = &auth_payload.user_id;
+
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::CCKeyGenFirstMsg,
+            &cc_party_one_first_message,
+        )?;
 
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::CCCommWitness,
-        &cc_comm_witness,
-    )?;
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::CCCommWitness,
+            &cc_comm_witness,
+        )?;
 
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::CCEcKeyPair,
-        &cc_ec_key_pair1,
-    )?;
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::CCEcKeyPair,
+            &cc_ec_key_pair1,
+        )?;
 
-    Ok(Json(cc_party_one_first_message))
+        Ok(Json(cc_party_one_first_message))
+    })
+    .await
 }
 
 #[post(
@@ -222,31 +263,42 @@ pub fn chain_code_first_message(
     format = "json",
     data = "<cc_party_two_first_message_d_log_proof>"
 )]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
 pub async fn chain_code_second_message(
     state: &State<AppConfig>,
     auth_payload: AuthPayload,
+    session_nonce: SessionNonce,
     id: String,
     cc_party_two_first_message_d_log_proof: Json<DLogProof<GE>>,
 ) -> Result<Json<Party1SecondMessage<GE>>, AnyhowError> {
-    let user_id = &auth_payload.user_id;
-
-    let cc_comm_witness: CommWitness<GE> =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::CCCommWitness)?
-            .ok_or_else(|| anyhow!("No CCCommWitness for such userId {} - id {}", user_id, id))?;
-
-    let party1_cc = chain_code::party1::ChainCode1::chain_code_second_message(
-        cc_comm_witness,
-        &cc_party_two_first_message_d_log_proof.0,
-    );
+    let record = AuditRecord::new(Operation::ChainCodeSecond, &auth_payload).wallet(&id);
+    audited(state, record, async {
+        validate_auth_token(state, &auth_payload).await?;
+        let _session = state.traces.step("keygen", &id, &session_nonce, Step::Last);
+        let user_id = &auth_payload.user_id;
+
+        let cc_comm_witness: CommWitness<GE> =
+            db::get(&state.db, user_id, &id, &EcdsaStruct::CCCommWitness)?.ok_or_else(|| {
+                anyhow!("No CCCommWitness for such userId {} - id {}", user_id, id)
+            })?;
+
+        let party1_cc = info_span!("crypto.chain_code_second").in_scope(|| {
+            chain_code::party1::ChainCode1::chain_code_second_message(
+                cc_comm_witness,
+                &cc_party_two_first_message_d_log_proof.0,
+            )
+        });
 
-    let party2_pub = &cc_party_two_first_message_d_log_proof.pk;
+        let party2_pub = &cc_party_two_first_message_d_log_proof.pk;
 
-    let master_key = chain_code_compute_message(state, &auth_payload, id, party2_pub)?;
+        let master_key = chain_code_compute_message(state, &auth_payload, id, party2_pub)?;
 
-    // Send mk#2 to HCMC
-    send_mk_to_vault(state, &auth_payload, &master_key).await?;
+        // Send mk#2 to HCMC
+        send_mk_to_vault(state, &auth_payload, &master_key).await?;
 
-    Ok(Json(party1_cc))
+        Ok(Json(party1_cc))
+    })
+    .await
 }
 
 pub fn chain_code_compute_message(
@@ -294,13 +346,16 @@ fn master_key(
         db::get(&state.db, user_id, &id, &EcdsaStruct::CommWitness)?
             .ok_or_else(|| anyhow!("No CommWitness for such userId {} - id {}", user_id, id))?;
 
-    let master_key = MasterKey1::set_master_key(
-        &party1_cc.chain_code,
-        party_one_private,
-        &comm_witness.public_share,
-        &party2_public,
-        paillier_key_pair,
-    );
+    let master_key = info_span!("crypto.set_master_key").in_scope(|| {
+        MasterKey1::set_master_key(
+            &party1_cc.chain_code,
+            party_one_private,
+            &comm_witness.public_share,
+            &party2_public,
+            paillier_key_pair,
+        )
+    });
+    ensure_paillier_strength(state, &master_key)?;
 
     db::insert(
         &state.db,
@@ -313,38 +368,50 @@ fn master_key(
     Ok(master_key)
 }
 
+/// Starts a signing session, bound to the transaction prepared by
+/// `/eth/tx/params` when `intent_id` is given.
 #[post(
-    "/ecdsa/sign/<id>/first",
+    "/ecdsa/sign/<id>/first?<intent_id>",
     format = "json",
     data = "<eph_key_gen_first_message_party_two>"
 )]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
 pub async fn sign_first(
     state: &State<AppConfig>,
     auth_payload: AuthPayload,
+    session_nonce: SessionNonce,
     id: String,
+    intent_id: Option<String>,
     eph_key_gen_first_message_party_two: Json<party_two::EphKeyGenFirstMsg>,
 ) -> Result<Json<party_one::EphKeyGenFirstMsg>, AnyhowError> {
-    validate_auth_token(state, &auth_payload).await?;
-    let (sign_party_one_first_message, eph_ec_key_pair_party1) = MasterKey1::sign_first_message();
-    let user_id = &auth_payload.user_id;
-
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::EphKeyGenFirstMsg,
-        &eph_key_gen_first_message_party_two.0,
-    )?;
+    let record = AuditRecord::new(Operation::SignFirst, &auth_payload).wallet(&id);
+    audited(state, record, async {
+        let _session = state.traces.step("sign", &id, &session_nonce, Step::First);
+        validate_auth_token(state, &auth_payload).await?;
+        let (sign_party_one_first_message, eph_ec_key_pair_party1) =
+            info_span!("crypto.sign_first").in_scope(MasterKey1::sign_first_message);
+        let user_id = &auth_payload.user_id;
+        intent::bind(&state.db, user_id, &id, intent_id.as_deref())?;
+
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::EphKeyGenFirstMsg,
+            &eph_key_gen_first_message_party_two.0,
+        )?;
 
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::EphEcKeyPair,
-        &eph_ec_key_pair_party1,
-    )?;
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::EphEcKeyPair,
+            &eph_ec_key_pair_party1,
+        )?;
 
-    Ok(Json(sign_party_one_first_message))
+        Ok(Json(sign_party_one_first_message))
+    })
+    .await
 }
 
 // Added here because the attribute data takes only a single struct
@@ -354,25 +421,65 @@ pub struct SignSecondMsgRequest {
     pub party_two_sign_message: party2::SignMessage,
     pub x_pos_child_key: BigInt,
     pub y_pos_child_key: BigInt,
+    /// What `message` is the hash of, checked against the wallet policy
+    pub tx: Option<TxSummary>,
+    /// The message `message` is the digest of, set by the routes that
+    /// compute it
+    #[serde(skip)]
+    pub summary: Option<MessageSummary>,
+    /// The transaction `message` is the hash of, set by the routes that
+    /// compute it, so it can be simulated
+    #[serde(skip)]
+    pub unsigned_tx: Option<UnsignedTx>,
 }
 #[post("/ecdsa/sign/<id>/second", format = "json", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
 pub async fn sign_second(
     state: &State<AppConfig>,
     auth_payload: AuthPayload,
+    session_nonce: SessionNonce,
     id: String,
     request: Json<SignSecondMsgRequest>,
 ) -> Result<Json<party_one::SignatureRecid>, AnyhowError> {
+    let record = AuditRecord::new(Operation::SignSecond, &auth_payload)
+        .wallet(&id)
+        .message_hash(request.message.to_hex())
+        .derivation_path(format!(
+            "{}/{}",
+            request.x_pos_child_key, request.y_pos_child_key
+        ));
+    audited(state, record, async {
+        validate_auth_token(state, &auth_payload).await?;
+        let _session = state.traces.step("sign", &id, &session_nonce, Step::Last);
+        let signature_with_recid = sign_message(state, &auth_payload, &id, &request).await?;
+        Ok(Json(signature_with_recid))
+    })
+    .await
+}
+
+/// Contributes the server share of the signature of `request.message`, once
+/// the wallet policy allows the transaction. When the session is bound to an
+/// intent, `request.message` must be the hash of its transaction and
+/// `request.tx` is ignored. Otherwise, unless the server computed the hash
+/// itself, `features.require_tx_intent` refuses the session.
+pub async fn sign_message(
+    state: &State<AppConfig>,
+    auth_payload: &AuthPayload,
+    id: &str,
+    request: &SignSecondMsgRequest,
+) -> Result<party_one::SignatureRecid> {
     let user_id = &auth_payload.user_id;
-    let master_key: MasterKey1 = match get_mk(state, auth_payload.clone(), &id) {
+    let master_key: MasterKey1 = match get_mk(state, auth_payload.clone(), id) {
         Ok(mk) => mk,
+        Err(e) if !state.settings.features.vault_fallback => return Err(e),
         Err(_) => {
             info!("MasterKey1 not found in memory, trying to get from vault");
-            let mk = match get_mk_from_vault(state, &auth_payload).await {
+            let mk = match get_mk_from_vault(state, auth_payload).await {
                 Ok(mk) => {
-                    db::insert(&state.db, user_id, &id, &EcdsaStruct::Party1MasterKey, &mk)?;
+                    db::insert(&state.db, user_id, id, &EcdsaStruct::Party1MasterKey, &mk)?;
                     mk
                 }
-                Err(e) => return Err(AnyhowError::from(anyhow!("{:#?}", e))),
+                Err(e) => return Err(anyhow!("{:#?}", e)),
             };
             mk
         }
@@ -384,11 +491,11 @@ pub async fn sign_second(
     let child_master_key = master_key.get_child(vec![x, y]);
 
     let eph_ec_key_pair_party1: party_one::EphEcKeyPair =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::EphEcKeyPair)?
+        db::get(&state.db, user_id, id, &EcdsaStruct::EphEcKeyPair)?
             .ok_or_else(|| anyhow!("No EphEcKeyPair for such userId {} - id {}", user_id, id))?;
 
     let eph_key_gen_first_message_party_two: party_two::EphKeyGenFirstMsg =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::EphKeyGenFirstMsg)?.ok_or_else(|| {
+        db::get(&state.db, user_id, id, &EcdsaStruct::EphKeyGenFirstMsg)?.ok_or_else(|| {
             anyhow!(
                 "No EphKeyGenFirstMsg for such userId {} - id {}",
                 user_id,
@@ -396,19 +503,83 @@ pub async fn sign_second(
             )
         })?;
 
-    let signature_with_recid = child_master_key.sign_second_message(
-        &request.party_two_sign_message,
-        &eph_key_gen_first_message_party_two,
-        &eph_ec_key_pair_party1,
-        &request.message,
-    );
+    let from = wallets::address_of(&child_master_key.public.q.pk_to_key_slice())?;
+    let intent = intent::bound(&state.db, user_id, id)?;
+    let mut tx = match &intent {
+        Some(intent) => {
+            if intent.from != from {
+                return Err(anyhow!(
+                    "Transaction intent {} is from {:?}, not from the signing key {:?}",
+                    intent.id,
+                    intent.from,
+                    from
+                ));
+            }
+            if request.message != BigInt::from_hex(&hex::encode(intent.tx.sighash())) {
+                return Err(anyhow!(
+                    "Message is not the hash of transaction intent {}",
+                    intent.id
+                ));
+            }
+            Some(intent.tx.summary())
+        }
+        None if request.summary.is_some() => None,
+        None if request.unsigned_tx.is_some() => request.tx.clone(),
+        None if state.settings.features.require_tx_intent => {
+            return Err(anyhow!(
+                "Signing requires a transaction intent, start the session with one from /eth/tx/params"
+            ));
+        }
+        None => request.tx.clone(),
+    };
+    let unsigned_tx = intent
+        .as_ref()
+        .map(|intent| &intent.tx)
+        .or(request.unsigned_tx.as_ref());
+    if let (Some(summary), Some(unsigned_tx)) = (tx.as_mut(), unsigned_tx) {
+        let policy = state.policies.get(&state.db, user_id, id)?;
+        if policy.rules.contains(&Rule::DenyReverts) {
+            let simulation =
+                simulate_tx(state, from, unsigned_tx, &StateOverrides::default()).await?;
+            summary.simulation = Some(simulation.outcome());
+        }
+    }
+    match (&tx, &request.summary) {
+        (None, Some(message)) => state
+            .policies
+            .authorize_message(&state.db, user_id, id, message)?,
+        _ => state
+            .policies
+            .authorize(&state.db, user_id, id, tx.as_ref())?,
+    };
+
+    let signature_with_recid = info_span!("crypto.sign_second").in_scope(|| {
+        child_master_key.sign_second_message(
+            &request.party_two_sign_message,
+            &eph_key_gen_first_message_party_two,
+            &eph_ec_key_pair_party1,
+            &request.message,
+        )
+    });
 
     if signature_with_recid.is_err() {
         error!("Signature validation failed");
-        return Err(AnyhowError::from(anyhow!("Signature validation failed")));
+        return Err(anyhow!("Signature validation failed"));
     };
+    if let Some(intent) = &intent {
+        intent::consume(&state.db, user_id, id, intent)?;
+    }
+    wallets::record(
+        &state.db,
+        user_id,
+        &WalletAddress {
+            address: wallets::address_of(&child_master_key.public.q.pk_to_key_slice())?,
+            wallet_id: id.to_string(),
+            derivation_path: format!("{}/{}", request.x_pos_child_key, request.y_pos_child_key),
+        },
+    )?;
 
-    Ok(Json(signature_with_recid.unwrap()))
+    Ok(signature_with_recid.unwrap())
 }
 
 pub fn get_mk(state: &State<AppConfig>, auth_payload: AuthPayload, id: &str) -> Result<MasterKey1> {
@@ -418,31 +589,41 @@ pub fn get_mk(state: &State<AppConfig>, auth_payload: AuthPayload, id: &str) ->
 }
 
 #[post("/ecdsa/rotate/<id>/first", format = "json")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
 pub async fn rotate_first(
     state: &State<AppConfig>,
     auth_payload: AuthPayload,
+    session_nonce: SessionNonce,
     id: String,
 ) -> Result<Json<coin_flip_optimal_rounds::Party1FirstMessage<GE>>, AnyhowError> {
-    validate_auth_token(state, &auth_payload).await?;
-    let (party1_coin_flip_first_message, m1, r1) = Rotation1::key_rotate_first_message();
-    let user_id = &auth_payload.user_id;
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::RotateCommitMessage1M,
-        &m1,
-    )?;
+    let record = AuditRecord::new(Operation::RotateFirst, &auth_payload).wallet(&id);
+    audited(state, record, async {
+        let _session = state
+            .traces
+            .step("rotate", &id, &session_nonce, Step::First);
+        validate_auth_token(state, &auth_payload).await?;
+        let (party1_coin_flip_first_message, m1, r1) =
+            info_span!("crypto.rotate_first").in_scope(Rotation1::key_rotate_first_message);
+        let user_id = &auth_payload.user_id;
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::RotateCommitMessage1M,
+            &m1,
+        )?;
 
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::RotateCommitMessage1R,
-        &r1,
-    )?;
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::RotateCommitMessage1R,
+            &r1,
+        )?;
 
-    Ok(Json(party1_coin_flip_first_message))
+        Ok(Json(party1_coin_flip_first_message))
+    })
+    .await
 }
 
 #[post(
@@ -450,10 +631,12 @@ pub async fn rotate_first(
     format = "json",
     data = "<party2_first_message>"
 )]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
 pub async fn rotate_second(
     state: &State<AppConfig>,
     id: String,
     auth_payload: AuthPayload,
+    session_nonce: SessionNonce,
     party2_first_message: Json<coin_flip_optimal_rounds::Party2FirstMessage<GE>>,
 ) -> Result<
     Json<(
@@ -462,84 +645,103 @@ pub async fn rotate_second(
     )>,
     AnyhowError,
 > {
-    let party_one_master_key: MasterKey1 = match get_mk(state, auth_payload.clone(), &id) {
-        Ok(mk) => mk,
-        Err(_) => {
-            info!("MasterKey1 not found in memory, trying to get from vault");
-            let mk = match get_mk_from_vault(state, &auth_payload).await {
-                Ok(mk) => {
-                    db::insert(&state.db, &auth_payload.user_id, &id, &EcdsaStruct::Party1MasterKey, &mk)?;
-                    mk
-                }
-                Err(e) => return Err(AnyhowError::from(anyhow!("{:#?}", e))),
-            };
-            mk
-        }
-    };
-    let user_id = &auth_payload.user_id;
-
-    let m1: Secp256k1Scalar =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::RotateCommitMessage1M)?.ok_or_else(
-            || {
-                anyhow!(
-                    "No RotateCommitMessage1M for such userId {} - id {}",
-                    user_id,
-                    id
-                )
-            },
-        )?;
-
-    let r1: Secp256k1Scalar =
-        db::get(&state.db, user_id, &id, &EcdsaStruct::RotateCommitMessage1R)?.ok_or_else(
-            || {
-                anyhow!(
-                    "No RotateCommitMessage1R for such userId {} - id {}",
-                    user_id,
-                    id
-                )
-            },
+    let record = AuditRecord::new(Operation::RotateSecond, &auth_payload).wallet(&id);
+    audited(state, record, async {
+        validate_auth_token(state, &auth_payload).await?;
+        let _session = state.traces.step("rotate", &id, &session_nonce, Step::Last);
+        let party_one_master_key: MasterKey1 = match get_mk(state, auth_payload.clone(), &id) {
+            Ok(mk) => mk,
+            Err(e) if !state.settings.features.vault_fallback => return Err(AnyhowError::from(e)),
+            Err(_) => {
+                info!("MasterKey1 not found in memory, trying to get from vault");
+                let mk = match get_mk_from_vault(state, &auth_payload).await {
+                    Ok(mk) => {
+                        db::insert(
+                            &state.db,
+                            &auth_payload.user_id,
+                            &id,
+                            &EcdsaStruct::Party1MasterKey,
+                            &mk,
+                        )?;
+                        mk
+                    }
+                    Err(e) => return Err(AnyhowError::from(anyhow!("{:#?}", e))),
+                };
+                mk
+            }
+        };
+        let user_id = &auth_payload.user_id;
+
+        let m1: Secp256k1Scalar =
+            db::get(&state.db, user_id, &id, &EcdsaStruct::RotateCommitMessage1M)?.ok_or_else(
+                || {
+                    anyhow!(
+                        "No RotateCommitMessage1M for such userId {} - id {}",
+                        user_id,
+                        id
+                    )
+                },
+            )?;
+
+        let r1: Secp256k1Scalar =
+            db::get(&state.db, user_id, &id, &EcdsaStruct::RotateCommitMessage1R)?.ok_or_else(
+                || {
+                    anyhow!(
+                        "No RotateCommitMessage1R for such userId {} - id {}",
+                        user_id,
+                        id
+                    )
+                },
+            )?;
+
+        let (party1_second_message, random1) = info_span!("crypto.rotate_second")
+            .in_scope(|| Rotation1::key_rotate_second_message(&party2_first_message.0, &m1, &r1));
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::RotateRandom1,
+            &random1,
         )?;
 
-    let (party1_second_message, random1) =
-        Rotation1::key_rotate_second_message(&party2_first_message.0, &m1, &r1);
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::RotateRandom1,
-        &random1,
-    )?;
-
-    let (rotation_party_one_first_message, party_one_master_key_rotated) =
-        party_one_master_key.rotation_first_message(&random1);
+        let (rotation_party_one_first_message, party_one_master_key_rotated) =
+            info_span!("crypto.rotate_master_key")
+                .in_scope(|| party_one_master_key.rotation_first_message(&random1));
 
-    db::insert(
-        &state.db,
-        user_id,
-        &id,
-        &EcdsaStruct::Party1MasterKey,
-        &party_one_master_key_rotated,
-    )?;
+        db::insert(
+            &state.db,
+            user_id,
+            &id,
+            &EcdsaStruct::Party1MasterKey,
+            &party_one_master_key_rotated,
+        )?;
 
-    // Send mk#2 to HCMC
-    send_mk_to_vault(state, &auth_payload, &party_one_master_key_rotated).await?;
+        // Send mk#2 to HCMC
+        send_mk_to_vault(state, &auth_payload, &party_one_master_key_rotated).await?;
 
-    Ok(Json((
-        party1_second_message,
-        rotation_party_one_first_message,
-    )))
+        Ok(Json((
+            party1_second_message,
+            rotation_party_one_first_message,
+        )))
+    })
+    .await
 }
 
 #[post("/ecdsa/<id>/recover", format = "json")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
 pub async fn recover(
     state: &State<AppConfig>,
     auth_payload: AuthPayload,
     id: String,
 ) -> Result<Json<u32>, AnyhowError> {
-    validate_auth_token(state, &auth_payload).await?;
-    let pos_old: u32 = db::get(&state.db, &auth_payload.user_id, &id, &EcdsaStruct::POS)?
-        .ok_or_else(|| anyhow!("No POS for such identifier {}", id))?;
-    Ok(Json(pos_old))
+    let record = AuditRecord::new(Operation::Recover, &auth_payload).wallet(&id);
+    audited(state, record, async {
+        validate_auth_token(state, &auth_payload).await?;
+        let pos_old: u32 = db::get(&state.db, &auth_payload.user_id, &id, &EcdsaStruct::POS)?
+            .ok_or_else(|| anyhow!("No POS for such identifier {}", id))?;
+        Ok(Json(pos_old))
+    })
+    .await
 }
 
 async fn send_mk_to_vault(
@@ -547,40 +749,72 @@ async fn send_mk_to_vault(
     auth_payload: &AuthPayload,
     master_key: &MasterKey1,
 ) -> Result<()> {
-    let http_client = HttpClient::new(state.hcmc_api.clone());
+    let http_client = HttpClient::new(
+        state.hcmc_api.clone(),
+        state.settings.timeouts.hcmc(),
+        &auth_payload.request_id,
+    );
 
-    let update_mk_resp = post(&http_client, "/api/v1/storage/secret")
+    state
+        .metrics
+        .observe_outbound("hcmc", "store_secret", async {
+            let update_mk_resp = post(&http_client, "/api/v1/storage/secret")
+                .await
+                .bearer_auth(&auth_payload.token)
+                .json(&HcmcMasterKey { master_key })
+                .send()
+                .await?;
+
+            if !update_mk_resp.status().is_success() {
+                return Err(anyhow!(
+                    "Store user's master key {:#?} into vault failed!",
+                    update_mk_resp.text().await?
+                ));
+            }
+
+            Ok(())
+        })
         .await
-        .bearer_auth(&auth_payload.token)
-        .json(&HcmcMasterKey { master_key })
-        .send()
-        .await?;
-
-    if !update_mk_resp.status().is_success() {
-        return Err(anyhow!(
-            "Store user's master key {:#?} into vault failed!",
-            update_mk_resp.text().await?
-        ));
-    }
-
-    Ok(())
 }
 
 async fn get_mk_from_vault(
     state: &State<AppConfig>,
     auth_payload: &AuthPayload,
 ) -> Result<MasterKey1> {
-    let http_client = HttpClient::new(state.hcmc_api.clone());
-    let mk_resp = get(&http_client, "/api/v1/storage/secret")
-        .await
-        .bearer_auth(&auth_payload.token)
-        .send()
+    let http_client = HttpClient::new(
+        state.hcmc_api.clone(),
+        state.settings.timeouts.hcmc(),
+        &auth_payload.request_id,
+    );
+    let mk_str = state
+        .metrics
+        .observe_outbound("hcmc", "fetch_secret", async {
+            let mk_resp = get(&http_client, "/api/v1/storage/secret")
+                .await
+                .bearer_auth(&auth_payload.token)
+                .send()
+                .await?;
+            Ok(mk_resp.text().await?)
+        })
         .await?;
 
-    let mk_str = mk_resp.text().await?;
     if mk_str.is_empty() {
         return Err(anyhow!("Get master key from vault failed!"));
     }
     let mk = serde_json::from_str::<MasterKey1>(&mk_str)?;
+    ensure_paillier_strength(state, &mk)?;
     Ok(mk)
 }
+
+fn ensure_paillier_strength(state: &State<AppConfig>, master_key: &MasterKey1) -> Result<()> {
+    let bits = master_key.public.paillier_pub.n.bit_length();
+    let min_bits = state.settings.paillier.min_modulus_bits;
+    if bits < min_bits {
+        return Err(anyhow!(
+            "Paillier modulus of {} bits is below the configured minimum of {}",
+            bits,
+            min_bits
+        ));
+    }
+    Ok(())
+}
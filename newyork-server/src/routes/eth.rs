@@ -1,14 +1,47 @@
-use anyhow::Result;
+use std::future::Future;
+
+use anyhow::{anyhow, Result};
+use curv::arithmetic::traits::Converter;
+use curv::BigInt;
+use futures::stream::{BoxStream, StreamExt};
+use kms::ecdsa::two_party::party2;
+use rocket::response::stream::{stream, Event, EventStream};
 use rocket::serde::json::Json;
-use rocket::State;
-use web3::types::{AccessList, Address, Bytes, TransactionParameters, H256, U256, U64};
-use web3::{transports, Web3};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::{Shutdown, State};
+use tracing::instrument;
+use web3::signing::keccak256;
+use web3::types::{
+    AccessList, Address, BlockNumber, Bytes, CallRequest, TransactionParameters, H256, U256, U64,
+};
 
 use crate::utils::requests::validate_auth_token;
+use crate::utils::settings::ChainSettings;
 use crate::AnyhowError;
 
 use super::super::auth::guards::AuthPayload;
+use super::super::eth::amount::{format_units, parse_native, parse_units, ETHER_DECIMALS};
+use super::super::eth::broadcast::{self, ProviderResult};
+use super::super::eth::eip712::TypedData;
+use super::super::eth::erc20;
+use super::super::eth::fees::{self, FeeEstimates, FeeTier, FEE_HISTORY_BLOCKS, FEE_PERCENTILES};
+use super::super::eth::gas::{self, Revert};
+use super::super::eth::intent;
+use super::super::eth::messages;
+use super::super::eth::nonces::{self, NonceCounts};
+use super::super::eth::pool::Connection;
+use super::super::eth::replace::{self, Replacement};
+use super::super::eth::simulate::{self, Simulation, StateOverrides};
+use super::super::eth::tracking::{self, TrackedTx, TxStatus};
+use super::super::eth::tx::{Fees, SignedTx, TxSignature, UnsignedTx};
+use super::super::eth::wallets;
+use super::super::policy::MessageSummary;
+use super::super::storage::audit::{AuditRecord, Operation};
+use super::super::telemetry::otel::{SessionNonce, Step};
 use super::super::AppConfig;
+use super::audit::audited;
+use super::ecdsa::{sign_message, SignSecondMsgRequest};
 
 #[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct EthTxParamsResp {
@@ -20,96 +53,956 @@ pub struct EthTxParamsResp {
     pub data: Vec<u8>,
     pub transaction_type: Option<U64>,
     pub access_list: AccessList,
+    pub max_fee_per_gas: U256,
     pub max_priority_fee_per_gas: U256,
+    /// Every tier, `None` on chains without EIP-1559
+    pub fee_estimates: Option<FeeEstimates>,
     pub chain_id: u64,
+    /// Set for token transfers, whose `to` is the token contract
+    pub token_transfer: Option<TokenTransfer>,
+    /// Pass to `sign_first` to sign exactly this transaction
+    pub intent_id: String,
+    /// Unix time in seconds after which the intent can no longer be signed
+    pub intent_expires_at: u64,
+    /// Unused nonces below reserved ones, which hold back the transactions above
+    pub nonce_gaps: Vec<U256>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct TokenTransfer {
+    pub token: Address,
+    pub recipient: Address,
+    /// In the token's base units
+    pub amount: U256,
+    pub decimals: u32,
 }
 
 #[derive(Deserialize, Debug, PartialEq, Clone)]
 pub struct EthTxParamsReqBody {
+    /// The default chain when not set
+    #[serde(default)]
+    pub chain_id: Option<u64>,
     pub from_address: Address,
+    /// The recipient, also of token transfers
     pub to_address: Address,
-    pub eth_value: f64,
+    /// Amount with a unit, e.g. `"0.5 ether"`, `"30 gwei"` or `"2 MATIC"`, in wei without one.
+    /// A decimal amount of the token for token transfers, e.g. `"12.5"`
+    #[serde(default)]
+    pub value: Option<String>,
+    /// Deprecated, ether as a JSON number
+    #[serde(default)]
+    pub eth_value: Option<f64>,
+    #[serde(default)]
+    pub fee_tier: FeeTier,
+    /// Call data, empty for plain transfers
+    #[serde(default)]
+    pub data: Bytes,
+    /// ERC-20 contract to transfer `value` of instead of ether
+    #[serde(default)]
+    pub token: Option<Address>,
+}
+
+impl EthTxParamsReqBody {
+    /// The amount in wei, from `value` or `eth_value`. `value` can also be in
+    /// whole `native_symbol`.
+    pub fn wei(&self, native_symbol: &str) -> Result<U256> {
+        match (&self.value, self.eth_value) {
+            (Some(value), None) => parse_native(value, native_symbol),
+            // Display prints the shortest decimal that reads back as the same
+            // f64, which is the number the client wrote
+            (None, Some(eth_value)) => parse_units(&eth_value.to_string(), ETHER_DECIMALS),
+            _ => Err(anyhow!("Exactly one of value and eth_value must be set")),
+        }
+    }
+
+    /// The amount of a token with `decimals` decimals, in base units.
+    pub fn token_amount(&self, decimals: u32) -> Result<U256> {
+        match (&self.value, self.eth_value) {
+            (Some(value), None) => parse_units(value, decimals),
+            _ => Err(anyhow!("Token transfers take their amount in value")),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct EthTokenBalancesReqBody {
+    #[serde(default)]
+    pub chain_id: Option<u64>,
+    pub address: Address,
+    /// ERC-20 contracts
+    pub tokens: Vec<Address>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct TokenBalance {
+    pub token: Address,
+    /// In the token's base units
+    pub balance: U256,
+    pub decimals: u32,
+    /// `balance` as a decimal amount of the token
+    pub amount: String,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct EthTokenBalancesResp {
+    pub address: Address,
+    pub balances: Vec<TokenBalance>,
+}
+
+/// Errors of `/eth/tx/params`. A transaction that would revert is reported
+/// with its reason rather than as a server error.
+#[derive(Responder)]
+pub enum EthTxParamsError {
+    #[response(status = 422)]
+    Reverted(Json<Revert>),
+    Failed(AnyhowError),
+}
+
+impl From<AnyhowError> for EthTxParamsError {
+    fn from(e: AnyhowError) -> Self {
+        match e.0.downcast_ref::<Revert>() {
+            Some(revert) => EthTxParamsError::Reverted(Json(revert.clone())),
+            None => EthTxParamsError::Failed(e),
+        }
+    }
 }
 
 #[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct EthSendTxResp {
     pub tx_hash: H256,
+    /// What each RPC provider answered, at least one accepted
+    pub providers: Vec<ProviderResult>,
 }
 
 #[derive(Deserialize, Debug, PartialEq, Clone)]
 pub struct EthSendTxReqBody {
+    #[serde(default)]
+    pub chain_id: Option<u64>,
     pub raw_tx: Bytes,
 }
 
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct EthReplaceTxReqBody {
+    /// The replacement pays at least the fees of this tier, `fast` when not set
+    #[serde(default)]
+    pub fee_tier: Option<FeeTier>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct EthReplaceTxResp {
+    /// The pending transaction replaced
+    pub replaces: H256,
+    pub replacement: Replacement,
+    pub tx: UnsignedTx,
+    /// Pass to `sign_first` to sign exactly `tx`
+    pub intent_id: String,
+    /// Unix time in seconds after which the intent can no longer be signed
+    pub intent_expires_at: u64,
+}
+
+#[derive(Deserialize)]
+pub struct EthSignTxReqBody {
+    pub tx: UnsignedTx,
+    pub party_two_sign_message: party2::SignMessage,
+    pub x_pos_child_key: BigInt,
+    pub y_pos_child_key: BigInt,
+    /// Also submit the signed transaction to the chain of `tx`
+    #[serde(default)]
+    pub broadcast: bool,
+}
+
+/// A personal message, as UTF-8 `message` or raw `data`.
+#[derive(Deserialize)]
+pub struct EthSignMessageReqBody {
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(default)]
+    pub data: Option<Bytes>,
+    pub party_two_sign_message: party2::SignMessage,
+    pub x_pos_child_key: BigInt,
+    pub y_pos_child_key: BigInt,
+}
+
+#[derive(Deserialize)]
+pub struct EthSignTypedDataReqBody {
+    pub typed_data: TypedData,
+    pub party_two_sign_message: party2::SignMessage,
+    pub x_pos_child_key: BigInt,
+    pub y_pos_child_key: BigInt,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct EthMessageSigResp {
+    /// `r || s || v`, with `v` 27 or 28
+    pub signature: Bytes,
+    pub digest: H256,
+    /// The address the signature recovers to
+    pub signer: Address,
+    pub summary: MessageSummary,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct EthSimulateReqBody {
+    /// Accounts to replace for the simulation, by address
+    #[serde(default)]
+    pub state_overrides: StateOverrides,
+}
+
+/// A chain of the registry, without its RPC URLs which may hold API keys.
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct ChainInfo {
+    pub chain_id: u64,
+    pub name: String,
+    pub native_symbol: String,
+    pub eip1559: bool,
+    pub confirmations: u64,
+    /// Used by requests without a `chain_id`
+    pub default: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct EthSignTxResp {
+    pub raw_tx: Bytes,
+    pub tx_hash: H256,
+    pub broadcast: bool,
+    /// Empty unless `broadcast`
+    pub providers: Vec<ProviderResult>,
+}
+
+const EIP2930_TX_ID: u64 = 1;
 const EIP1559_TX_ID: u64 = 2;
+/// Most tokens `/eth/tokens/balance` reads in one request
+const MAX_BALANCE_TOKENS: usize = 50;
 
 #[post("/eth/tx/params", format = "json", data = "<tx_info>")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
 pub async fn tx_parameters(
     state: &State<AppConfig>,
     auth_payload: AuthPayload,
     tx_info: Json<EthTxParamsReqBody>,
-) -> Result<Json<EthTxParamsResp>, AnyhowError> {
-    validate_auth_token(state, &auth_payload).await?;
-    let tx_params = create_eth_transaction(tx_info.to_address, tx_info.eth_value)?;
-    let web3 = establish_web3_connection(&state.alchemy_api).await?;
+) -> Result<Json<EthTxParamsResp>, EthTxParamsError> {
+    let record = AuditRecord::new(Operation::EthTxParams, &auth_payload);
+    audited(state, record, async {
+        validate_auth_token(state, &auth_payload).await?;
+        let chain = state.chains.get(tx_info.chain_id)?;
+        let web3 = with_rpc_timeout(state, "connect", state.rpc.connection(chain.chain_id)).await?;
+        let (tx_params, token_transfer) = match tx_info.token {
+            None => (
+                create_eth_transaction(
+                    chain.chain_id,
+                    tx_info.to_address,
+                    tx_info.wei(&chain.native_symbol)?,
+                    tx_info.data.clone(),
+                )?,
+                None,
+            ),
+            Some(token) => {
+                if !tx_info.data.0.is_empty() {
+                    return Err(anyhow!("Token transfers cannot set data").into());
+                }
+                let decimals =
+                    with_rpc_timeout(state, "decimals", token_decimals(web3.clone(), token))
+                        .await?;
+                let transfer = TokenTransfer {
+                    token,
+                    recipient: tx_info.to_address,
+                    amount: tx_info.token_amount(decimals)?,
+                    decimals,
+                };
+                let data = erc20::transfer(transfer.recipient, transfer.amount);
+                (
+                    create_eth_transaction(chain.chain_id, token, U256::zero(), data)?,
+                    Some(transfer),
+                )
+            }
+        };
 
-    let (nonce, gas_price, chain_id) =
-        get_chain_required_params(tx_info.from_address, tx_params.clone(), web3).await?;
+        let (chain_params, fee_estimates, gas_estimate) = futures::future::join3(
+            with_rpc_timeout(
+                state,
+                "chain_params",
+                get_chain_required_params(tx_info.from_address, tx_params.clone(), web3.clone()),
+            ),
+            async {
+                if chain.eip1559 {
+                    with_rpc_timeout(state, "fee_history", estimate_fees(web3.clone())).await
+                } else {
+                    Ok(None)
+                }
+            },
+            with_rpc_timeout(
+                state,
+                "estimate_gas",
+                estimate_gas(tx_info.from_address, &tx_params, web3.clone()),
+            ),
+        )
+        .await;
+        let (nonces, gas_price, chain_id) = chain_params?;
+        let gas = gas::with_margin(gas_estimate?, state.settings.eth.gas_margin_percent);
+        let fee_estimates = fee_estimates.unwrap_or_else(|e| {
+            info!("No fee history, falling back to legacy pricing ({:#})", e);
+            None
+        });
 
-    let max_priority_fee_per_gas = match tx_params.transaction_type {
-        Some(tx_type) if tx_type == U64::from(EIP1559_TX_ID) => {
-            tx_params.max_priority_fee_per_gas.unwrap_or(gas_price)
-        }
-        _ => gas_price,
-    };
+        let access_list = tx_params.access_list.unwrap_or_default();
+        let fees = match &fee_estimates {
+            Some(estimates) => {
+                let estimate = estimates.tier(tx_info.fee_tier);
+                Fees::Eip1559 {
+                    max_fee_per_gas: estimate.max_fee_per_gas,
+                    max_priority_fee_per_gas: estimate.max_priority_fee_per_gas,
+                    access_list: access_list.clone(),
+                }
+            }
+            None => Fees::Legacy { gas_price },
+        };
+        let (transaction_type, max_fee_per_gas, max_priority_fee_per_gas) = match &fees {
+            Fees::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+                ..
+            } => (
+                Some(U64::from(EIP1559_TX_ID)),
+                *max_fee_per_gas,
+                *max_priority_fee_per_gas,
+            ),
+            Fees::Eip2930 { gas_price, .. } => {
+                (Some(U64::from(EIP2930_TX_ID)), *gas_price, *gas_price)
+            }
+            Fees::Legacy { gas_price } => (None, *gas_price, *gas_price),
+        };
+        let (intent, nonce_gaps) = state.nonces.reserve(
+            &state.db,
+            &auth_payload.user_id,
+            chain_id,
+            tx_info.from_address,
+            nonces,
+            |nonce| {
+                intent::create(
+                    &state.db,
+                    &auth_payload.user_id,
+                    tx_info.from_address,
+                    UnsignedTx {
+                        chain_id,
+                        nonce,
+                        gas,
+                        to: tx_params.to,
+                        value: tx_params.value,
+                        data: tx_params.data.clone(),
+                        fees,
+                    },
+                    state.settings.eth.intent_ttl(),
+                )
+            },
+        )?;
 
-    let resp = EthTxParamsResp {
-        to: tx_params.to,
-        nonce,
-        gas: tx_params.gas,
-        gas_price,
-        value: tx_params.value,
-        data: tx_params.data.0,
-        transaction_type: tx_params.transaction_type,
-        access_list: tx_params.access_list.unwrap_or_default(),
-        max_priority_fee_per_gas,
-        chain_id,
-    };
+        let resp = EthTxParamsResp {
+            to: tx_params.to,
+            nonce: intent.tx.nonce,
+            gas,
+            // The max fee per gas of EIP-1559 transactions
+            gas_price: max_fee_per_gas,
+            value: tx_params.value,
+            data: tx_params.data.0,
+            transaction_type,
+            access_list,
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+            fee_estimates,
+            chain_id,
+            token_transfer,
+            intent_id: intent.id,
+            intent_expires_at: intent.expires_at,
+            nonce_gaps,
+        };
 
-    Ok(Json(resp))
+        Ok(Json(resp))
+    })
+    .await
+    .map_err(EthTxParamsError::from)
 }
 
 #[post("/eth/tx/send", format = "json", data = "<signed>")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
 pub async fn tx_send(
     state: &State<AppConfig>,
     auth_payload: AuthPayload,
     signed: Json<EthSendTxReqBody>,
 ) -> Result<Json<EthSendTxResp>, AnyhowError> {
+    let record = AuditRecord::new(Operation::EthTxSend, &auth_payload)
+        .message_hash(hex::encode(keccak256(&signed.raw_tx.0)));
+    audited(state, record, async {
+        validate_auth_token(state, &auth_payload).await?;
+        let decoded = SignedTx::decode(&signed.raw_tx.0)?;
+        let chain = state
+            .chains
+            .get(Some(signed.chain_id.unwrap_or(decoded.tx.chain_id)))?;
+        if decoded.tx.chain_id != chain.chain_id {
+            return Err(anyhow!(
+                "Transaction is signed for chain {}, not {} ({})",
+                decoded.tx.chain_id,
+                chain.chain_id,
+                chain.name
+            )
+            .into());
+        }
+        if wallets::lookup(&state.db, &auth_payload.user_id, decoded.from)?.is_none() {
+            return Err(anyhow!(
+                "Sender {:?} is not the address of one of your wallets",
+                decoded.from
+            )
+            .into());
+        }
+
+        let web3 = with_rpc_timeout(state, "connect", state.rpc.connection(chain.chain_id)).await?;
+        let counts = with_rpc_timeout(state, "transaction_count", async {
+            Ok(nonces::counts(&web3, decoded.from).await?)
+        })
+        .await?;
+        decoded.check_nonce(counts.mined, counts.pending)?;
+
+        let providers = with_rpc_timeout(
+            state,
+            "send_tx",
+            broadcast::send(&state.rpc, chain.chain_id, &signed.raw_tx),
+        )
+        .await?;
+        track(state, &auth_payload.user_id, chain, &decoded);
+
+        Ok(Json(EthSendTxResp {
+            tx_hash: decoded.hash,
+            providers,
+        }))
+    })
+    .await
+}
+
+#[get("/eth/chains")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn chains(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+) -> Result<Json<Vec<ChainInfo>>, AnyhowError> {
+    validate_auth_token(state, &auth_payload).await?;
+    let default_chain_id = state.chains.default_chain().chain_id;
+    let chains = state
+        .chains
+        .iter()
+        .map(|chain| ChainInfo {
+            chain_id: chain.chain_id,
+            name: chain.name.clone(),
+            native_symbol: chain.native_symbol.clone(),
+            eip1559: chain.eip1559,
+            confirmations: chain.confirmations,
+            default: chain.chain_id == default_chain_id,
+        })
+        .collect();
+    Ok(Json(chains))
+}
+
+/// ERC-20 balances of `address`.
+#[post("/eth/tokens/balance", format = "json", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn token_balances(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    request: Json<EthTokenBalancesReqBody>,
+) -> Result<Json<EthTokenBalancesResp>, AnyhowError> {
+    validate_auth_token(state, &auth_payload).await?;
+    if request.tokens.len() > MAX_BALANCE_TOKENS {
+        return Err(anyhow!("At most {} tokens per request", MAX_BALANCE_TOKENS).into());
+    }
+    let chain = state.chains.get(request.chain_id)?;
+    let web3 = with_rpc_timeout(state, "connect", state.rpc.connection(chain.chain_id)).await?;
+    let balances = futures::future::try_join_all(request.tokens.iter().map(|token| {
+        with_rpc_timeout(
+            state,
+            "token_balance",
+            token_balance(web3.clone(), *token, request.address),
+        )
+    }))
+    .await?;
+
+    Ok(Json(EthTokenBalancesResp {
+        address: request.address,
+        balances,
+    }))
+}
+
+/// Second signing step for an Ethereum transaction. The server hashes `tx`
+/// itself, so the signature it contributes is only valid for that exact
+/// transaction, and returns it fully signed.
+#[post("/eth/tx/<id>/sign", format = "json", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn tx_sign(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    session_nonce: SessionNonce,
+    id: String,
+    request: Json<EthSignTxReqBody>,
+) -> Result<Json<EthSignTxResp>, AnyhowError> {
+    let EthSignTxReqBody {
+        tx,
+        party_two_sign_message,
+        x_pos_child_key,
+        y_pos_child_key,
+        broadcast,
+    } = request.into_inner();
+    let sighash = tx.sighash();
+    let record = AuditRecord::new(Operation::EthTxSign, &auth_payload)
+        .wallet(&id)
+        .message_hash(hex::encode(sighash))
+        .derivation_path(format!("{}/{}", x_pos_child_key, y_pos_child_key));
+    audited(state, record, async {
+        validate_auth_token(state, &auth_payload).await?;
+        let _session = state.traces.step("sign", &id, &session_nonce, Step::Last);
+        tx.validate()?;
+        let sign_request = SignSecondMsgRequest {
+            message: BigInt::from_hex(&hex::encode(sighash)),
+            party_two_sign_message,
+            x_pos_child_key,
+            y_pos_child_key,
+            tx: Some(tx.summary()),
+            summary: None,
+            unsigned_tx: Some(tx.clone()),
+        };
+        let signature = sign_message(state, &auth_payload, &id, &sign_request).await?;
+        let raw_tx = tx.encode_signed(&TxSignature {
+            r: to_u256(&signature.r)?,
+            s: to_u256(&signature.s)?,
+            recid: signature.recid,
+        })?;
+        let tx_hash = H256::from(keccak256(&raw_tx.0));
+
+        let providers = if broadcast {
+            let chain = state.chains.get(Some(tx.chain_id))?;
+            let providers = with_rpc_timeout(
+                state,
+                "send_tx",
+                broadcast::send(&state.rpc, chain.chain_id, &raw_tx),
+            )
+            .await?;
+            track(
+                state,
+                &auth_payload.user_id,
+                chain,
+                &SignedTx::decode(&raw_tx.0)?,
+            );
+            providers
+        } else {
+            Vec::new()
+        };
+
+        Ok(Json(EthSignTxResp {
+            raw_tx,
+            tx_hash,
+            broadcast,
+            providers,
+        }))
+    })
+    .await
+}
+
+/// Second signing step for an EIP-191 personal message, hashed by the server.
+#[post("/eth/message/<id>/sign", format = "json", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn message_sign(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    session_nonce: SessionNonce,
+    id: String,
+    request: Json<EthSignMessageReqBody>,
+) -> Result<Json<EthMessageSigResp>, AnyhowError> {
+    let EthSignMessageReqBody {
+        message,
+        data,
+        party_two_sign_message,
+        x_pos_child_key,
+        y_pos_child_key,
+    } = request.into_inner();
+    let message = match (message, data) {
+        (Some(message), None) => message.into_bytes(),
+        (None, Some(data)) => data.0,
+        _ => return Err(anyhow!("Either message or data must be set").into()),
+    };
+    let digest = messages::personal_digest(&message);
+    let sign_request = SignSecondMsgRequest {
+        message: BigInt::from_hex(&hex::encode(digest)),
+        party_two_sign_message,
+        x_pos_child_key,
+        y_pos_child_key,
+        tx: None,
+        summary: Some(messages::personal_summary(&message)),
+        unsigned_tx: None,
+    };
+    sign_digest(
+        state,
+        auth_payload,
+        &session_nonce,
+        &id,
+        Operation::EthMessageSign,
+        digest,
+        sign_request,
+    )
+    .await
+}
+
+/// Second signing step for an EIP-712 typed data document, hashed by the
+/// server.
+#[post("/eth/typed-data/<id>/sign", format = "json", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn typed_data_sign(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    session_nonce: SessionNonce,
+    id: String,
+    request: Json<EthSignTypedDataReqBody>,
+) -> Result<Json<EthMessageSigResp>, AnyhowError> {
+    let EthSignTypedDataReqBody {
+        typed_data,
+        party_two_sign_message,
+        x_pos_child_key,
+        y_pos_child_key,
+    } = request.into_inner();
+    let digest = typed_data.digest()?;
+    let sign_request = SignSecondMsgRequest {
+        message: BigInt::from_hex(&hex::encode(digest)),
+        party_two_sign_message,
+        x_pos_child_key,
+        y_pos_child_key,
+        tx: None,
+        summary: Some(typed_data.summary()?),
+        unsigned_tx: None,
+    };
+    sign_digest(
+        state,
+        auth_payload,
+        &session_nonce,
+        &id,
+        Operation::EthTypedDataSign,
+        digest,
+        sign_request,
+    )
+    .await
+}
+
+/// Signs `digest`, the `message` of `request`, which is the hash of the
+/// message `request.summary` describes.
+async fn sign_digest(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    session_nonce: &SessionNonce,
+    id: &str,
+    operation: Operation,
+    digest: H256,
+    request: SignSecondMsgRequest,
+) -> Result<Json<EthMessageSigResp>, AnyhowError> {
+    let summary = request
+        .summary
+        .clone()
+        .ok_or_else(|| anyhow!("The message is not described"))?;
+    let record = AuditRecord::new(operation, &auth_payload)
+        .wallet(id)
+        .message_hash(hex::encode(digest))
+        .derivation_path(format!(
+            "{}/{}",
+            request.x_pos_child_key, request.y_pos_child_key
+        ))
+        .summary(serde_json::to_string(&summary).map_err(anyhow::Error::from)?);
+    audited(state, record, async {
+        validate_auth_token(state, &auth_payload).await?;
+        let _session = state.traces.step("sign", id, session_nonce, Step::Last);
+        let signature = sign_message(state, &auth_payload, id, &request).await?;
+        let signature = TxSignature {
+            r: to_u256(&signature.r)?,
+            s: to_u256(&signature.s)?,
+            recid: signature.recid,
+        };
+        Ok(Json(EthMessageSigResp {
+            signature: messages::signature_bytes(&signature)?,
+            digest,
+            signer: signature.recover(digest)?,
+            summary,
+        }))
+    })
+    .await
+}
+
+/// Simulates a prepared transaction on the latest block, with optional state
+/// overrides, to preview its outcome before signing it.
+#[post(
+    "/eth/intents/<intent_id>/simulate",
+    format = "json",
+    data = "<request>"
+)]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn simulate_intent(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    intent_id: String,
+    request: Json<EthSimulateReqBody>,
+) -> Result<Json<Simulation>, AnyhowError> {
     validate_auth_token(state, &auth_payload).await?;
-    let web3 = establish_web3_connection(&state.alchemy_api).await?;
-    let tx_hash = send_tx(web3, signed.raw_tx.clone()).await?;
+    let intent = intent::fetch(&state.db, &auth_payload.user_id, &intent_id)?;
+    let simulation = simulate_tx(state, intent.from, &intent.tx, &request.state_overrides).await?;
+    Ok(Json(simulation))
+}
 
-    Ok(Json(EthSendTxResp { tx_hash }))
+/// Simulates `tx` sent by `from` on its chain.
+pub async fn simulate_tx(
+    state: &State<AppConfig>,
+    from: Address,
+    tx: &UnsignedTx,
+    overrides: &StateOverrides,
+) -> Result<Simulation> {
+    let chain = state.chains.get(Some(tx.chain_id))?;
+    let web3 = with_rpc_timeout(state, "connect", state.rpc.connection(chain.chain_id)).await?;
+    with_rpc_timeout(
+        state,
+        "simulate",
+        simulate::simulate(&web3, from, tx, overrides),
+    )
+    .await
 }
 
-fn create_eth_transaction(to: Address, eth_value: f64) -> Result<TransactionParameters> {
+/// Prepares a replacement of a pending transaction with the same call and
+/// higher fees.
+#[post("/eth/tx/<hash>/speed-up", format = "json", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn tx_speed_up(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    hash: &str,
+    request: Json<EthReplaceTxReqBody>,
+) -> Result<Json<EthReplaceTxResp>, AnyhowError> {
+    replace_tx(
+        state,
+        auth_payload,
+        hash,
+        Replacement::SpeedUp,
+        request.fee_tier,
+    )
+    .await
+}
+
+/// Prepares a zero-value transfer to the sender replacing a pending
+/// transaction, with higher fees.
+#[post("/eth/tx/<hash>/cancel", format = "json", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn tx_cancel(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    hash: &str,
+    request: Json<EthReplaceTxReqBody>,
+) -> Result<Json<EthReplaceTxResp>, AnyhowError> {
+    replace_tx(
+        state,
+        auth_payload,
+        hash,
+        Replacement::Cancel,
+        request.fee_tier,
+    )
+    .await
+}
+
+async fn replace_tx(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    hash: &str,
+    kind: Replacement,
+    fee_tier: Option<FeeTier>,
+) -> Result<Json<EthReplaceTxResp>, AnyhowError> {
+    let record = AuditRecord::new(Operation::EthTxReplace, &auth_payload)
+        .message_hash(hash.trim_start_matches("0x").to_string());
+    audited(state, record, async {
+        validate_auth_token(state, &auth_payload).await?;
+        let hash = tracking::parse_hash(hash)?;
+        let tracked = tracking::get(&state.db, &auth_payload.user_id, hash)?
+            .ok_or_else(|| anyhow!("Transaction {:?} is not tracked", hash))?;
+        if tracked.status != TxStatus::Pending {
+            return Err(anyhow!(
+                "Transaction {:?} is {:?}, only pending transactions can be replaced",
+                hash,
+                tracked.status
+            )
+            .into());
+        }
+
+        let web3 =
+            with_rpc_timeout(state, "connect", state.rpc.connection(tracked.chain_id)).await?;
+        let (gas_price, fee_estimates) = futures::future::try_join(
+            with_rpc_timeout(state, "gas_price", async {
+                Ok(web3.eth().gas_price().await?)
+            }),
+            async {
+                match tracked.tx.fees {
+                    Fees::Eip1559 { .. } => {
+                        with_rpc_timeout(state, "fee_history", estimate_fees(web3.clone())).await
+                    }
+                    _ => Ok(None),
+                }
+            },
+        )
+        .await?;
+        let fees = replace::bumped_fees(
+            &tracked.tx.fees,
+            fee_estimates
+                .as_ref()
+                .map(|estimates| estimates.tier(fee_tier.unwrap_or(FeeTier::Fast))),
+            gas_price,
+        );
+        let tx = replace::replace(&tracked.tx, tracked.from, kind, fees);
+        tx.validate()?;
+        // The nonce is already in the pending pool, there is none to reserve
+        let intent = intent::create(
+            &state.db,
+            &auth_payload.user_id,
+            tracked.from,
+            tx.clone(),
+            state.settings.eth.intent_ttl(),
+        )?;
+
+        Ok(Json(EthReplaceTxResp {
+            replaces: hash,
+            replacement: kind,
+            tx,
+            intent_id: intent.id,
+            intent_expires_at: intent.expires_at,
+        }))
+    })
+    .await
+}
+
+/// Abandons a transaction intent, freeing its nonce for the next one.
+#[delete("/eth/intents/<intent_id>")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn abandon_intent(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    intent_id: &str,
+) -> Result<(), AnyhowError> {
+    validate_auth_token(state, &auth_payload).await?;
+    let intent = intent::abandon(&state.db, &auth_payload.user_id, intent_id)?;
+    state
+        .nonces
+        .release(&state.db, &auth_payload.user_id, &intent)?;
+    Ok(())
+}
+
+/// Status of a transaction sent through the server.
+#[get("/eth/tx/<hash>/status")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn tx_status(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    hash: &str,
+) -> Result<Option<Json<TrackedTx>>, AnyhowError> {
+    validate_auth_token(state, &auth_payload).await?;
+    let hash = tracking::parse_hash(hash)?;
+    Ok(tracking::get(&state.db, &auth_payload.user_id, hash)?.map(Json))
+}
+
+/// Server-sent `status` events of a transaction sent through the server, the
+/// current status first, until the transaction is confirmed, dropped or
+/// replaced.
+#[get("/eth/tx/<hash>/events")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn tx_events(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    hash: &str,
+    mut shutdown: Shutdown,
+) -> Result<Option<EventStream<BoxStream<'static, Event>>>, AnyhowError> {
+    validate_auth_token(state, &auth_payload).await?;
+    let hash = tracking::parse_hash(hash)?;
+    // Subscribed before reading the status, so no update is missed
+    let mut updates = state.txs.subscribe();
+    let mut tracked = match tracking::get(&state.db, &auth_payload.user_id, hash)? {
+        Some(tracked) => tracked,
+        None => return Ok(None),
+    };
+    let db = state.db.clone();
+    let user_id = auth_payload.user_id;
+    let events = stream! {
+        loop {
+            yield Event::json(&tracked).event("status");
+            if tracked.status.is_final() {
+                break;
+            }
+            tracked = loop {
+                select! {
+                    update = updates.recv() => match update {
+                        Ok((user, tx)) if user == user_id && tx.hash == hash => break tx,
+                        Ok(_) => continue,
+                        // Updates were missed, the stored status is the latest
+                        Err(RecvError::Lagged(_)) => match tracking::get(&db, &user_id, hash) {
+                            Ok(Some(tx)) => break tx,
+                            _ => return,
+                        },
+                        Err(RecvError::Closed) => return,
+                    },
+                    _ = &mut shutdown => return,
+                }
+            };
+        }
+    };
+    Ok(Some(EventStream::from(events.boxed())))
+}
+
+/// Follows a transaction the server broadcast. The transaction is already
+/// sent, so failing to store its status is logged instead of returned.
+fn track(state: &State<AppConfig>, user_id: &str, chain: &ChainSettings, signed: &SignedTx) {
+    if let Err(e) = state.txs.track(
+        &state.db,
+        user_id,
+        chain.chain_id,
+        chain.confirmations,
+        signed,
+    ) {
+        warn!("Transaction {:?} is not tracked ({:#})", signed.hash, e);
+    }
+}
+
+fn to_u256(n: &BigInt) -> Result<U256> {
+    U256::from_str_radix(&n.to_hex(), 16)
+        .map_err(|e| anyhow!("{} does not fit 256 bits ({:?})", n, e))
+}
+
+fn create_eth_transaction(
+    chain_id: u64,
+    to: Address,
+    value: U256,
+    data: Bytes,
+) -> Result<TransactionParameters> {
     Ok(TransactionParameters {
+        chain_id: Some(chain_id),
         to: Some(to),
-        value: eth_to_wei(eth_value),
+        value,
+        data,
         ..Default::default()
     })
 }
 
-pub async fn establish_web3_connection(url: &str) -> Result<Web3<transports::WebSocket>> {
-    let transport = transports::WebSocket::new(url).await?;
-    Ok(Web3::new(transport))
+async fn with_rpc_timeout<T, F>(state: &State<AppConfig>, call: &str, fut: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let timeout = state.settings.timeouts.rpc();
+    state
+        .metrics
+        .observe_outbound("rpc", call, async {
+            tokio::time::timeout(timeout, fut)
+                .await
+                .map_err(|_| anyhow!("Ethereum RPC did not answer within {:?}", timeout))?
+        })
+        .await
 }
 
 pub async fn get_chain_required_params(
     from_address: Address,
     tx_params: TransactionParameters,
-    web3: Web3<transports::WebSocket>,
-) -> Result<(U256, U256, u64)> {
+    web3: Connection,
+) -> Result<(NonceCounts, U256, u64)> {
     macro_rules! maybe {
         ($o: expr, $f: expr) => {
             async {
@@ -130,27 +1023,82 @@ pub async fn get_chain_required_params(
         _ => tx_params.gas_price,
     };
 
-    let (nonce, gas_price, chain_id) = futures::future::try_join3(
-        maybe!(
-            tx_params.nonce,
-            web3.eth().transaction_count(from_address, None)
-        ),
+    let (nonces, gas_price, chain_id) = futures::future::try_join3(
+        nonces::counts(&web3, from_address),
         maybe!(gas_price, web3.eth().gas_price()),
         maybe!(tx_params.chain_id.map(U256::from), web3.eth().chain_id()),
     )
     .await?;
 
-    Ok((nonce, gas_price, chain_id.as_u64()))
+    Ok((nonces, gas_price, chain_id.as_u64()))
+}
+
+/// Fee tiers from recent blocks, `None` when the chain has no base fee.
+pub async fn estimate_fees(web3: Connection) -> Result<Option<FeeEstimates>> {
+    let history = web3
+        .eth()
+        .fee_history(
+            U256::from(FEE_HISTORY_BLOCKS),
+            BlockNumber::Latest,
+            Some(FEE_PERCENTILES.to_vec()),
+        )
+        .await?;
+    Ok(fees::estimate(&history))
 }
 
-pub async fn send_tx(web3: Web3<transports::WebSocket>, raw_tx: Bytes) -> Result<H256> {
-    let tx_hash = web3.eth().send_raw_transaction(raw_tx).await?;
-    Ok(tx_hash)
+/// Gas used by `tx_params` when sent from `from`. Fails with a [`Revert`]
+/// when the transaction would revert.
+pub async fn estimate_gas(
+    from: Address,
+    tx_params: &TransactionParameters,
+    web3: Connection,
+) -> Result<U256> {
+    let request = CallRequest {
+        from: Some(from),
+        to: tx_params.to,
+        value: Some(tx_params.value),
+        data: Some(tx_params.data.clone()),
+        ..Default::default()
+    };
+    web3.eth()
+        .estimate_gas(request, None)
+        .await
+        .map_err(|e| match gas::revert_of(&e) {
+            Some(revert) => revert.into(),
+            None => e.into(),
+        })
 }
 
-pub fn eth_to_wei(eth_value: f64) -> U256 {
-    let result = eth_value * 1_000_000_000_000_000_000.0;
-    let result = result as u128;
+/// Calls a view function of `contract` at the latest block.
+async fn view(web3: Connection, contract: Address, data: Bytes) -> Result<Bytes> {
+    let request = CallRequest {
+        to: Some(contract),
+        data: Some(data),
+        ..Default::default()
+    };
+    Ok(web3.eth().call(request, None).await?)
+}
 
-    U256::from(result)
+pub async fn token_decimals(web3: Connection, token: Address) -> Result<u32> {
+    let output = view(web3, token, erc20::decimals()).await?;
+    erc20::decode_decimals(&output.0)
+}
+
+pub async fn token_balance(
+    web3: Connection,
+    token: Address,
+    owner: Address,
+) -> Result<TokenBalance> {
+    let (decimals, balance) = futures::future::try_join(
+        token_decimals(web3.clone(), token),
+        view(web3, token, erc20::balance_of(owner)),
+    )
+    .await?;
+    let balance = erc20::decode_uint(&balance.0)?;
+    Ok(TokenBalance {
+        token,
+        balance,
+        decimals,
+        amount: format_units(balance, decimals),
+    })
 }
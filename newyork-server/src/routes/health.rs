@@ -0,0 +1,97 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+
+use super::super::eth::pool::{EndpointHealth, RpcPool};
+use super::super::storage::db;
+use super::super::AppConfig;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct ComponentHealth {
+    pub up: bool,
+    pub latency_ms: u128,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct Readiness {
+    pub ready: bool,
+    pub rocksdb: ComponentHealth,
+    pub hcmc: ComponentHealth,
+    /// The default chain
+    pub ethereum: ComponentHealth,
+    /// Every pooled RPC endpoint, by chain
+    pub rpc: BTreeMap<String, Vec<EndpointHealth>>,
+}
+
+async fn check<F>(probe: F) -> ComponentHealth
+where
+    F: Future<Output = Result<()>>,
+{
+    let start = Instant::now();
+    let outcome = match tokio::time::timeout(CHECK_TIMEOUT, probe).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("Timed out after {:?}", CHECK_TIMEOUT)),
+    };
+
+    ComponentHealth {
+        up: outcome.is_ok(),
+        latency_ms: start.elapsed().as_millis(),
+        error: outcome.err().map(|e| format!("{:#}", e)),
+    }
+}
+
+/// Any HTTP response means HCMC is reachable, we only care about the network path here.
+async fn probe_hcmc(hcmc_api: &str) -> Result<()> {
+    reqwest::Client::new().get(hcmc_api).send().await?;
+    Ok(())
+}
+
+async fn probe_ethereum(rpc: &RpcPool, chain_id: u64) -> Result<()> {
+    let web3 = rpc.connection(chain_id).await?;
+    web3.eth().block_number().await?;
+    Ok(())
+}
+
+#[get("/health/live")]
+pub fn live() -> Status {
+    Status::Ok
+}
+
+#[get("/health/ready")]
+pub async fn ready(state: &State<AppConfig>) -> (Status, Json<Readiness>) {
+    let (rocksdb, hcmc, ethereum) = futures::future::join3(
+        check(async { db::estimate_num_keys(&state.db).map(|_| ()) }),
+        check(probe_hcmc(&state.hcmc_api)),
+        check(probe_ethereum(
+            &state.rpc,
+            state.chains.default_chain().chain_id,
+        )),
+    )
+    .await;
+
+    let ready = rocksdb.up && hcmc.up && ethereum.up;
+    let status = if ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+
+    (
+        status,
+        Json(Readiness {
+            ready,
+            rocksdb,
+            hcmc,
+            ethereum,
+            rpc: state.rpc.health(),
+        }),
+    )
+}
@@ -0,0 +1,11 @@
+use rocket::http::ContentType;
+use rocket::State;
+
+use super::super::AnyhowError;
+use super::super::AppConfig;
+
+#[get("/metrics")]
+pub fn metrics(state: &State<AppConfig>) -> Result<(ContentType, String), AnyhowError> {
+    let body = state.metrics.render(&state.db)?;
+    Ok((ContentType::Plain, body))
+}
@@ -1,5 +1,9 @@
+pub mod audit;
 pub mod ecdsa;
 pub mod eddsa;
 pub mod eth;
-pub mod ping;
+pub mod health;
+pub mod metrics;
+pub mod policy;
 pub mod schnorr;
+pub mod siwe;
@@ -0,0 +1,65 @@
+use rocket::serde::json::Json;
+use rocket::State;
+use tracing::instrument;
+
+use super::super::auth::guards::AuthPayload;
+use super::super::policy::{Decision, Policy, TxSummary};
+use super::super::storage::audit::{AuditRecord, Operation};
+use super::super::AppConfig;
+use super::audit::audited;
+use super::ecdsa::get_mk;
+use crate::utils::requests::validate_auth_token;
+use crate::AnyhowError;
+
+#[get("/ecdsa/<id>/policy")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn get_policy(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    id: String,
+) -> Result<Json<Policy>, AnyhowError> {
+    validate_auth_token(state, &auth_payload).await?;
+    let policy = state.policies.get(&state.db, &auth_payload.user_id, &id)?;
+    Ok(Json(policy))
+}
+
+#[put("/ecdsa/<id>/policy", format = "json", data = "<policy>")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn set_policy(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    id: String,
+    policy: Json<Policy>,
+) -> Result<Json<Policy>, AnyhowError> {
+    let record = AuditRecord::new(Operation::PolicyUpdate, &auth_payload).wallet(&id);
+    audited(state, record, async {
+        validate_auth_token(state, &auth_payload).await?;
+        // Only wallets held by this server can have a policy
+        get_mk(state, auth_payload.clone(), &id)?;
+        state
+            .policies
+            .set(&state.db, &auth_payload.user_id, &id, &policy)?;
+        Ok(policy)
+    })
+    .await
+}
+
+/// Evaluates the wallet policy for `tx` without signing it.
+#[post("/ecdsa/<id>/policy/evaluate", format = "json", data = "<tx>")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn evaluate_policy(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+    id: String,
+    tx: Json<TxSummary>,
+) -> Result<Json<Decision>, AnyhowError> {
+    let record = AuditRecord::new(Operation::PolicyDryRun, &auth_payload).wallet(&id);
+    audited(state, record, async {
+        validate_auth_token(state, &auth_payload).await?;
+        let decision = state
+            .policies
+            .dry_run(&state.db, &auth_payload.user_id, &id, Some(&tx))?;
+        Ok(Json(decision))
+    })
+    .await
+}
@@ -0,0 +1,102 @@
+use anyhow::anyhow;
+use rocket::serde::json::Json;
+use rocket::State;
+use tracing::instrument;
+use web3::types::{Address, Bytes};
+
+use super::super::auth::guards::AuthPayload;
+use super::super::auth::siwe::{self, SiweMessage, SESSION_PREFIX};
+use super::super::storage::audit::{AuditRecord, Operation};
+use super::super::telemetry::request_id::RequestId;
+use super::super::AppConfig;
+use super::audit::audited;
+use crate::utils::requests::validate_auth_token;
+use crate::AnyhowError;
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct SiweNonceResp {
+    pub nonce: String,
+    /// Unix time in seconds after which the nonce can no longer be signed
+    pub expires_at: u64,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct SiweSignInReqBody {
+    /// The EIP-4361 message, exactly as signed
+    pub message: String,
+    /// The 65 bytes `personal_sign` signature of `message`
+    pub signature: Bytes,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct SiweSessionResp {
+    /// Send as `Authorization: Bearer <token>`
+    pub token: String,
+    /// Send as the `user_id` header
+    pub user_id: String,
+    pub address: Address,
+    /// Unix time in seconds
+    pub expires_at: u64,
+}
+
+#[get("/auth/siwe/nonce")]
+pub async fn nonce(state: &State<AppConfig>) -> Result<Json<SiweNonceResp>, AnyhowError> {
+    let (nonce, expires_at) = state
+        .siwe
+        .issue_nonce(state.settings.siwe.nonce_ttl(), siwe::now()?);
+    Ok(Json(SiweNonceResp { nonce, expires_at }))
+}
+
+/// Checks a signed SIWE message and starts a session for its address. Only
+/// messages signed by their address are audited, as the log they are
+/// written to is that address's.
+#[post("/auth/siwe/verify", format = "json", data = "<request>")]
+#[instrument(skip_all, fields(request_id = %request_id.0))]
+pub async fn verify(
+    state: &State<AppConfig>,
+    request_id: RequestId,
+    request: Json<SiweSignInReqBody>,
+) -> Result<Json<SiweSessionResp>, AnyhowError> {
+    let message: SiweMessage = request.message.parse()?;
+    let now = siwe::now()?;
+    let verified = state.siwe.verify(
+        &state.settings.siwe,
+        &message,
+        &request.message,
+        &request.signature.0,
+        now,
+    )?;
+    let auth_payload = AuthPayload {
+        token: String::new(),
+        user_id: siwe::user_id(verified.address()),
+        request_id: request_id.0,
+    };
+    let record = AuditRecord::new(Operation::SiweSignIn, &auth_payload);
+    audited(state, record, async {
+        let (token, session) =
+            state
+                .siwe
+                .sign_in(&state.db, &state.settings.siwe, &verified, now)?;
+        Ok(Json(SiweSessionResp {
+            token,
+            user_id: session.user_id,
+            address: session.address,
+            expires_at: session.expires_at,
+        }))
+    })
+    .await
+}
+
+#[post("/auth/siwe/logout")]
+#[instrument(skip_all, fields(request_id = %auth_payload.request_id))]
+pub async fn logout(
+    state: &State<AppConfig>,
+    auth_payload: AuthPayload,
+) -> Result<(), AnyhowError> {
+    if !auth_payload.token.starts_with(SESSION_PREFIX) {
+        return Err(anyhow!("Not a Sign-In with Ethereum session").into());
+    }
+    validate_auth_token(state, &auth_payload).await?;
+    state.siwe.sign_out(&state.db, &auth_payload.token)?;
+    Ok(())
+}
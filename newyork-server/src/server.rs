@@ -1,11 +1,25 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
 use rocket;
-use rocket::Request;
-use rocksdb;
+use rocket::{Build, Request, Rocket};
 
-use crate::utils::settings::{get_app_env, AppEnv};
+use crate::utils::settings::Settings;
 
+use super::auth::siwe::{self as siwe_auth, SiweAuth};
+use super::eth::chains::ChainRegistry;
+use super::eth::intent;
+use super::eth::nonces::NonceManager;
+use super::eth::pool::{self, RpcPool};
+use super::eth::tracking::{self, TxTracker};
+use super::policy::Policies;
 use super::routes::*;
-use super::storage::db;
+use super::storage::audit::AuditLog;
+use super::storage::{backup, db};
+use super::telemetry::logging;
+use super::telemetry::metrics::{Metrics, MetricsFairing};
+use super::telemetry::otel::{self, SessionNonceFairing, SessionTraces};
+use super::telemetry::request_id::RequestIdFairing;
 use super::AppConfig;
 
 #[catch(500)]
@@ -25,19 +39,65 @@ fn not_found(req: &Request) -> String {
 
 #[launch]
 pub fn get_server() -> _ {
-    let env_configs = get_app_env::<AppEnv>(".env.staging");
+    Settings::load(&[])
+        .and_then(build_server)
+        .unwrap_or_else(|e| panic!("Server startup failed: {:#}", e))
+}
+
+/// Opens storage with already validated `settings` before building the server,
+/// so a misconfigured deployment fails at startup instead of serving 500s.
+pub fn build_server(settings: Settings) -> Result<Rocket<Build>> {
+    logging::init(&settings.log, otel::tracer(&settings.otel)?);
+    info!("Starting with profile '{}'", settings.profile);
+    let backup_policy = settings.backup_policy();
+    let eth_routes = settings.features.eth_routes;
+    let siwe_routes = settings.siwe.domain.is_some();
+    let metrics = Arc::new(Metrics::new()?);
+    let db = Arc::new(get_db(&settings.db.path)?);
+    let audit = AuditLog::open(db.clone())?;
+    let chains = ChainRegistry::new(&settings.eth);
+    let siwe = SiweAuth::new(&settings.siwe)?;
+    let siwe_nonce_ttl = settings.siwe.nonce_ttl();
+    if siwe_routes && settings.siwe.nonce_key.is_none() {
+        warn!("siwe.nonce_key is not set, SIWE nonces are only valid on this instance until it restarts");
+    }
+    let rpc = Arc::new(RpcPool::new(
+        &chains,
+        settings.timeouts.rpc(),
+        metrics.clone(),
+    ));
+    let rpc_probe_interval = settings.eth.probe_interval();
+    let txs = Arc::new(TxTracker::new(&db)?);
+    let tracking_interval = settings.eth.tracking_interval();
+    let drop_after = settings.eth.drop_after();
+    let intent_ttl = settings.eth.intent_ttl();
     let app_config = AppConfig {
-        db: get_db(),
-        hcmc_api: env_configs.hcmc_host,
-        alchemy_api: env_configs.alchemy_api,
+        db,
+        hcmc_api: settings.hcmc_host.clone(),
+        chains,
+        rpc,
+        txs,
+        nonces: NonceManager::default(),
+        settings,
+        metrics: metrics.clone(),
+        traces: SessionTraces::new(),
+        audit,
+        policies: Policies::new(),
+        siwe,
     };
 
-    rocket::build()
+    let mut rocket = rocket::build()
         .register("/", catchers![internal_error, not_found, bad_request])
+        .attach(RequestIdFairing)
+        .attach(MetricsFairing(metrics))
+        .attach(SessionNonceFairing)
         .mount(
             "/",
             routes![
-                ping::ping,
+                health::live,
+                health::ready,
+                metrics::metrics,
+                audit::events,
                 ecdsa::first_message,
                 ecdsa::second_message,
                 ecdsa::chain_code_first_message,
@@ -47,24 +107,53 @@ pub fn get_server() -> _ {
                 ecdsa::rotate_first,
                 ecdsa::rotate_second,
                 ecdsa::recover,
+                policy::get_policy,
+                policy::set_policy,
+                policy::evaluate_policy,
+            ],
+        );
+    if siwe_routes {
+        rocket = rocket
+            .mount("/", routes![siwe::nonce, siwe::verify, siwe::logout])
+            .attach(siwe_auth::fairing(siwe_nonce_ttl));
+    }
+    if eth_routes {
+        rocket = rocket.mount(
+            "/",
+            routes![
+                eth::chains,
                 eth::tx_parameters,
+                eth::tx_sign,
                 eth::tx_send,
+                eth::message_sign,
+                eth::typed_data_sign,
+                eth::abandon_intent,
+                eth::simulate_intent,
+                eth::tx_speed_up,
+                eth::tx_cancel,
+                eth::tx_status,
+                eth::tx_events,
+                eth::token_balances
             ],
-        )
-        .manage(app_config)
+        );
+        rocket = rocket
+            .attach(pool::fairing(rpc_probe_interval))
+            .attach(tracking::fairing(tracking_interval, drop_after))
+            .attach(intent::fairing(intent_ttl));
+    }
+    if let Some(policy) = backup_policy {
+        rocket = rocket.attach(backup::fairing(policy));
+    }
+
+    Ok(rocket.manage(app_config))
 }
 
-fn get_db() -> db::DB {
-    match rocksdb::DB::open_default("./db") {
-        Ok(db) => {
-            info!("Init RocksDB connection successfully");
-            db::DB::Local(db)
-        }
-        Err(e) => {
-            error!("{:#?}", e);
-            db::DB::ConnError(
-                "Failed to connect RocksDB, please check your configuration".to_string(),
-            )
-        }
-    }
+fn get_db(path: &str) -> Result<db::DB> {
+    let rocksdb_client =
+        db::open(path).map_err(|e| anyhow!("Failed to open RocksDB at {} ({})", path, e))?;
+    let db = db::DB::Local(rocksdb_client);
+    db::estimate_num_keys(&db)
+        .map_err(|e| anyhow!("RocksDB at {} is not readable ({})", path, e))?;
+    info!("Init RocksDB connection successfully");
+    Ok(db)
 }
@@ -0,0 +1,364 @@
+//! Append-only audit log of key lifecycle and signing events.
+//!
+//! Events live in their own column family under `e/<seq>`, with a per-user
+//! index under `u/<hex user id>/<seq>`. Every event carries the hash of the
+//! previous one and its own hash covers its content plus that link, so
+//! editing, deleting or reordering stored events breaks the chain. The hash
+//! of the newest event is kept under `head` and printed by `audit_verify`, so
+//! it can be anchored somewhere the database owner cannot rewrite.
+
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rocksdb::{ColumnFamily, Direction, IteratorMode, WriteBatch};
+
+use super::codec;
+use super::db::DB;
+use crate::auth::guards::AuthPayload;
+
+pub const AUDIT_CF: &str = "audit";
+const EVENT_PREFIX: &str = "e/";
+const USER_PREFIX: &str = "u/";
+const HEAD_KEY: &[u8] = b"head";
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    KeygenFirst,
+    KeygenSecond,
+    ChainCodeFirst,
+    ChainCodeSecond,
+    SignFirst,
+    SignSecond,
+    RotateFirst,
+    RotateSecond,
+    Recover,
+    EthTxParams,
+    EthTxSign,
+    EthTxSend,
+    EthTxReplace,
+    EthMessageSign,
+    EthTypedDataSign,
+    PolicyUpdate,
+    PolicyDryRun,
+    SiweSignIn,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failure,
+    /// Refused by the signing policy
+    Denied,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct AuditEvent {
+    pub seq: u64,
+    /// Unix time in milliseconds
+    pub timestamp: u64,
+    pub user_id: String,
+    pub wallet_id: Option<String>,
+    pub operation: Operation,
+    pub message_hash: Option<String>,
+    pub derivation_path: Option<String>,
+    /// What was signed when it is not a transaction, left out of events
+    /// without one so their hash is unchanged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    pub outcome: Outcome,
+    pub error: Option<String>,
+    pub request_id: String,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+impl AuditEvent {
+    /// Hash of every field but `hash` itself.
+    fn compute_hash(&self) -> Result<String> {
+        let mut unhashed = self.clone();
+        unhashed.hash = String::new();
+        let mut hasher = Sha256::new();
+        hasher.input(&serde_json::to_vec(&unhashed)?);
+        Ok(hasher.result_str())
+    }
+}
+
+/// What a handler knows about an operation before it runs.
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    user_id: String,
+    request_id: String,
+    operation: Operation,
+    wallet_id: Option<String>,
+    message_hash: Option<String>,
+    derivation_path: Option<String>,
+    summary: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(operation: Operation, auth_payload: &AuthPayload) -> Self {
+        AuditRecord {
+            user_id: auth_payload.user_id.clone(),
+            request_id: auth_payload.request_id.clone(),
+            operation,
+            wallet_id: None,
+            message_hash: None,
+            derivation_path: None,
+            summary: None,
+        }
+    }
+
+    pub fn wallet(mut self, id: &str) -> Self {
+        self.wallet_id = Some(id.to_string());
+        self
+    }
+
+    pub fn message_hash(mut self, hash: String) -> Self {
+        self.message_hash = Some(hash);
+        self
+    }
+
+    pub fn derivation_path(mut self, path: String) -> Self {
+        self.derivation_path = Some(path);
+        self
+    }
+
+    pub fn summary(mut self, summary: String) -> Self {
+        self.summary = Some(summary);
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+struct Head {
+    next_seq: u64,
+    last_hash: String,
+}
+
+impl Default for Head {
+    fn default() -> Self {
+        Head {
+            next_seq: 0,
+            last_hash: GENESIS_HASH.to_string(),
+        }
+    }
+}
+
+fn event_key(seq: u64) -> String {
+    format!("{}{:020}", EVENT_PREFIX, seq)
+}
+
+fn user_prefix(user_id: &str) -> String {
+    format!("{}{}/", USER_PREFIX, hex::encode(user_id))
+}
+
+fn audit_cf(db: &rocksdb::DB) -> Result<&ColumnFamily> {
+    db.cf_handle(AUDIT_CF)
+        .ok_or_else(|| anyhow!("Column family {} is missing", AUDIT_CF))
+}
+
+fn read_head(db: &rocksdb::DB) -> Result<Option<Head>> {
+    match db.get_cf(audit_cf(db)?, HEAD_KEY)? {
+        Some(bytes) => Ok(Some(codec::decode(&bytes)?)),
+        None => Ok(None),
+    }
+}
+
+pub struct AuditLog {
+    db: Arc<DB>,
+    /// Appends are serialized so every event links to its predecessor
+    head: Mutex<Head>,
+}
+
+impl AuditLog {
+    pub fn open(db: Arc<DB>) -> Result<Self> {
+        let DB::Local(rocksdb_client) = db.as_ref();
+        let head = read_head(rocksdb_client)?.unwrap_or_default();
+        Ok(AuditLog {
+            db,
+            head: Mutex::new(head),
+        })
+    }
+
+    /// Records the outcome of `record`, with the error of a failed or denied
+    /// operation.
+    pub fn append(
+        &self,
+        record: AuditRecord,
+        outcome: Outcome,
+        error: Option<String>,
+    ) -> Result<AuditEvent> {
+        let mut head = self.head.lock().unwrap_or_else(|e| e.into_inner());
+        let mut event = AuditEvent {
+            seq: head.next_seq,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+            user_id: record.user_id,
+            wallet_id: record.wallet_id,
+            operation: record.operation,
+            message_hash: record.message_hash,
+            derivation_path: record.derivation_path,
+            summary: record.summary,
+            outcome,
+            error,
+            request_id: record.request_id,
+            prev_hash: head.last_hash.clone(),
+            hash: String::new(),
+        };
+        event.hash = event.compute_hash()?;
+        let next = Head {
+            next_seq: event.seq + 1,
+            last_hash: event.hash.clone(),
+        };
+
+        let DB::Local(rocksdb_client) = self.db.as_ref();
+        let cf = audit_cf(rocksdb_client)?;
+        let mut batch = WriteBatch::default();
+        batch.put_cf(
+            cf,
+            event_key(event.seq),
+            codec::encode(&event, codec::DEFAULT_ENCODING)?,
+        );
+        batch.put_cf(
+            cf,
+            format!("{}{:020}", user_prefix(&event.user_id), event.seq),
+            b"",
+        );
+        batch.put_cf(cf, HEAD_KEY, codec::encode(&next, codec::DEFAULT_ENCODING)?);
+        rocksdb_client.write(batch)?;
+        *head = next;
+        Ok(event)
+    }
+
+    /// Up to `limit` events of `user_id`, oldest first, starting after `after`.
+    pub fn user_events(
+        &self,
+        user_id: &str,
+        after: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>> {
+        let DB::Local(rocksdb_client) = self.db.as_ref();
+        let cf = audit_cf(rocksdb_client)?;
+        let prefix = user_prefix(user_id);
+        let start = format!("{}{:020}", prefix, after.map(|seq| seq + 1).unwrap_or(0));
+
+        let mut events = Vec::new();
+        for (key, _) in
+            rocksdb_client.iterator_cf(cf, IteratorMode::From(start.as_bytes(), Direction::Forward))
+        {
+            if events.len() >= limit || !key.starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let seq: u64 = String::from_utf8_lossy(&key[prefix.len()..]).parse()?;
+            let bytes = rocksdb_client
+                .get_cf(cf, event_key(seq))?
+                .ok_or_else(|| anyhow!("Audit event {} is indexed but missing", seq))?;
+            events.push(codec::decode(&bytes)?);
+        }
+        Ok(events)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct VerifyReport {
+    pub events: u64,
+    pub head_hash: String,
+    pub problems: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Walks the whole chain and the user index, reporting every inconsistency.
+pub fn verify(db: &rocksdb::DB) -> Result<VerifyReport> {
+    let cf = audit_cf(db)?;
+    let mut problems = Vec::new();
+    let mut expected_seq = 0u64;
+    let mut prev_hash = GENESIS_HASH.to_string();
+
+    for (key, value) in db.iterator_cf(
+        cf,
+        IteratorMode::From(EVENT_PREFIX.as_bytes(), Direction::Forward),
+    ) {
+        if !key.starts_with(EVENT_PREFIX.as_bytes()) {
+            break;
+        }
+        let key = String::from_utf8_lossy(&key).to_string();
+        let event: AuditEvent = match codec::decode(&value) {
+            Ok(event) => event,
+            Err(e) => {
+                problems.push(format!("{}: unreadable ({})", key, e));
+                continue;
+            }
+        };
+
+        if key != event_key(event.seq) {
+            problems.push(format!("{}: holds event {}", key, event.seq));
+        }
+        if event.seq != expected_seq {
+            problems.push(format!(
+                "{}: expected event {}, events are missing or reordered",
+                key, expected_seq
+            ));
+        }
+        if event.prev_hash != prev_hash {
+            problems.push(format!("{}: does not link to the previous event", key));
+        }
+        if event.compute_hash()? != event.hash {
+            problems.push(format!("{}: content does not match its hash", key));
+        }
+        expected_seq = event.seq + 1;
+        prev_hash = event.hash;
+    }
+
+    match read_head(db) {
+        Ok(Some(head)) => {
+            if head.next_seq != expected_seq || head.last_hash != prev_hash {
+                problems.push(format!(
+                    "head points at event {} ({}) but the chain ends at {} ({})",
+                    head.next_seq as i64 - 1,
+                    head.last_hash,
+                    expected_seq as i64 - 1,
+                    prev_hash
+                ));
+            }
+        }
+        Ok(None) if expected_seq > 0 => problems.push("head is missing".to_string()),
+        Ok(None) => {}
+        Err(e) => problems.push(format!("head is unreadable ({})", e)),
+    }
+
+    for (key, _) in db.iterator_cf(
+        cf,
+        IteratorMode::From(USER_PREFIX.as_bytes(), Direction::Forward),
+    ) {
+        if !key.starts_with(USER_PREFIX.as_bytes()) {
+            break;
+        }
+        let key = String::from_utf8_lossy(&key).to_string();
+        let event = key
+            .rsplit_once('/')
+            .and_then(|(_, seq)| seq.parse::<u64>().ok())
+            .and_then(|seq| db.get_cf(cf, event_key(seq)).ok().flatten())
+            .and_then(|bytes| codec::decode::<AuditEvent>(&bytes).ok());
+        match event {
+            Some(event) if key.starts_with(&user_prefix(&event.user_id)) => {}
+            Some(_) => problems.push(format!("{}: indexes another user's event", key)),
+            None => problems.push(format!("{}: index entry without event", key)),
+        }
+    }
+
+    Ok(VerifyReport {
+        events: expected_seq,
+        head_hash: prev_hash,
+        problems,
+    })
+}
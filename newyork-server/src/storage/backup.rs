@@ -0,0 +1,220 @@
+//! RocksDB backups through the backup engine.
+//!
+//! Each backup gets a small JSON manifest next to it recording how many
+//! records it holds. The count is taken from the backup itself (restored to a
+//! scratch directory) so a later `verify` can tell a truncated or corrupted
+//! backup from a healthy one.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use rocket::fairing::AdHoc;
+use rocksdb::backup::{BackupEngine, BackupEngineOptions, RestoreOptions};
+use rocksdb::{IteratorMode, Options};
+use uuid::Uuid;
+
+use super::db::DB;
+use crate::AppConfig;
+
+const MANIFEST_DIR: &str = "manifests";
+
+#[derive(Debug, Clone)]
+pub struct BackupPolicy {
+    pub dir: PathBuf,
+    pub interval: Duration,
+    pub keep: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct BackupManifest {
+    pub backup_id: u32,
+    pub timestamp: i64,
+    pub size: u64,
+    pub records: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq, Clone)]
+pub struct VerifyReport {
+    pub backup_id: u32,
+    pub expected_records: Option<u64>,
+    pub actual_records: u64,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.expected_records
+            .map_or(true, |expected| expected == self.actual_records)
+    }
+}
+
+fn open_engine(dir: &Path) -> Result<BackupEngine> {
+    fs::create_dir_all(dir)?;
+    Ok(BackupEngine::open(&BackupEngineOptions::default(), dir)?)
+}
+
+fn manifest_path(dir: &Path, backup_id: u32) -> PathBuf {
+    dir.join(MANIFEST_DIR).join(format!("{}.json", backup_id))
+}
+
+fn read_manifest(dir: &Path, backup_id: u32) -> Option<BackupManifest> {
+    let bytes = fs::read(manifest_path(dir, backup_id)).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// Counts records of a database directory, across all column families,
+/// without taking the write lock.
+pub fn count_records(db_path: &Path) -> Result<u64> {
+    let opts = Options::default();
+    let cfs = rocksdb::DB::list_cf(&opts, db_path)?;
+    let db = rocksdb::DB::open_cf_for_read_only(&opts, db_path, &cfs, false)?;
+    let mut records = 0;
+    for name in &cfs {
+        let cf = db
+            .cf_handle(name)
+            .ok_or_else(|| anyhow!("Column family {} disappeared", name))?;
+        records += db.iterator_cf(cf, IteratorMode::Start).count() as u64;
+    }
+    Ok(records)
+}
+
+/// Restores `backup_id` into a scratch directory, counts its records and
+/// removes the scratch copy again.
+fn count_backup_records(engine: &mut BackupEngine, backup_id: u32) -> Result<u64> {
+    let scratch = std::env::temp_dir().join(format!("nyc-backup-{}-{}", backup_id, Uuid::new_v4()));
+    engine.restore_from_backup(&scratch, &scratch, &RestoreOptions::default(), backup_id)?;
+    let records = count_records(&scratch);
+    let _ = fs::remove_dir_all(&scratch);
+    records
+}
+
+pub fn create(db: &rocksdb::DB, dir: &Path) -> Result<BackupManifest> {
+    let mut engine = open_engine(dir)?;
+    engine.create_new_backup_flush(db, true)?;
+
+    let info = engine
+        .get_backup_info()
+        .into_iter()
+        .max_by_key(|info| info.backup_id)
+        .ok_or_else(|| anyhow!("Backup engine reported no backups after creating one"))?;
+    let records = count_backup_records(&mut engine, info.backup_id)?;
+
+    let manifest = BackupManifest {
+        backup_id: info.backup_id,
+        timestamp: info.timestamp,
+        size: info.size,
+        records,
+    };
+    fs::create_dir_all(dir.join(MANIFEST_DIR))?;
+    fs::write(
+        manifest_path(dir, info.backup_id),
+        serde_json::to_vec_pretty(&manifest)?,
+    )?;
+
+    Ok(manifest)
+}
+
+/// Keeps the `keep` most recent backups and drops the manifests of the rest.
+pub fn purge(dir: &Path, keep: usize) -> Result<()> {
+    let mut engine = open_engine(dir)?;
+    engine.purge_old_backups(keep)?;
+
+    let remaining: Vec<u32> = engine
+        .get_backup_info()
+        .iter()
+        .map(|info| info.backup_id)
+        .collect();
+    if let Ok(entries) = fs::read_dir(dir.join(MANIFEST_DIR)) {
+        for entry in entries.flatten() {
+            let id = entry
+                .path()
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u32>().ok());
+            if matches!(id, Some(id) if !remaining.contains(&id)) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn list(dir: &Path) -> Result<Vec<BackupManifest>> {
+    let engine = open_engine(dir)?;
+    Ok(engine
+        .get_backup_info()
+        .into_iter()
+        .map(|info| {
+            read_manifest(dir, info.backup_id).unwrap_or(BackupManifest {
+                backup_id: info.backup_id,
+                timestamp: info.timestamp,
+                size: info.size,
+                records: 0,
+            })
+        })
+        .collect())
+}
+
+/// Restores `backup_id` (or the latest backup) into `db_path`.
+/// The server must not be running against `db_path`.
+pub fn restore(dir: &Path, backup_id: Option<u32>, db_path: &Path) -> Result<()> {
+    let mut engine = open_engine(dir)?;
+    let opts = RestoreOptions::default();
+    match backup_id {
+        Some(id) => engine.restore_from_backup(db_path, db_path, &opts, id)?,
+        None => engine.restore_from_latest_backup(db_path, db_path, &opts)?,
+    }
+    Ok(())
+}
+
+/// Checks the backup files, then opens a restored copy read-only and compares
+/// its record count with the one recorded at creation.
+pub fn verify(dir: &Path, backup_id: u32) -> Result<VerifyReport> {
+    let mut engine = open_engine(dir)?;
+    engine.verify_backup(backup_id)?;
+
+    Ok(VerifyReport {
+        backup_id,
+        expected_records: read_manifest(dir, backup_id).map(|m| m.records),
+        actual_records: count_backup_records(&mut engine, backup_id)?,
+    })
+}
+
+fn run_scheduled(db: &DB, policy: &BackupPolicy) -> Result<BackupManifest> {
+    let DB::Local(rocksdb_client) = db;
+    let manifest = create(rocksdb_client, &policy.dir)?;
+    purge(&policy.dir, policy.keep)?;
+    Ok(manifest)
+}
+
+/// Takes a backup every `policy.interval` for as long as the server runs.
+pub fn fairing(policy: BackupPolicy) -> AdHoc {
+    AdHoc::on_liftoff("RocksDB backups", |rocket| {
+        Box::pin(async move {
+            let db: Arc<DB> = match rocket.state::<AppConfig>() {
+                Some(config) => config.db.clone(),
+                None => return,
+            };
+
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(policy.interval);
+                // The first tick completes immediately, skip it so startup is not slowed down.
+                ticker.tick().await;
+                loop {
+                    ticker.tick().await;
+                    let (db, policy) = (db.clone(), policy.clone());
+                    match tokio::task::spawn_blocking(move || run_scheduled(&db, &policy)).await {
+                        Ok(Ok(manifest)) => info!(
+                            "Backup {} created with {} records",
+                            manifest.backup_id, manifest.records
+                        ),
+                        Ok(Err(e)) => error!("Scheduled backup failed: {:#}", e),
+                        Err(e) => error!("Scheduled backup task panicked: {}", e),
+                    }
+                }
+            });
+        })
+    })
+}
@@ -1,10 +1,22 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use rocksdb;
 use serde;
+use tracing::instrument;
+
+use super::audit::AUDIT_CF;
+use super::codec;
 
 pub enum DB {
     Local(rocksdb::DB),
-    ConnError(String),
+}
+
+/// Opens the database at `path` with every column family this build uses,
+/// creating whatever is missing.
+pub fn open(path: impl AsRef<std::path::Path>) -> Result<rocksdb::DB> {
+    let mut opts = rocksdb::Options::default();
+    opts.create_if_missing(true);
+    opts.create_missing_column_families(true);
+    Ok(rocksdb::DB::open_cf(&opts, path, [AUDIT_CF])?)
 }
 
 pub trait MPCStruct {
@@ -19,6 +31,7 @@ fn idify(user_id: &str, id: &str, name: &dyn MPCStruct) -> String {
     format!("{}_{}_{}", user_id, id, name.to_string())
 }
 
+#[instrument(name = "db.insert", skip_all, fields(record = %name.to_string()))]
 pub fn insert<T>(db: &DB, user_id: &str, id: &str, name: &dyn MPCStruct, v: T) -> Result<()>
 where
     T: serde::ser::Serialize,
@@ -26,21 +39,15 @@ where
     match db {
         DB::Local(rocksdb_client) => {
             let identifier = idify(user_id, id, name);
-            let v_string = serde_json::to_string(&v)?;
-            rocksdb_client.put(identifier.as_bytes(), v_string.as_bytes())?;
-            info!(
-                "Insert {} of ({}) into db SUCCESS",
-                name.to_string(),
-                identifier
-            );
+            let v_bytes = codec::encode(&v, codec::DEFAULT_ENCODING)?;
+            rocksdb_client.put(identifier.as_bytes(), v_bytes)?;
+            debug!("Insert {} of id {} into db SUCCESS", name.to_string(), id);
             Ok(())
         }
-        DB::ConnError(msg) => {
-            return Err(anyhow!("{}", msg));
-        }
     }
 }
 
+#[instrument(name = "db.get", skip_all, fields(record = %name.to_string()))]
 pub fn get<T>(db: &DB, user_id: &str, id: &str, name: &dyn MPCStruct) -> Result<Option<T>>
 where
     T: serde::de::DeserializeOwned,
@@ -53,25 +60,38 @@ where
             let vec_option: Option<Vec<u8>> = db_option.map(|v| v.to_vec());
             match vec_option {
                 Some(vec) => {
-                    info!(
-                        "Get {} of ({}) from db SUCCESS",
-                        name.to_string(),
-                        identifier
-                    );
-                    Ok(serde_json::from_slice(&vec)?)
+                    debug!("Get {} of id {} from db SUCCESS", name.to_string(), id);
+                    Ok(codec::decode(&vec)?)
                 }
                 None => {
-                    error!(
-                        "Get {} of ({}) from db FAILED",
-                        name.to_string(),
-                        identifier
-                    );
+                    debug!("Get {} of id {} from db NOT FOUND", name.to_string(), id);
                     Ok(None)
                 }
             }
         }
-        DB::ConnError(msg) => {
-            return Err(anyhow!("{}", msg));
+    }
+}
+
+#[instrument(name = "db.remove", skip_all, fields(record = %name.to_string()))]
+pub fn remove(db: &DB, user_id: &str, id: &str, name: &dyn MPCStruct) -> Result<()> {
+    match db {
+        DB::Local(rocksdb_client) => {
+            let identifier = idify(user_id, id, name);
+            rocksdb_client.delete(identifier.as_bytes())?;
+            debug!("Remove {} of id {} from db SUCCESS", name.to_string(), id);
+            Ok(())
         }
     }
 }
+
+/// Cheap probe used by the readiness check, returns the estimated number of keys.
+pub fn estimate_num_keys(db: &DB) -> Result<u64> {
+    int_property(db, "rocksdb.estimate-num-keys")
+}
+
+/// Reads an integer RocksDB property such as `rocksdb.total-sst-files-size`.
+pub fn int_property(db: &DB, name: &str) -> Result<u64> {
+    match db {
+        DB::Local(rocksdb_client) => Ok(rocksdb_client.property_int_value(name)?.unwrap_or(0)),
+    }
+}
@@ -1 +1,4 @@
+pub mod audit;
+pub mod backup;
+pub mod codec;
 pub mod db;
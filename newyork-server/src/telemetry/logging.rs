@@ -0,0 +1,43 @@
+use opentelemetry::sdk::trace::Tracer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter};
+
+use super::redact::Redacting;
+use crate::utils::settings::{LogFormat, LogSettings};
+
+/// Installs the global subscriber, which also receives `log` records from
+/// dependencies. Does nothing when one is already installed, as happens when
+/// tests build several servers in one process. Spans are exported through
+/// `tracer` when one is given.
+pub fn init(settings: &LogSettings, tracer: Option<Tracer>) {
+    let filter = EnvFilter::try_new(&settings.filter).unwrap_or_else(|_| EnvFilter::new("info"));
+    // ANSI styling would split `key=value` pairs and hide them from redaction
+    let (json, text) = match settings.format {
+        LogFormat::Json => (
+            Some(
+                fmt::layer()
+                    .json()
+                    .with_current_span(true)
+                    .with_span_list(false)
+                    .with_writer(Redacting(std::io::stdout)),
+            ),
+            None,
+        ),
+        LogFormat::Text => (
+            None,
+            Some(
+                fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(Redacting(std::io::stdout)),
+            ),
+        ),
+    };
+
+    let _ = tracing_subscriber::registry()
+        .with(filter)
+        .with(json)
+        .with(text)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .try_init();
+}
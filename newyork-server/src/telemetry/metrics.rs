@@ -0,0 +1,281 @@
+//! Prometheus metrics.
+//!
+//! HTTP latency, protocol outcomes and in-flight sessions are all derived
+//! from the matched route by [`MetricsFairing`], so a newly mounted route is
+//! instrumented without touching its handler. Outbound HCMC and RPC calls are
+//! timed explicitly with [`Metrics::observe_outbound`], and the health of
+//! pooled RPC endpoints is reported by the pool.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use prometheus::{
+    exponential_buckets, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+use tracing::Instrument;
+
+use crate::storage::db;
+
+/// Sessions that never reach their last step stop counting as in flight after this long.
+pub(crate) const SESSION_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Final step of each protocol, its status decides the outcome label.
+const PROTOCOL_ROUTES: &[(&str, &str)] = &[
+    ("/ecdsa/keygen/<id>/chaincode/second", "keygen"),
+    ("/ecdsa/sign/<id>/second", "sign"),
+    ("/eth/tx/<id>/sign", "sign"),
+    ("/ecdsa/rotate/<id>/second", "rotate"),
+    ("/ecdsa/<id>/recover", "recover"),
+];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Phase {
+    Start,
+    End,
+}
+
+/// Steps opening and closing a multi-request session keyed by its `<id>`.
+const SESSION_ROUTES: &[(&str, &str, Phase)] = &[
+    ("/ecdsa/keygen/<id>/second", "keygen", Phase::Start),
+    ("/ecdsa/keygen/<id>/chaincode/second", "keygen", Phase::End),
+    ("/ecdsa/sign/<id>/first", "sign", Phase::Start),
+    ("/ecdsa/sign/<id>/second", "sign", Phase::End),
+    ("/eth/tx/<id>/sign", "sign", Phase::End),
+    ("/ecdsa/rotate/<id>/first", "rotate", Phase::Start),
+    ("/ecdsa/rotate/<id>/second", "rotate", Phase::End),
+];
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: HistogramVec,
+    protocol_operations: IntCounterVec,
+    outbound_calls: HistogramVec,
+    outbound_errors: IntCounterVec,
+    rocksdb_sst_bytes: IntGauge,
+    rocksdb_keys: IntGauge,
+    sessions_in_flight: IntGaugeVec,
+    rpc_endpoint_up: IntGaugeVec,
+    rpc_connections: IntCounterVec,
+    sessions: Mutex<HashMap<(&'static str, String), Instant>>,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("nyc".to_string()), None)?;
+        let latency_buckets = exponential_buckets(0.005, 2.0, 14)?;
+
+        let http_requests = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency")
+                .buckets(latency_buckets.clone()),
+            &["method", "route", "status"],
+        )?;
+        let protocol_operations = IntCounterVec::new(
+            Opts::new("protocol_operations_total", "Completed protocol runs"),
+            &["operation", "outcome"],
+        )?;
+        let outbound_calls = HistogramVec::new(
+            HistogramOpts::new(
+                "outbound_call_duration_seconds",
+                "Latency of calls to HCMC and Ethereum RPC",
+            )
+            .buckets(latency_buckets),
+            &["target", "call"],
+        )?;
+        let outbound_errors = IntCounterVec::new(
+            Opts::new(
+                "outbound_call_errors_total",
+                "Failed calls to HCMC and Ethereum RPC",
+            ),
+            &["target", "call"],
+        )?;
+        let rocksdb_sst_bytes =
+            IntGauge::new("rocksdb_sst_bytes", "Total size of RocksDB SST files")?;
+        let rocksdb_keys = IntGauge::new("rocksdb_keys", "Estimated number of RocksDB keys")?;
+        let sessions_in_flight = IntGaugeVec::new(
+            Opts::new(
+                "sessions_in_flight",
+                "Started but unfinished protocol sessions",
+            ),
+            &["kind"],
+        )?;
+
+        let rpc_endpoint_up = IntGaugeVec::new(
+            Opts::new(
+                "rpc_endpoint_up",
+                "Whether the last call to a pooled RPC endpoint succeeded",
+            ),
+            &["chain", "endpoint", "transport"],
+        )?;
+        let rpc_connections = IntCounterVec::new(
+            Opts::new(
+                "rpc_connections_total",
+                "Connections opened to pooled RPC endpoints",
+            ),
+            &["chain", "endpoint", "transport"],
+        )?;
+
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(protocol_operations.clone()))?;
+        registry.register(Box::new(outbound_calls.clone()))?;
+        registry.register(Box::new(outbound_errors.clone()))?;
+        registry.register(Box::new(rocksdb_sst_bytes.clone()))?;
+        registry.register(Box::new(rocksdb_keys.clone()))?;
+        registry.register(Box::new(sessions_in_flight.clone()))?;
+        registry.register(Box::new(rpc_endpoint_up.clone()))?;
+        registry.register(Box::new(rpc_connections.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            http_requests,
+            protocol_operations,
+            outbound_calls,
+            outbound_errors,
+            rocksdb_sst_bytes,
+            rocksdb_keys,
+            sessions_in_flight,
+            rpc_endpoint_up,
+            rpc_connections,
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Times an outbound call, counting it as an error when `fut` fails.
+    pub async fn observe_outbound<T, F>(&self, target: &str, call: &str, fut: F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let start = Instant::now();
+        let result = fut
+            .instrument(info_span!("outbound", service = target, call))
+            .await;
+        let elapsed = start.elapsed();
+        debug!(
+            service = target,
+            call,
+            elapsed_ms = elapsed.as_millis() as u64,
+            failed = result.is_err(),
+            "Outbound call"
+        );
+        self.outbound_calls
+            .with_label_values(&[target, call])
+            .observe(elapsed.as_secs_f64());
+        if result.is_err() {
+            self.outbound_errors
+                .with_label_values(&[target, call])
+                .inc();
+        }
+        result
+    }
+
+    /// `endpoint` is the position of the URL in the chain's `rpc_urls`, the
+    /// URL itself often holds an API key.
+    pub fn rpc_endpoint_health(&self, chain: &str, endpoint: usize, transport: &str, up: bool) {
+        self.rpc_endpoint_up
+            .with_label_values(&[chain, &endpoint.to_string(), transport])
+            .set(up as i64);
+    }
+
+    pub fn rpc_connected(&self, chain: &str, endpoint: usize, transport: &str) {
+        self.rpc_connections
+            .with_label_values(&[chain, &endpoint.to_string(), transport])
+            .inc();
+    }
+
+    fn track_session(&self, kind: &'static str, id: String, phase: Phase) {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        match phase {
+            Phase::Start => {
+                sessions.insert((kind, id), Instant::now());
+            }
+            Phase::End => {
+                sessions.remove(&(kind, id));
+            }
+        }
+        self.refresh_sessions(&mut sessions);
+    }
+
+    fn refresh_sessions(&self, sessions: &mut HashMap<(&'static str, String), Instant>) {
+        sessions.retain(|_, started| started.elapsed() < SESSION_TTL);
+        for (_, kind, _) in SESSION_ROUTES {
+            let count = sessions.keys().filter(|(k, _)| k == kind).count();
+            self.sessions_in_flight
+                .with_label_values(&[kind])
+                .set(count as i64);
+        }
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn render(&self, db: &db::DB) -> Result<String> {
+        if let Ok(bytes) = db::int_property(db, "rocksdb.total-sst-files-size") {
+            self.rocksdb_sst_bytes.set(bytes as i64);
+        }
+        if let Ok(keys) = db::estimate_num_keys(db) {
+            self.rocksdb_keys.set(keys as i64);
+        }
+        {
+            let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+            self.refresh_sessions(&mut sessions);
+        }
+
+        Ok(TextEncoder::new().encode_to_string(&self.registry.gather())?)
+    }
+}
+
+/// Records latency, protocol outcomes and session progress for every request.
+pub struct MetricsFairing(pub Arc<Metrics>);
+
+#[derive(Clone, Copy)]
+struct RequestStart(Option<Instant>);
+
+#[rocket::async_trait]
+impl Fairing for MetricsFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Prometheus metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let route = request
+            .route()
+            .map(|route| route.uri.path().to_string())
+            .unwrap_or_else(|| "unmatched".to_string());
+        let status = response.status();
+
+        if let RequestStart(Some(start)) = request.local_cache(|| RequestStart(None)) {
+            self.0
+                .http_requests
+                .with_label_values(&[request.method().as_str(), &route, &status.code.to_string()])
+                .observe(start.elapsed().as_secs_f64());
+        }
+
+        let succeeded = status.class().is_success();
+        if let Some((_, operation)) = PROTOCOL_ROUTES.iter().find(|(path, _)| *path == route) {
+            let outcome = if succeeded { "success" } else { "failure" };
+            self.0
+                .protocol_operations
+                .with_label_values(&[operation, outcome])
+                .inc();
+        }
+
+        if let Some((_, kind, phase)) = SESSION_ROUTES.iter().find(|(path, _, _)| *path == route) {
+            // A failed first step never opened a session, a failed last step still closes it
+            if succeeded || *phase == Phase::End {
+                if let Some(Ok(id)) = request.param::<String>(0) {
+                    self.0.track_session(kind, id, *phase);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,5 @@
+pub mod logging;
+pub mod metrics;
+pub mod otel;
+pub mod redact;
+pub mod request_id;
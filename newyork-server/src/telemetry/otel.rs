@@ -0,0 +1,201 @@
+//! OpenTelemetry trace export.
+//!
+//! Every handler runs in a request span. Protocol steps that share a session
+//! id and [`SessionNonce`] additionally hang under one session span through
+//! [`SessionTraces`], so a whole keygen or signing round shows up as a single
+//! trace whose root measures the end-to-end latency.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::Result;
+use opentelemetry::sdk::export::trace::SpanData;
+use opentelemetry::sdk::trace::{self as sdktrace, Span as SdkSpan, SpanProcessor, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::TraceResult;
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+use rocket::{Request, Response};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use uuid::Uuid;
+
+use super::metrics::SESSION_TTL;
+use crate::utils::settings::OtelSettings;
+
+/// Builds the OTLP exporter, `None` when no collector is configured.
+/// Spans are exported in batches from the Tokio runtime the server runs on.
+pub fn tracer(settings: &OtelSettings) -> Result<Option<Tracer>> {
+    let endpoint = match &settings.endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+
+    let tracer =
+        opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(
+                opentelemetry_otlp::new_exporter()
+                    .tonic()
+                    .with_endpoint(endpoint.clone()),
+            )
+            .with_trace_config(sdktrace::config().with_resource(Resource::new(vec![
+                KeyValue::new("service.name", settings.service_name.clone()),
+            ])))
+            .install_batch(opentelemetry::runtime::Tokio)?;
+    Ok(Some(tracer))
+}
+
+/// Flushes spans still buffered by the batch exporter.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Keeps finished spans in memory, for tests and local debugging.
+#[derive(Debug, Clone, Default)]
+pub struct InMemorySpans(Arc<Mutex<Vec<SpanData>>>);
+
+impl InMemorySpans {
+    pub fn spans(&self) -> Vec<SpanData> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+impl SpanProcessor for InMemorySpans {
+    fn on_start(&self, _span: &mut SdkSpan, _cx: &Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).push(span);
+    }
+
+    fn force_flush(&self) -> TraceResult<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> TraceResult<()> {
+        Ok(())
+    }
+}
+
+pub const SESSION_NONCE_HEADER: &str = "X-Session-Nonce";
+
+/// Tells apart the sessions of one kind on one wallet, e.g. two signatures
+/// requested concurrently. The first step of a session answers with a new
+/// nonce in `X-Session-Nonce`, which the client sends back with the
+/// following steps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionNonce(pub String);
+
+/// The nonce a request used, echoed by [`SessionNonceFairing`].
+struct UsedNonce(Option<SessionNonce>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SessionNonce {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let UsedNonce(nonce) = request.local_cache(|| {
+            let nonce = match request.headers().get_one(SESSION_NONCE_HEADER) {
+                Some(nonce) if Uuid::parse_str(nonce).is_ok() => nonce.to_string(),
+                _ => Uuid::new_v4().to_string(),
+            };
+            UsedNonce(Some(SessionNonce(nonce)))
+        });
+        match nonce {
+            Some(nonce) => Outcome::Success(nonce.clone()),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+/// Returns the session nonce to the client on the routes that take one.
+pub struct SessionNonceFairing;
+
+#[rocket::async_trait]
+impl Fairing for SessionNonceFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Session nonce",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if let UsedNonce(Some(nonce)) = request.local_cache(|| UsedNonce(None)) {
+            response.set_header(Header::new(SESSION_NONCE_HEADER, nonce.0.clone()));
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Step {
+    /// Starts a new session, replacing an unfinished one with the same id
+    First,
+    Next,
+    /// Ends the session once the handler returns
+    Last,
+}
+
+/// Root span and start of each session, by kind, id and nonce.
+type Roots = HashMap<(&'static str, String, String), (Span, Instant)>;
+
+/// Root spans of the protocol sessions in progress.
+#[derive(Default)]
+pub struct SessionTraces {
+    roots: Mutex<Roots>,
+}
+
+impl SessionTraces {
+    pub fn new() -> Self {
+        SessionTraces::default()
+    }
+
+    /// Links the current request span to the session `kind`/`id`/`nonce`.
+    /// Hold the returned guard until the handler returns.
+    #[must_use]
+    pub fn step(
+        &self,
+        kind: &'static str,
+        id: &str,
+        nonce: &SessionNonce,
+        step: Step,
+    ) -> SessionStep {
+        let mut roots = self.roots.lock().unwrap_or_else(|e| e.into_inner());
+        roots.retain(|_, (_, started)| started.elapsed() < SESSION_TTL);
+
+        let key = (kind, id.to_string(), nonce.0.clone());
+        if step == Step::First {
+            roots.remove(&key);
+        }
+        let (root, _) = roots.entry(key.clone()).or_insert_with(|| {
+            (
+                info_span!(
+                    parent: None,
+                    "session",
+                    session.kind = kind,
+                    session.id = id,
+                    session.nonce = %nonce.0
+                ),
+                Instant::now(),
+            )
+        });
+        Span::current().set_parent(root.context());
+
+        match step {
+            Step::Last => SessionStep {
+                _root: roots.remove(&key).map(|(root, _)| root),
+            },
+            _ => SessionStep { _root: None },
+        }
+    }
+}
+
+/// Ends the session span, if this was the last step, when dropped.
+pub struct SessionStep {
+    _root: Option<Span>,
+}
@@ -0,0 +1,79 @@
+//! Scrubs secrets from formatted log lines.
+//!
+//! Redaction runs on the final output of every log event, so it also covers
+//! messages from dependencies and `Debug` output of structs nobody audited.
+//! It errs on the side of hiding too much: any long run of digits or bare hex
+//! is treated as key material, which is how curv scalars, secret shares and
+//! Paillier keys render.
+
+use std::borrow::Cow;
+use std::io;
+
+use regex::{Captures, Regex};
+use tracing_subscriber::fmt::MakeWriter;
+
+pub const REDACTED: &str = "[REDACTED]";
+
+lazy_static! {
+    static ref BEARER: Regex = Regex::new(r"(?i)(bearer\s+)[A-Za-z0-9\-._~+/]+=*").unwrap();
+    static ref JWT: Regex =
+        Regex::new(r"eyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]*").unwrap();
+    static ref SECRET_FIELD: Regex = Regex::new(
+        r#"(?i)(\b(?:token|access_token|authorization|password|pass|secret|secret_share|x1|private|paillier_private|dk)"?\s*[:=]\s*)("[^"]*"|[^\s,}\]]+)"#
+    )
+    .unwrap();
+    static ref BIG_DECIMAL: Regex = Regex::new(r"\b[0-9]{40,}\b").unwrap();
+    /// `0x` prefixed values are hashes, addresses and raw transactions, which are public
+    static ref BARE_HEX: Regex = Regex::new(r"\b(0x)?[0-9a-fA-F]{64,}\b").unwrap();
+}
+
+pub fn redact(line: &str) -> Cow<'_, str> {
+    let mut out = Cow::Borrowed(line);
+    for (re, replacement) in [
+        (&*BEARER, "${1}[REDACTED]"),
+        (&*JWT, REDACTED),
+        (&*SECRET_FIELD, "${1}[REDACTED]"),
+        (&*BIG_DECIMAL, REDACTED),
+    ] {
+        if let Cow::Owned(s) = re.replace_all(&out, replacement) {
+            out = Cow::Owned(s);
+        }
+    }
+    if let Cow::Owned(s) = BARE_HEX.replace_all(&out, |caps: &Captures| match caps.get(1) {
+        Some(_) => caps[0].to_string(),
+        None => REDACTED.to_string(),
+    }) {
+        out = Cow::Owned(s);
+    }
+    out
+}
+
+/// Wraps a `MakeWriter` so everything it writes goes through [`redact`].
+pub struct Redacting<M>(pub M);
+
+impl<'a, M> MakeWriter<'a> for Redacting<M>
+where
+    M: MakeWriter<'a>,
+{
+    type Writer = RedactingWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter(self.0.make_writer())
+    }
+}
+
+pub struct RedactingWriter<W>(W);
+
+impl<W: io::Write> io::Write for RedactingWriter<W> {
+    /// The fmt layer writes each event with a single call, so a secret is
+    /// never split across two buffers.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let line = String::from_utf8_lossy(buf);
+        self.0.write_all(redact(&line).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
@@ -0,0 +1,68 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+use rocket::{Data, Request, Response};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_LEN: usize = 64;
+
+/// Correlates the logs of one request across handlers, storage and the
+/// calls made to HCMC. A caller supplied `X-Request-Id` is reused when it is
+/// well formed, otherwise a new one is generated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn of(request: &Request<'_>) -> RequestId {
+        request
+            .local_cache(|| RequestId::from_header(request.headers().get_one(REQUEST_ID_HEADER)))
+            .clone()
+    }
+
+    fn from_header(header: Option<&str>) -> RequestId {
+        match header {
+            Some(id)
+                if !id.is_empty()
+                    && id.len() <= MAX_LEN
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+            {
+                RequestId(id.to_string())
+            }
+            _ => RequestId(Uuid::new_v4().to_string()),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(RequestId::of(request))
+    }
+}
+
+/// Assigns the request id before routing and echoes it in the response.
+pub struct RequestIdFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request id",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        RequestId::of(request);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_header(Header::new(REQUEST_ID_HEADER, RequestId::of(request).0));
+    }
+}
@@ -2,6 +2,7 @@
 mod test_suites {
 
     use crate::utils::settings::get_app_env;
+    use crate::utils::settings::Settings;
     use crate::utils::settings::TestEnv;
 
     use super::super::routes::ecdsa;
@@ -267,6 +268,9 @@ mod test_suites {
             party_two_sign_message,
             x_pos_child_key: x_pos,
             y_pos_child_key: y_pos,
+            tx: None,
+            summary: None,
+            unsigned_tx: None,
         };
 
         let body = serde_json::to_string(&request).unwrap();
@@ -295,7 +299,7 @@ mod test_suites {
 
     #[test]
     fn key_gen_and_sign() {
-        let env_configs = get_app_env::<TestEnv>(".env.test");
+        let env_configs = get_app_env::<TestEnv>(".env.test").expect("valid test env");
         let signin_url = env_configs.test_signin_url;
         let test_email = env_configs.test_email;
         let test_pass = env_configs.test_pass;
@@ -319,7 +323,11 @@ mod test_suites {
         let auth_header = Header::new("Authorization", format!("Bearer {}", http_resp.Msg));
         let user_id_header = Header::new("user_id", test_email);
 
-        let client = Client::tracked(server::get_server()).expect("valid rocket instance");
+        // Signs a raw hash, which requires opting out of transaction intents
+        let mut settings = Settings::load(&[]).expect("valid settings");
+        settings.features.require_tx_intent = false;
+        let client = Client::tracked(server::build_server(settings).unwrap())
+            .expect("valid rocket instance");
 
         let (id, master_key_2): (String, MasterKey2) =
             key_gen(&client, auth_header.clone(), user_id_header.clone());
@@ -393,3 +401,2573 @@ mod test_suites {
         assert_eq!(401, response.status().code);
     }
 }
+
+#[cfg(test)]
+mod storage_suites {
+    use crate::storage::backup;
+    use crate::storage::codec::{self, Encoding, SCHEMA_VERSION};
+    use crate::storage::db::{self, MPCStruct, DB};
+    use curv::BigInt;
+    use uuid::Uuid;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Record {
+        pos: u32,
+        value: BigInt,
+    }
+
+    #[test]
+    fn codec_round_trip() {
+        let record = Record {
+            pos: 7,
+            value: BigInt::from(123_456_789),
+        };
+
+        for encoding in [Encoding::Json, Encoding::Cbor] {
+            let bytes = codec::encode(&record, encoding).unwrap();
+            let header = codec::header(&bytes).unwrap().unwrap();
+            assert_eq!(header.version, SCHEMA_VERSION);
+            assert_eq!(header.encoding, encoding);
+            assert_eq!(codec::decode::<Record>(&bytes).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn codec_reads_legacy_json() {
+        let legacy = br#"{"pos":3,"value":"ff"}"#;
+        assert!(codec::header(legacy).unwrap().is_none());
+
+        let record: Record = codec::decode(legacy).unwrap();
+        assert_eq!(record.pos, 3);
+    }
+
+    #[test]
+    fn codec_rejects_newer_schema() {
+        let mut bytes = codec::encode(&1u32, Encoding::Cbor).unwrap();
+        bytes[1] = SCHEMA_VERSION + 1;
+        assert!(codec::decode::<u32>(&bytes).is_err());
+    }
+
+    struct KeyShare;
+
+    impl MPCStruct for KeyShare {
+        fn to_string(&self) -> String {
+            "KeyShare".to_string()
+        }
+    }
+
+    fn share(pos: u32) -> Record {
+        Record {
+            pos,
+            value: BigInt::from(u64::from(pos)),
+        }
+    }
+
+    #[test]
+    fn backups_are_purged_verified_and_restored() {
+        let root = std::env::temp_dir().join(format!("backup-{}", Uuid::new_v4()));
+        let (db_path, dir) = (root.join("db"), root.join("backups"));
+        let db = DB::Local(db::open(&db_path).unwrap());
+        let DB::Local(rocks) = &db;
+
+        db::insert(&db, "alice", "w1", &KeyShare, share(1)).unwrap();
+        let first = backup::create(rocks, &dir).unwrap();
+        db::insert(&db, "alice", "w2", &KeyShare, share(2)).unwrap();
+        let second = backup::create(rocks, &dir).unwrap();
+        assert!(second.backup_id > first.backup_id);
+        assert_eq!((first.records, second.records), (1, 2));
+
+        backup::purge(&dir, 1).unwrap();
+        assert_eq!(backup::list(&dir).unwrap(), vec![second.clone()]);
+
+        let report = backup::verify(&dir, second.backup_id).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.expected_records, Some(2));
+        assert_eq!(report.actual_records, 2);
+
+        let restored_path = root.join("restored");
+        backup::restore(&dir, None, &restored_path).unwrap();
+        let restored = DB::Local(db::open(&restored_path).unwrap());
+        let record: Option<Record> = db::get(&restored, "alice", "w2", &KeyShare).unwrap();
+        assert_eq!(record, Some(share(2)));
+
+        drop((db, restored));
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
+
+#[cfg(test)]
+mod settings_suites {
+    use std::collections::BTreeMap;
+
+    use crate::eth::chains::ChainRegistry;
+    use crate::utils::settings::{ChainSettings, Profile, Settings};
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> BTreeMap<String, String> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn cli_flags_override_profile_defaults() {
+        let settings = Settings::from_sources(
+            &args(&[
+                "--profile",
+                "test",
+                "--config",
+                "./missing-settings.toml",
+                "--db.path=./db-cli",
+                "--timeouts.rpc-secs",
+                "3",
+            ]),
+            &env(&[]),
+        )
+        .unwrap();
+
+        assert_eq!(settings.profile, Profile::Test);
+        assert_eq!(settings.db.path, "./db-cli");
+        assert_eq!(settings.timeouts.rpc_secs, 3);
+        assert_eq!(settings.timeouts.hcmc_secs, 10);
+    }
+
+    #[test]
+    fn environment_layers_are_ordered() {
+        let vars = env(&[
+            ("NYC_PROFILE", "test"),
+            ("HCMC_HOST", "http://legacy.example.com"),
+            ("RUST_LOG", "debug"),
+            ("NYC_HCMC_HOST", "http://hcmc.example.com"),
+            ("NYC_TIMEOUTS__RPC_SECS", "5"),
+            ("NYC_DB__PATH", "./db-env"),
+            ("UNRELATED", "1"),
+        ]);
+        let settings = Settings::from_sources(
+            &args(&["--config", "./missing-settings.toml", "--db.path=./db-cli"]),
+            &vars,
+        )
+        .unwrap();
+
+        assert_eq!(settings.profile, Profile::Test);
+        assert_eq!(settings.hcmc_host, "http://hcmc.example.com");
+        assert_eq!(settings.log.filter, "debug");
+        assert_eq!(settings.timeouts.rpc_secs, 5);
+        assert_eq!(settings.db.path, "./db-cli");
+    }
+
+    #[test]
+    fn invalid_settings_are_reported_together() {
+        let mut settings = Settings::defaults(Profile::Prod);
+        settings.timeouts.rpc_secs = 0;
+        settings.siwe.nonce_key = Some("abcd".to_string());
+
+        let err = settings.validate().unwrap_err().to_string();
+        assert!(err.contains("hcmc_host is not set"));
+        assert!(err.contains("eth.rpc_url is not set"));
+        assert!(err.contains("timeouts"));
+        assert!(err.contains("siwe.nonce_key"));
+    }
+
+    #[test]
+    fn unknown_profile_is_rejected() {
+        assert!(Settings::from_sources(&args(&["--profile", "qa"]), &env(&[])).is_err());
+        assert!(Settings::from_sources(&args(&[]), &env(&[("NYC_PROFILE", "qa")])).is_err());
+    }
+
+    fn chain(chain_id: u64, name: &str) -> ChainSettings {
+        ChainSettings {
+            chain_id,
+            name: name.to_string(),
+            rpc_urls: vec![format!("wss://{}.example.com", name)],
+            broadcast_urls: Vec::new(),
+            native_symbol: "ETH".to_string(),
+            eip1559: true,
+            confirmations: 12,
+        }
+    }
+
+    #[test]
+    fn chain_registry() {
+        let mut settings = Settings::defaults(Profile::Dev);
+        let registry = ChainRegistry::new(&settings.eth);
+        assert_eq!(registry.default_chain().chain_id, 1337);
+        assert_eq!(
+            registry.default_chain().rpc_urls,
+            vec![settings.eth.rpc_url.clone()]
+        );
+
+        settings.eth.default_chain_id = 137;
+        settings.eth.chains = vec![chain(1, "ethereum"), chain(137, "polygon")];
+        settings.validate().unwrap();
+        let registry = ChainRegistry::new(&settings.eth);
+        assert_eq!(registry.get(None).unwrap().name, "polygon");
+        assert_eq!(registry.get(Some(1)).unwrap().name, "ethereum");
+        assert!(registry.get(Some(56)).is_err());
+    }
+
+    #[test]
+    fn invalid_chains_are_reported() {
+        let mut settings = Settings::defaults(Profile::Dev);
+        settings.eth.chains = vec![chain(1, "ethereum"), chain(1, "mainnet"), chain(10, "")];
+        settings.eth.chains[0].rpc_urls = vec!["ftp://ethereum.example.com".to_string()];
+
+        let err = settings.validate().unwrap_err().to_string();
+        assert!(err.contains("eth.chains[1] repeats chain id 1"));
+        assert!(err.contains("eth.chains[2].name"));
+        assert!(err.contains("eth.chains[0].rpc_urls must use"));
+        assert!(err.contains("eth.default_chain_id 1337 is not in eth.chains"));
+    }
+}
+
+#[cfg(test)]
+mod metrics_suites {
+    use rocket::http::{ContentType, Status};
+    use rocket::local::blocking::Client;
+    use uuid::Uuid;
+
+    use crate::server;
+    use crate::utils::settings::{Profile, Settings};
+
+    #[test]
+    fn routes_are_measured() {
+        let path = std::env::temp_dir().join(format!("metrics-{}", Uuid::new_v4()));
+        let mut settings = Settings::defaults(Profile::Test);
+        settings.db.path = path.to_string_lossy().to_string();
+        settings.features.eth_routes = false;
+        let client = Client::tracked(server::build_server(settings).unwrap())
+            .expect("valid rocket instance");
+
+        assert_eq!(client.get("/health/live").dispatch().status(), Status::Ok);
+        let status = client
+            .post("/ecdsa/sign/wallet/second")
+            .header(ContentType::JSON)
+            .body("{}")
+            .dispatch()
+            .status();
+        assert_eq!(status, Status::Unauthorized);
+
+        let response = client.get("/metrics").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        assert!(body.contains(
+            r#"nyc_http_request_duration_seconds_count{method="GET",route="/health/live",status="200"} 1"#
+        ));
+        assert!(
+            body.contains(r#"nyc_protocol_operations_total{operation="sign",outcome="failure"} 1"#)
+        );
+
+        drop(client);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[cfg(test)]
+mod send_suites {
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::blocking::Client;
+    use secp256k1::SecretKey;
+    use serde_json::json;
+    use uuid::Uuid;
+    use web3::signing::{keccak256, Key, SecretKeyRef};
+
+    use super::eth_suites::EIP155_EXAMPLE;
+    use super::siwe_suites::message;
+    use crate::auth::siwe;
+    use crate::eth::messages;
+    use crate::eth::tx::SignedTx;
+    use crate::eth::wallets::{self, WalletAddress};
+    use crate::server;
+    use crate::utils::settings::{Profile, Settings};
+    use crate::AppConfig;
+
+    /// Signs in with Ethereum, returning the headers of the session.
+    fn sign_in(client: &Client) -> (Header<'static>, Header<'static>) {
+        let key = SecretKey::from_slice(&keccak256(b"sender")).unwrap();
+        let key = SecretKeyRef::new(&key);
+        let response = client.get("/auth/siwe/nonce").dispatch();
+        let body: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let text = message(
+            &siwe::checksum(key.address()),
+            body["nonce"].as_str().unwrap(),
+            "",
+        );
+        let signature = key
+            .sign_message(messages::personal_digest(text.as_bytes()).as_bytes())
+            .unwrap();
+        let signature = format!(
+            "0x{}{}{:02x}",
+            hex::encode(signature.r),
+            hex::encode(signature.s),
+            27 + signature.v
+        );
+        let response = client
+            .post("/auth/siwe/verify")
+            .header(ContentType::JSON)
+            .body(json!({ "message": text, "signature": signature }).to_string())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        (
+            Header::new(
+                "Authorization",
+                format!("Bearer {}", body["token"].as_str().unwrap()),
+            ),
+            Header::new("user_id", body["user_id"].as_str().unwrap().to_string()),
+        )
+    }
+
+    #[test]
+    fn unknown_senders_are_refused_before_any_rpc_call() {
+        let path = std::env::temp_dir().join(format!("send-{}", Uuid::new_v4()));
+        let mut settings = Settings::defaults(Profile::Test);
+        settings.db.path = path.to_string_lossy().to_string();
+        settings.siwe.domain = Some("wallet.example.com".to_string());
+        // Nothing listens on port 1
+        settings.eth.rpc_url = "http://127.0.0.1:1".to_string();
+        // The chain of `EIP155_EXAMPLE`
+        settings.eth.default_chain_id = 1;
+        let client = Client::tracked(server::build_server(settings).unwrap())
+            .expect("valid rocket instance");
+        let (auth_header, user_id_header) = sign_in(&client);
+        let user_id = user_id_header.value().to_string();
+        let send = || {
+            client
+                .post("/eth/tx/send")
+                .header(ContentType::JSON)
+                .header(auth_header.clone())
+                .header(user_id_header.clone())
+                .body(json!({ "raw_tx": format!("0x{}", EIP155_EXAMPLE) }).to_string())
+                .dispatch()
+                .status()
+        };
+        let state = client.rocket().state::<AppConfig>().unwrap();
+        let last_error = || {
+            state
+                .audit
+                .user_events(&user_id, None, 10)
+                .unwrap()
+                .pop()
+                .unwrap()
+                .error
+                .unwrap()
+        };
+
+        assert_eq!(send(), Status::InternalServerError);
+        assert!(last_error().contains("is not the address of one of your wallets"));
+
+        // Once the sender is known, the request reaches the unreachable RPC
+        let from = SignedTx::decode(&hex::decode(EIP155_EXAMPLE).unwrap())
+            .unwrap()
+            .from;
+        let wallet = WalletAddress {
+            address: from,
+            wallet_id: "wallet".to_string(),
+            derivation_path: "0/0".to_string(),
+        };
+        wallets::record(&state.db, &user_id, &wallet).unwrap();
+        assert_eq!(send(), Status::InternalServerError);
+        assert!(!last_error().contains("is not the address of one of your wallets"));
+
+        drop(client);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[cfg(test)]
+mod health_suites {
+    use rocket::http::Status;
+    use rocket::local::blocking::Client;
+    use uuid::Uuid;
+
+    use crate::server;
+    use crate::utils::settings::{Profile, Settings};
+
+    #[test]
+    fn unreachable_dependencies_are_not_ready() {
+        let path = std::env::temp_dir().join(format!("health-{}", Uuid::new_v4()));
+        let mut settings = Settings::defaults(Profile::Test);
+        settings.db.path = path.to_string_lossy().to_string();
+        // Nothing listens on port 1
+        settings.hcmc_host = "http://127.0.0.1:1".to_string();
+        settings.eth.rpc_url = "http://127.0.0.1:1".to_string();
+        let client = Client::tracked(server::build_server(settings).unwrap())
+            .expect("valid rocket instance");
+
+        let response = client.get("/health/ready").dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(body["ready"], false);
+        assert_eq!(body["rocksdb"]["up"], true);
+        assert_eq!(body["hcmc"]["up"], false);
+        assert!(body["hcmc"]["error"].is_string());
+        assert_eq!(body["ethereum"]["up"], false);
+        assert!(body["rocksdb"]["latency_ms"].is_u64());
+        assert!(body["rpc"].is_object());
+
+        drop(client);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[cfg(test)]
+mod redact_suites {
+    use crate::telemetry::redact::redact;
+
+    #[test]
+    fn tokens_and_secret_fields_are_hidden() {
+        let line = r#"Authorization: Bearer abc.def-123 {"token":"s3cr3t","user_id":"u1"} x1: 12"#;
+        let out = redact(line);
+
+        assert!(!out.contains("abc.def-123"));
+        assert!(!out.contains("s3cr3t"));
+        assert!(!out.contains("x1: 12"));
+        assert!(out.contains(r#""user_id":"u1""#));
+    }
+
+    #[test]
+    fn key_material_is_hidden_but_hashes_are_kept() {
+        let paillier_n = "9".repeat(600);
+        let scalar = "ab".repeat(32);
+        let tx_hash = format!("0x{}", "cd".repeat(32));
+        let line = format!("n={} share {} tx {}", paillier_n, scalar, tx_hash);
+        let out = redact(&line);
+
+        assert!(!out.contains(&paillier_n));
+        assert!(!out.contains(&scalar));
+        assert!(out.contains(&tx_hash));
+    }
+}
+
+#[cfg(test)]
+mod otel_suites {
+    use opentelemetry::sdk::trace::TracerProvider;
+    use opentelemetry::trace::TracerProvider as _;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::telemetry::otel::{InMemorySpans, SessionNonce, SessionTraces, Step};
+
+    fn nonce() -> SessionNonce {
+        SessionNonce(uuid::Uuid::new_v4().to_string())
+    }
+
+    #[test]
+    fn session_steps_share_one_trace() {
+        let exported = InMemorySpans::default();
+        let provider = TracerProvider::builder()
+            .with_span_processor(exported.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let traces = SessionTraces::new();
+        let nonce = nonce();
+
+        tracing::subscriber::with_default(subscriber, || {
+            for step in [Step::First, Step::Next, Step::Last] {
+                let request = info_span!("request");
+                let _entered = request.enter();
+                let _session = traces.step("keygen", "id-1", &nonce, step);
+                info_span!("db.insert").in_scope(|| {});
+            }
+        });
+
+        let spans = exported.spans();
+        let session = spans.iter().find(|s| s.name == "session").unwrap();
+        let requests: Vec<_> = spans.iter().filter(|s| s.name == "request").collect();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(spans.len(), 7);
+        for span in &spans {
+            assert_eq!(
+                span.span_context.trace_id(),
+                session.span_context.trace_id()
+            );
+        }
+        for request in requests {
+            assert_eq!(request.parent_span_id, session.span_context.span_id());
+        }
+    }
+
+    #[test]
+    fn concurrent_sessions_on_one_wallet_are_apart() {
+        let exported = InMemorySpans::default();
+        let provider = TracerProvider::builder()
+            .with_span_processor(exported.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let traces = SessionTraces::new();
+        let (first, second) = (nonce(), nonce());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let steps = [
+                ("sign", &first, Step::First),
+                ("sign", &second, Step::First),
+                ("rotate", &first, Step::First),
+                ("sign", &first, Step::Last),
+                ("sign", &second, Step::Last),
+                ("rotate", &first, Step::Last),
+            ];
+            for (kind, nonce, step) in steps {
+                let request = info_span!("request", kind, nonce = %nonce.0);
+                let _entered = request.enter();
+                let _session = traces.step(kind, "wallet", nonce, step);
+            }
+        });
+
+        let spans = exported.spans();
+        let sessions: Vec<_> = spans.iter().filter(|s| s.name == "session").collect();
+        assert_eq!(sessions.len(), 3);
+        for session in &sessions {
+            let children = spans
+                .iter()
+                .filter(|s| s.parent_span_id == session.span_context.span_id())
+                .count();
+            assert_eq!(children, 2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod audit_suites {
+    use std::sync::Arc;
+
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::blocking::Client;
+    use uuid::Uuid;
+
+    use crate::auth::guards::AuthPayload;
+    use crate::server;
+    use crate::storage::audit::{self, AuditLog, AuditRecord, Operation, Outcome, AUDIT_CF};
+    use crate::storage::codec;
+    use crate::storage::db::{self, DB};
+    use crate::utils::settings::{Profile, Settings};
+    use crate::AppConfig;
+
+    fn payload(user_id: &str) -> AuthPayload {
+        AuthPayload {
+            token: String::new(),
+            user_id: user_id.to_string(),
+            request_id: Uuid::new_v4().to_string(),
+        }
+    }
+
+    #[test]
+    fn chain_verifies_until_tampered() {
+        let path = std::env::temp_dir().join(format!("audit-{}", Uuid::new_v4()));
+        let db = Arc::new(DB::Local(db::open(&path).unwrap()));
+        let log = AuditLog::open(db.clone()).unwrap();
+
+        log.append(
+            AuditRecord::new(Operation::KeygenFirst, &payload("alice")).wallet("w1"),
+            Outcome::Success,
+            None,
+        )
+        .unwrap();
+        log.append(
+            AuditRecord::new(Operation::KeygenFirst, &payload("bob")),
+            Outcome::Success,
+            None,
+        )
+        .unwrap();
+        let failed = log
+            .append(
+                AuditRecord::new(Operation::SignSecond, &payload("alice")).wallet("w1"),
+                Outcome::Failure,
+                Some("bad proof".to_string()),
+            )
+            .unwrap();
+        assert_eq!(failed.outcome, Outcome::Failure);
+
+        let alice = log.user_events("alice", None, 10).unwrap();
+        assert_eq!(alice.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(log.user_events("alice", Some(0), 10).unwrap(), vec![failed]);
+
+        let DB::Local(rocksdb_client) = db.as_ref();
+        let report = audit::verify(rocksdb_client).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.events, 3);
+
+        let cf = rocksdb_client.cf_handle(AUDIT_CF).unwrap();
+        let key = "e/00000000000000000002";
+        let mut event: audit::AuditEvent =
+            codec::decode(&rocksdb_client.get_cf(cf, key).unwrap().unwrap()).unwrap();
+        event.outcome = Outcome::Success;
+        event.error = None;
+        rocksdb_client
+            .put_cf(
+                cf,
+                key,
+                codec::encode(&event, codec::DEFAULT_ENCODING).unwrap(),
+            )
+            .unwrap();
+        assert!(!audit::verify(rocksdb_client).unwrap().is_ok());
+
+        drop(log);
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn invalid_tokens_are_not_audited() {
+        let path = std::env::temp_dir().join(format!("audit-auth-{}", Uuid::new_v4()));
+        let mut settings = Settings::defaults(Profile::Test);
+        settings.db.path = path.to_string_lossy().to_string();
+        let client = Client::tracked(server::build_server(settings).unwrap())
+            .expect("valid rocket instance");
+
+        let status = client
+            .post("/ecdsa/keygen/first")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer siwe.forged"))
+            .header(Header::new("user_id", "victim"))
+            .dispatch()
+            .status();
+        assert_eq!(status, Status::InternalServerError);
+        // Following steps of a session authenticate too
+        let status = client
+            .post("/ecdsa/keygen/wallet/chaincode/first")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer siwe.forged"))
+            .header(Header::new("user_id", "victim"))
+            .dispatch()
+            .status();
+        assert_eq!(status, Status::InternalServerError);
+
+        let state = client.rocket().state::<AppConfig>().unwrap();
+        assert!(state
+            .audit
+            .user_events("victim", None, 10)
+            .unwrap()
+            .is_empty());
+
+        drop(client);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[cfg(test)]
+mod policy_suites {
+    use uuid::Uuid;
+    use web3::types::{Address, U256};
+
+    use crate::eth::erc20;
+    use crate::eth::tx::{Fees, UnsignedTx};
+    use crate::policy::{Policies, Policy, PolicyDenied, TxSummary};
+    use crate::storage::db::{self, DB};
+
+    fn tx(to: u64, value: u64) -> TxSummary {
+        TxSummary {
+            chain_id: 1,
+            to: Some(Address::from_low_u64_be(to)),
+            value: U256::from(value),
+            gas: U256::from(21_000),
+            token_transfer: None,
+            simulation: None,
+            hashed_by_server: true,
+        }
+    }
+
+    /// A transfer of 10 tokens of contract 1 to `recipient`.
+    fn token_tx(recipient: u64) -> TxSummary {
+        UnsignedTx {
+            chain_id: 1,
+            nonce: U256::zero(),
+            gas: U256::from(60_000),
+            to: Some(Address::from_low_u64_be(1)),
+            value: U256::zero(),
+            data: erc20::transfer(Address::from_low_u64_be(recipient), U256::from(10)),
+            fees: Fees::Legacy {
+                gas_price: U256::one(),
+            },
+        }
+        .summary()
+    }
+
+    #[test]
+    fn rules_are_read_from_json() {
+        let policy: Policy = serde_json::from_str(
+            r#"{"rules": [
+                {"rule": "daily_limit", "wei": "0x64"},
+                {"rule": "deny_destinations", "addresses": ["0x0000000000000000000000000000000000000002"]},
+                {"rule": "max_gas", "gas": "0x5208"},
+                {"rule": "chain_ids", "ids": [1, 137]}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(policy.rules.len(), 4);
+        assert!(serde_json::from_str::<Policy>(r#"{"rules": [{"rule": "any"}]}"#).is_err());
+    }
+
+    #[test]
+    fn every_violated_rule_is_reported() {
+        let policy: Policy = serde_json::from_str(
+            r#"{"rules": [
+                {"rule": "daily_limit", "wei": "0x64"},
+                {"rule": "allow_destinations", "addresses": ["0x0000000000000000000000000000000000000001"]},
+                {"rule": "chain_ids", "ids": [137]}
+            ]}"#,
+        )
+        .unwrap();
+
+        let decision = policy.evaluate(Some(&tx(1, 40)), U256::from(60));
+        assert!(!decision.allowed);
+        assert_eq!(decision.violations.len(), 1);
+
+        let decision = policy.evaluate(Some(&tx(2, 41)), U256::from(60));
+        assert_eq!(decision.violations.len(), 3);
+
+        let decision = policy.evaluate(Some(&tx(1, 40)), U256::max_value());
+        assert_eq!(decision.violations.len(), 2);
+    }
+
+    #[test]
+    fn a_policy_requires_a_described_transaction() {
+        assert!(Policy::default().evaluate(None, U256::zero()).allowed);
+
+        let policy: Policy =
+            serde_json::from_str(r#"{"rules": [{"rule": "max_gas", "gas": "0x5208"}]}"#).unwrap();
+        assert!(policy.evaluate(Some(&tx(1, 1)), U256::zero()).allowed);
+        assert!(!policy.evaluate(None, U256::zero()).allowed);
+    }
+
+    #[test]
+    fn token_transfers_are_checked_against_their_recipient() {
+        let policy: Policy = serde_json::from_str(
+            r#"{"rules": [
+                {"rule": "deny_destinations", "addresses": ["0x0000000000000000000000000000000000000002"]}
+            ]}"#,
+        )
+        .unwrap();
+        let decision = policy.evaluate(Some(&token_tx(2)), U256::zero());
+        assert!(!decision.allowed);
+        assert!(decision.violations[0].contains("token recipient"));
+        assert!(policy.evaluate(Some(&token_tx(3)), U256::zero()).allowed);
+
+        // Token amounts are not wei, a daily limit refuses them
+        let policy: Policy =
+            serde_json::from_str(r#"{"rules": [{"rule": "daily_limit", "wei": "0x64"}]}"#).unwrap();
+        assert!(!policy.evaluate(Some(&token_tx(3)), U256::zero()).allowed);
+        assert!(policy.evaluate(Some(&tx(1, 0)), U256::zero()).allowed);
+    }
+
+    #[test]
+    fn summaries_sent_by_clients_are_refused() {
+        let path = std::env::temp_dir().join(format!("policy-{}", Uuid::new_v4()));
+        let db = DB::Local(db::open(&path).unwrap());
+        let policies = Policies::new();
+        let policy: Policy =
+            serde_json::from_str(r#"{"rules": [{"rule": "max_gas", "gas": "0x5208"}]}"#).unwrap();
+        policies.set(&db, "alice", "wallet", &policy).unwrap();
+
+        // A harmless summary sent next to a hash it does not describe
+        let mut summary: TxSummary = serde_json::from_str(
+            r#"{"chain_id": 1, "to": "0x0000000000000000000000000000000000000001",
+                "value": "0x0", "gas": "0x5208"}"#,
+        )
+        .unwrap();
+        let err = policies
+            .authorize(&db, "alice", "wallet", Some(&summary))
+            .unwrap_err();
+        assert!(err.is::<PolicyDenied>());
+        assert!(
+            policies
+                .dry_run(&db, "alice", "wallet", Some(&summary))
+                .unwrap()
+                .allowed
+        );
+
+        summary.hashed_by_server = true;
+        assert!(policies
+            .authorize(&db, "alice", "wallet", Some(&summary))
+            .is_ok());
+        // Wallets without rules still sign raw hashes
+        summary.hashed_by_server = false;
+        assert!(policies
+            .authorize(&db, "alice", "other", Some(&summary))
+            .is_ok());
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[cfg(test)]
+mod eth_suites {
+    use std::str::FromStr;
+
+    use secp256k1::SecretKey;
+    use web3::signing::{Key, SecretKeyRef, Signature, SigningError};
+    use web3::types::{AccessListItem, Address, Bytes, TransactionParameters, H256, U256, U64};
+    use web3::{transports, Web3};
+
+    use crate::eth::tx::{Fees, SignedTx, TxSignature, UnsignedTx};
+    use crate::eth::wallets;
+
+    pub(super) const EIP155_EXAMPLE: &str = "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83";
+
+    /// Signs everything with the same signature, to compare encodings.
+    struct FixedKey;
+
+    impl Key for FixedKey {
+        fn sign(&self, _: &[u8], _: Option<u64>) -> Result<Signature, SigningError> {
+            unreachable!("only typed transactions are signed")
+        }
+
+        fn sign_message(&self, _: &[u8]) -> Result<Signature, SigningError> {
+            Ok(Signature {
+                v: 1,
+                r: H256::from_low_u64_be(0xaa),
+                s: H256::from_low_u64_be(0xbb),
+            })
+        }
+
+        fn address(&self) -> Address {
+            Address::zero()
+        }
+    }
+
+    #[test]
+    fn legacy_tx_matches_eip155_example() {
+        let tx = UnsignedTx {
+            chain_id: 1,
+            nonce: U256::from(9),
+            gas: U256::from(21_000),
+            to: Some(Address::from_str("3535353535353535353535353535353535353535").unwrap()),
+            value: U256::exp10(18),
+            data: Bytes::default(),
+            fees: Fees::Legacy {
+                gas_price: U256::from(20_000_000_000u64),
+            },
+        };
+        assert_eq!(
+            tx.sighash(),
+            H256::from_str("daf5a779ae972f972197303d7b574746c7ef83eadac0f2791ad23db92e4c8e53")
+                .unwrap()
+        );
+
+        let signature = TxSignature {
+            r: U256::from_dec_str(
+                "18515461264373351373200002665853028612451056578545711640558177340181847433846",
+            )
+            .unwrap(),
+            s: U256::from_dec_str(
+                "46948507304638947509940763649030358759909902576025900602547168820602576006531",
+            )
+            .unwrap(),
+            recid: 0,
+        };
+        assert_eq!(
+            hex::encode(tx.encode_signed(&signature).unwrap().0),
+            EIP155_EXAMPLE
+        );
+    }
+
+    #[test]
+    fn eip1559_tx_matches_web3_signing() {
+        let access_list = vec![AccessListItem {
+            address: Address::from_low_u64_be(0x42),
+            storage_keys: vec![H256::from_low_u64_be(1)],
+        }];
+        let tx = UnsignedTx {
+            chain_id: 5,
+            nonce: U256::from(7),
+            gas: U256::from(60_000),
+            to: Some(Address::from_low_u64_be(0x42)),
+            value: U256::from(1_000),
+            data: Bytes(vec![1, 2, 3]),
+            fees: Fees::Eip1559 {
+                max_fee_per_gas: U256::from(30_000_000_000u64),
+                max_priority_fee_per_gas: U256::from(2_000_000_000u64),
+                access_list: access_list.clone(),
+            },
+        };
+
+        let web3 = Web3::new(transports::Http::new("http://127.0.0.1:8545").unwrap());
+        let params = TransactionParameters {
+            nonce: Some(tx.nonce),
+            to: tx.to,
+            gas: tx.gas,
+            value: tx.value,
+            data: tx.data.clone(),
+            chain_id: Some(tx.chain_id),
+            transaction_type: Some(U64::from(2)),
+            access_list: Some(access_list),
+            max_fee_per_gas: Some(U256::from(30_000_000_000u64)),
+            max_priority_fee_per_gas: Some(U256::from(2_000_000_000u64)),
+            ..Default::default()
+        };
+        let signed =
+            futures::executor::block_on(web3.accounts().sign_transaction(params, FixedKey))
+                .unwrap();
+        assert_eq!(tx.sighash(), signed.message_hash);
+
+        let signature = TxSignature {
+            r: U256::from_big_endian(signed.r.as_bytes()),
+            s: U256::from_big_endian(signed.s.as_bytes()),
+            recid: signed.v as u8,
+        };
+        assert_eq!(
+            tx.encode_signed(&signature).unwrap(),
+            signed.raw_transaction
+        );
+    }
+
+    #[test]
+    fn transactions_are_read_from_json() {
+        let tx: UnsignedTx = serde_json::from_str(
+            r#"{"type": "eip1559", "chain_id": 1, "nonce": "0x1", "gas": "0x5208",
+                "to": "0x3535353535353535353535353535353535353535", "value": "0x0",
+                "max_fee_per_gas": "0x2", "max_priority_fee_per_gas": "0x3"}"#,
+        )
+        .unwrap();
+        assert!(tx.validate().is_err());
+        assert!(serde_json::from_str::<UnsignedTx>(
+            r#"{"type": "legacy", "chain_id": 1, "nonce": "0x1", "gas": "0x5208",
+                "to": null, "value": "0x0", "data": "0x6001", "gas_price": "0x1"}"#,
+        )
+        .unwrap()
+        .validate()
+        .is_ok());
+    }
+
+    #[test]
+    fn legacy_tx_decodes_to_its_sender() {
+        let raw = hex::decode(EIP155_EXAMPLE).unwrap();
+        let signed = SignedTx::decode(&raw).unwrap();
+        assert_eq!(signed.tx.chain_id, 1);
+        assert_eq!(signed.tx.nonce, U256::from(9));
+        assert_eq!(
+            signed.from,
+            Address::from_str("9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f").unwrap()
+        );
+
+        let mut trailing = raw.clone();
+        trailing.push(0);
+        assert!(SignedTx::decode(&trailing).is_err());
+        assert!(SignedTx::decode(&raw[..raw.len() - 1]).is_err());
+        assert!(SignedTx::decode(&[]).is_err());
+        assert!(SignedTx::decode(&[3, 0xc0]).is_err());
+    }
+
+    #[test]
+    fn typed_txs_decode_to_their_sender() {
+        let key = SecretKey::from_slice(&[0x46; 32]).unwrap();
+        let web3 = Web3::new(transports::Http::new("http://127.0.0.1:8545").unwrap());
+        let access_list = vec![AccessListItem {
+            address: Address::from_low_u64_be(0x42),
+            storage_keys: vec![H256::from_low_u64_be(1)],
+        }];
+        for (tx_type, to) in [(1u64, None), (2, Some(Address::from_low_u64_be(0x42)))] {
+            let params = TransactionParameters {
+                nonce: Some(U256::from(3)),
+                to,
+                gas: U256::from(60_000),
+                gas_price: Some(U256::from(10_000_000_000u64)),
+                value: U256::from(1_000),
+                data: Bytes(vec![1, 2, 3]),
+                chain_id: Some(5),
+                transaction_type: Some(U64::from(tx_type)),
+                access_list: Some(access_list.clone()),
+                max_fee_per_gas: Some(U256::from(30_000_000_000u64)),
+                max_priority_fee_per_gas: Some(U256::from(2_000_000_000u64)),
+            };
+            let signed =
+                futures::executor::block_on(web3.accounts().sign_transaction(params, &key))
+                    .unwrap();
+
+            let decoded = SignedTx::decode(&signed.raw_transaction.0).unwrap();
+            assert_eq!(decoded.from, SecretKeyRef::new(&key).address());
+            assert_eq!(decoded.hash, signed.transaction_hash);
+            assert_eq!(decoded.tx.sighash(), signed.message_hash);
+            assert_eq!(decoded.tx.chain_id, 5);
+            assert_eq!(decoded.tx.to, to);
+            match decoded.tx.fees {
+                Fees::Eip2930 {
+                    access_list: list, ..
+                } if tx_type == 1 => {
+                    assert_eq!(list, access_list)
+                }
+                Fees::Eip1559 {
+                    access_list: list, ..
+                } if tx_type == 2 => {
+                    assert_eq!(list, access_list)
+                }
+                fees => panic!("type {} decoded to {:?}", tx_type, fees),
+            }
+        }
+    }
+
+    #[test]
+    fn nonces_are_checked_against_the_sender_counts() {
+        let signed = SignedTx::decode(&hex::decode(EIP155_EXAMPLE).unwrap()).unwrap();
+        // Nonce 9: the next one, a replacement of a pending one, used, gap
+        assert!(signed.check_nonce(U256::from(9), U256::from(9)).is_ok());
+        assert!(signed.check_nonce(U256::from(8), U256::from(11)).is_ok());
+        assert!(signed.check_nonce(U256::from(10), U256::from(10)).is_err());
+        assert!(signed.check_nonce(U256::from(7), U256::from(8)).is_err());
+    }
+
+    #[test]
+    fn addresses_of_public_keys() {
+        // The generator, public key of the private key 1
+        let public_key = hex::decode(
+            "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798\
+             483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8",
+        )
+        .unwrap();
+        assert_eq!(
+            wallets::address_of(&public_key).unwrap(),
+            Address::from_str("7e5f4552091a69125d5dfcb7b8c2659029395bdf").unwrap()
+        );
+        assert!(wallets::address_of(&public_key[1..]).is_err());
+    }
+}
+
+#[cfg(test)]
+mod intent_suites {
+    use std::time::Duration;
+
+    use uuid::Uuid;
+    use web3::types::{Address, Bytes, U256};
+
+    use crate::eth::intent;
+    use crate::eth::tx::{Fees, UnsignedTx};
+    use crate::storage::db::{self, DB};
+
+    fn unsigned_tx() -> UnsignedTx {
+        UnsignedTx {
+            chain_id: 1,
+            nonce: U256::zero(),
+            gas: U256::from(21_000),
+            to: Some(Address::from_low_u64_be(1)),
+            value: U256::one(),
+            data: Bytes::default(),
+            fees: Fees::Legacy {
+                gas_price: U256::one(),
+            },
+        }
+    }
+
+    #[test]
+    fn intents_are_signed_once_before_they_expire() {
+        let path = std::env::temp_dir().join(format!("intent-{}", Uuid::new_v4()));
+        let db = DB::Local(db::open(&path).unwrap());
+        let tx = unsigned_tx();
+        let from = Address::from_low_u64_be(2);
+
+        let created =
+            intent::create(&db, "alice", from, tx.clone(), Duration::from_secs(60)).unwrap();
+        assert!(intent::bind(&db, "bob", "w1", Some(&created.id)).is_err());
+        intent::bind(&db, "alice", "w1", Some(&created.id)).unwrap();
+        let bound = intent::bound(&db, "alice", "w1").unwrap().unwrap();
+        assert_eq!(bound.id, created.id);
+        assert_eq!(bound.wallet.as_deref(), Some("w1"));
+
+        intent::consume(&db, "alice", "w1", &created).unwrap();
+        assert_eq!(intent::bound(&db, "alice", "w1").unwrap(), None);
+        assert!(intent::bind(&db, "alice", "w1", Some(&created.id)).is_err());
+
+        let expired = intent::create(&db, "alice", from, tx, Duration::from_secs(0)).unwrap();
+        assert!(intent::bind(&db, "alice", "w1", Some(&expired.id)).is_err());
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn expired_and_abandoned_intents_are_cleaned_up() {
+        let path = std::env::temp_dir().join(format!("intent-{}", Uuid::new_v4()));
+        let db = DB::Local(db::open(&path).unwrap());
+        let from = Address::from_low_u64_be(2);
+        let ttl = Duration::from_secs(60);
+
+        // Abandoning a bound intent clears the binding of its wallet
+        let abandoned = intent::create(&db, "alice", from, unsigned_tx(), ttl).unwrap();
+        intent::bind(&db, "alice", "w1", Some(&abandoned.id)).unwrap();
+        intent::abandon(&db, "alice", &abandoned.id).unwrap();
+        assert_eq!(intent::bound(&db, "alice", "w1").unwrap(), None);
+
+        // Rebinding an intent moves it from one wallet to the other
+        let moved = intent::create(&db, "alice", from, unsigned_tx(), ttl).unwrap();
+        intent::bind(&db, "alice", "w1", Some(&moved.id)).unwrap();
+        intent::bind(&db, "alice", "w2", Some(&moved.id)).unwrap();
+        assert_eq!(intent::bound(&db, "alice", "w1").unwrap(), None);
+
+        let kept = intent::create(&db, "bob", from, unsigned_tx(), ttl).unwrap();
+        let expires_at = moved.expires_at;
+        assert_eq!(intent::purge_expired(&db, expires_at - 1).unwrap(), 0);
+        assert_eq!(intent::purge_expired(&db, expires_at + 1).unwrap(), 2);
+        assert_eq!(intent::bound(&db, "alice", "w2").unwrap(), None);
+        assert!(intent::fetch(&db, "alice", &moved.id).is_err());
+        assert!(intent::fetch(&db, "bob", &kept.id).is_err());
+        assert_eq!(intent::purge_expired(&db, expires_at + 1).unwrap(), 0);
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[cfg(test)]
+mod amount_suites {
+    use proptest::prelude::*;
+    use web3::types::U256;
+
+    use crate::eth::amount::{format_units, parse_eth, parse_native, parse_units};
+
+    #[test]
+    fn amounts_are_exact() {
+        assert_eq!(parse_eth("0.1 ether").unwrap(), U256::exp10(17));
+        assert_eq!(parse_eth("30gwei").unwrap(), U256::from(30_000_000_000u64));
+        assert_eq!(parse_eth("1000").unwrap(), U256::from(1000));
+        assert_eq!(
+            parse_eth(" 1.50 ETH ").unwrap(),
+            U256::from(15) * U256::exp10(17)
+        );
+        assert_eq!(parse_units(".5", 6).unwrap(), U256::from(500_000));
+        assert_eq!(format_units(U256::from(1_500_000), 6), "1.5");
+        assert_eq!(format_units(U256::from(5), 6), "0.000005");
+
+        for invalid in ["", ".", "-1", "1.5", "1e18", "0x10", "1 finney", "1.0.0"] {
+            assert!(parse_eth(invalid).is_err(), "{}", invalid);
+        }
+        assert!(parse_units(&U256::max_value().to_string(), 0).is_ok());
+        assert!(parse_units(&format!("{}0", U256::max_value()), 0).is_err());
+        assert!(parse_units("1", 78).is_err());
+    }
+
+    #[test]
+    fn native_symbol_is_a_unit() {
+        assert_eq!(
+            parse_native("2 MATIC", "MATIC").unwrap(),
+            U256::from(2) * U256::exp10(18)
+        );
+        assert_eq!(
+            parse_native("30 gwei", "MATIC").unwrap(),
+            U256::from(30) * U256::exp10(9)
+        );
+        assert!(parse_eth("2 MATIC").is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn formatted_amounts_parse_back(words in any::<[u64; 4]>(), decimals in 0u32..40) {
+            let value = U256(words);
+            prop_assert_eq!(parse_units(&format_units(value, decimals), decimals).unwrap(), value);
+        }
+
+        #[test]
+        fn units_scale_exactly(amount in any::<u64>(), fraction in 0u32..1_000_000_000) {
+            let gwei = U256::from(amount) * U256::exp10(9) + U256::from(fraction);
+            let gwei_amount = format!("{}.{:09} gwei", amount, fraction);
+            let ether_amount = format!("{}.{:09}ether", amount, fraction);
+            prop_assert_eq!(parse_eth(&gwei_amount).unwrap(), gwei);
+            prop_assert_eq!(parse_eth(&ether_amount).unwrap(), gwei * U256::exp10(9));
+        }
+
+        #[test]
+        fn negative_amounts_are_rejected(amount in any::<u64>()) {
+            prop_assert!(parse_eth(&format!("-{} wei", amount)).is_err());
+        }
+
+        #[test]
+        fn extra_decimals_are_rejected(decimals in 0u32..30, digit in 1u8..10) {
+            let amount = format!("0.{}{}", "0".repeat(decimals as usize), digit);
+            prop_assert!(parse_units(&amount, decimals).is_err());
+            prop_assert!(parse_units(&amount, decimals + 1).is_ok());
+        }
+    }
+}
+
+#[cfg(test)]
+mod fee_suites {
+    use web3::types::{BlockNumber, FeeHistory, U256};
+
+    use crate::eth::fees::{self, FeeTier};
+
+    fn gwei(n: u64) -> U256 {
+        U256::from(n) * U256::exp10(9)
+    }
+
+    fn history(base_fee: u64, rewards: Vec<[u64; 3]>) -> FeeHistory {
+        FeeHistory {
+            oldest_block: BlockNumber::Number(100.into()),
+            base_fee_per_gas: vec![gwei(base_fee); rewards.len() + 1],
+            gas_used_ratio: vec![0.5; rewards.len()],
+            reward: Some(
+                rewards
+                    .into_iter()
+                    .map(|block| block.iter().map(|r| gwei(*r)).collect())
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn tiers_tip_the_median_of_their_percentile() {
+        let estimates = fees::estimate(&history(
+            30,
+            vec![[1, 2, 5], [0, 0, 0], [2, 3, 8], [1, 2, 4]],
+        ))
+        .unwrap();
+
+        assert_eq!(estimates.base_fee_per_gas, gwei(30));
+        assert_eq!(estimates.slow.max_priority_fee_per_gas, gwei(1));
+        assert_eq!(estimates.normal.max_priority_fee_per_gas, gwei(2));
+        let fast = estimates.tier(FeeTier::Fast);
+        assert_eq!(fast.max_priority_fee_per_gas, gwei(5));
+        assert_eq!(fast.max_fee_per_gas, gwei(65));
+    }
+
+    #[test]
+    fn empty_blocks_and_legacy_chains() {
+        let estimates = fees::estimate(&history(10, vec![[0, 0, 0]])).unwrap();
+        assert_eq!(estimates.normal.max_priority_fee_per_gas, gwei(1));
+        assert_eq!(estimates.normal.max_fee_per_gas, gwei(21));
+
+        assert_eq!(fees::estimate(&history(0, vec![[1, 2, 3]])), None);
+    }
+}
+
+#[cfg(test)]
+mod gas_suites {
+    use serde_json::json;
+    use web3::ethabi::{self, Token};
+    use web3::types::U256;
+
+    use crate::eth::gas;
+
+    fn rpc_error(error: serde_json::Value) -> web3::Error {
+        web3::Error::Rpc(serde_json::from_value(error).unwrap())
+    }
+
+    fn revert_data(selector: &str, token: Token) -> String {
+        format!("0x{}{}", selector, hex::encode(ethabi::encode(&[token])))
+    }
+
+    #[test]
+    fn margin_is_added_in_percent() {
+        assert_eq!(gas::with_margin(21_000.into(), 20), U256::from(25_200));
+        assert_eq!(gas::with_margin(21_000.into(), 0), U256::from(21_000));
+        assert_eq!(gas::with_margin(U256::MAX, 20), U256::MAX);
+    }
+
+    #[test]
+    fn reverts_carry_their_reason() {
+        let data = revert_data("08c379a0", Token::String("Insufficient balance".into()));
+        let revert = gas::revert_of(&rpc_error(json!({
+            "code": 3,
+            "message": "execution reverted: Insufficient balance",
+            "data": data,
+        })))
+        .unwrap();
+        assert_eq!(revert.reason.as_deref(), Some("Insufficient balance"));
+        assert_eq!(revert.data.unwrap().0, hex::decode(&data[2..]).unwrap());
+
+        let data = revert_data("4e487b71", Token::Uint(0x11.into()));
+        let revert = gas::revert_of(&rpc_error(json!({
+            "code": -32000,
+            "message": "execution reverted",
+            "data": { "data": data },
+        })))
+        .unwrap();
+        assert_eq!(revert.reason.as_deref(), Some("panic 0x11"));
+
+        let revert = gas::revert_of(&rpc_error(json!({
+            "code": -32000,
+            "message": "execution reverted: Ownable: caller is not the owner",
+        })))
+        .unwrap();
+        assert_eq!(
+            revert.reason.as_deref(),
+            Some("Ownable: caller is not the owner")
+        );
+        assert_eq!(revert.data, None);
+    }
+
+    #[test]
+    fn other_errors_are_not_reverts() {
+        let error = rpc_error(json!({
+            "code": -32000,
+            "message": "insufficient funds for gas * price + value",
+        }));
+        assert_eq!(gas::revert_of(&error), None);
+        assert_eq!(gas::revert_of(&web3::Error::Unreachable), None);
+        assert_eq!(gas::decode_reason(&[0x08, 0xc3]), None);
+    }
+}
+
+#[cfg(test)]
+mod erc20_suites {
+    use web3::types::{Address, U256};
+
+    use crate::eth::erc20;
+    use crate::routes::eth::EthTxParamsReqBody;
+
+    fn word(value: u64) -> Vec<u8> {
+        let mut word = [0u8; 32];
+        U256::from(value).to_big_endian(&mut word);
+        word.to_vec()
+    }
+
+    #[test]
+    fn transfer_call_data() {
+        let to: Address = "0x00000000000000000000000000000000000000aa"
+            .parse()
+            .unwrap();
+        let data = erc20::transfer(to, U256::from(1_500_000));
+        assert_eq!(
+            data.0,
+            [
+                hex::decode("a9059cbb").unwrap(),
+                word(0xaa),
+                word(1_500_000)
+            ]
+            .concat()
+        );
+        assert_eq!(
+            erc20::balance_of(to).0,
+            [hex::decode("70a08231").unwrap(), word(0xaa)].concat()
+        );
+        assert_eq!(erc20::decimals().0, hex::decode("313ce567").unwrap());
+    }
+
+    #[test]
+    fn decimals_output() {
+        assert_eq!(erc20::decode_decimals(&word(6)).unwrap(), 6);
+        assert!(erc20::decode_decimals(&word(256)).is_err());
+        assert!(erc20::decode_decimals(&[]).is_err());
+    }
+
+    #[test]
+    fn token_amounts_use_the_token_decimals() {
+        let body: EthTxParamsReqBody = serde_json::from_value(serde_json::json!({
+            "from_address": "0x00000000000000000000000000000000000000aa",
+            "to_address": "0x00000000000000000000000000000000000000bb",
+            "token": "0x00000000000000000000000000000000000000cc",
+            "value": "12.5",
+        }))
+        .unwrap();
+        assert_eq!(body.token_amount(6).unwrap(), U256::from(12_500_000));
+        assert!(body.token_amount(0).is_err());
+    }
+}
+
+#[cfg(test)]
+mod pool_suites {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use anyhow::anyhow;
+    use web3::types::{Bytes, H256};
+
+    use crate::eth::broadcast::{self, ProviderResult};
+    use crate::eth::chains::ChainRegistry;
+    use crate::eth::pool::RpcPool;
+    use crate::telemetry::metrics::Metrics;
+    use crate::utils::settings::{ChainSettings, Profile, Settings};
+
+    fn pool(rpc_urls: &[&str]) -> RpcPool {
+        let mut settings = Settings::defaults(Profile::Test).eth;
+        settings.chains = vec![ChainSettings {
+            chain_id: settings.default_chain_id,
+            name: "devnet".to_string(),
+            rpc_urls: rpc_urls.iter().map(|url| url.to_string()).collect(),
+            broadcast_urls: Vec::new(),
+            native_symbol: "ETH".to_string(),
+            eip1559: true,
+            confirmations: 1,
+        }];
+        RpcPool::new(
+            &ChainRegistry::new(&settings),
+            Duration::from_secs(2),
+            Arc::new(Metrics::new().unwrap()),
+        )
+    }
+
+    /// An HTTP JSON-RPC node answering every call with `chain_id`, which
+    /// is what `eth_chainId` and `eth_blockNumber` both expect.
+    fn node(chain_id: u64) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 4096];
+                // One JSON object per request, the body ends with its brace
+                while !request.ends_with(b"}") {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let body = format!(r#"{{"jsonrpc":"2.0","id":0,"result":"{:#x}"}}"#, chain_id);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn failing_endpoints_are_marked_down_and_skipped() {
+        // Nothing listens on port 1
+        let pool = pool(&["http://127.0.0.1:1", &node(1337)]);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let web3 = pool.connection(1337).await.unwrap();
+            assert!(web3.eth().block_number().await.is_ok());
+            let health = &pool.health()["devnet"];
+            assert!(!health[0].up);
+            assert_eq!(health[0].consecutive_failures, 1);
+            assert!(health[0].last_error.is_some());
+            assert!(health[1].up);
+
+            let web3 = pool.connection(1337).await.unwrap();
+            assert!(web3.eth().block_number().await.is_ok());
+            let health = &pool.health()["devnet"];
+            assert_eq!(health[0].consecutive_failures, 1);
+            assert!(health[1].up);
+
+            assert!(pool.connection(1).await.is_err());
+        });
+    }
+
+    #[test]
+    fn endpoints_of_another_chain_are_refused() {
+        let refused = pool(&[&node(1)]);
+        let pool = pool(&[&node(1), &node(1337)]);
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            pool.connection(1337).await.unwrap();
+            let health = &pool.health()["devnet"];
+            assert!(!health[0].up);
+            assert_eq!(
+                health[0].last_error.as_deref(),
+                Some("Got invalid response: Endpoint serves chain 1, not 1337")
+            );
+            assert!(health[1].up);
+        });
+
+        let error = runtime.block_on(refused.connection(1337)).unwrap_err();
+        assert!(error.to_string().contains("serves chain 1, not 1337"));
+    }
+
+    #[test]
+    fn broadcast_reports_every_provider() {
+        let mut pool_settings = Settings::defaults(Profile::Test).eth;
+        pool_settings.chains = vec![ChainSettings {
+            chain_id: 1337,
+            name: "devnet".to_string(),
+            rpc_urls: vec!["http://127.0.0.1:1".to_string()],
+            broadcast_urls: vec!["http://localhost:2/v2/secret-key".to_string()],
+            native_symbol: "ETH".to_string(),
+            eip1559: true,
+            confirmations: 1,
+        }];
+        let pool = RpcPool::new(
+            &ChainRegistry::new(&pool_settings),
+            Duration::from_secs(2),
+            Arc::new(Metrics::new().unwrap()),
+        );
+        let health = &pool.health()["devnet"];
+        assert_eq!(health[1].provider, "http://localhost");
+        assert!(health[1].broadcast_only);
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let error = runtime
+            .block_on(broadcast::send(&pool, 1337, &Bytes(vec![0xc0])))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("http://127.0.0.1: "));
+        assert!(error.contains("http://localhost: "));
+        assert!(!error.contains("secret-key"));
+    }
+
+    #[test]
+    fn already_known_counts_as_accepted() {
+        let accepted = ProviderResult::new(
+            "https://a".to_string(),
+            Err(anyhow!("RPC error: Error {{ message: \"already known\" }}")),
+        );
+        assert!(accepted.accepted && accepted.already_known);
+        assert!(broadcast::is_already_known("Known transaction: 0xab"));
+        assert!(broadcast::is_already_known("ALREADY_EXISTS: AlreadyKnown"));
+
+        let rejected = ProviderResult::new("https://b".to_string(), Err(anyhow!("nonce too low")));
+        assert!(!rejected.accepted);
+        assert!(ProviderResult::new("https://c".to_string(), Ok(H256::zero())).accepted);
+    }
+}
+
+#[cfg(test)]
+mod tracking_suites {
+    use std::time::Duration;
+
+    use web3::types::{H256, U256};
+
+    use crate::eth::tracking::{self, Observation, TrackedTx, TxReceipt, TxStatus};
+    use crate::eth::tx::SignedTx;
+
+    use super::eth_suites::EIP155_EXAMPLE;
+
+    const SUBMITTED_AT: u64 = 1_000;
+    const DROP_AFTER: Duration = Duration::from_secs(600);
+
+    fn tracked() -> TrackedTx {
+        let signed = SignedTx::decode(&hex::decode(EIP155_EXAMPLE).unwrap()).unwrap();
+        TrackedTx::new(1, 3, &signed, SUBMITTED_AT)
+    }
+
+    fn mined_at(block_number: u64) -> TxReceipt {
+        TxReceipt {
+            block_number,
+            block_hash: H256::from_low_u64_be(block_number),
+            success: Some(true),
+            gas_used: Some(U256::from(21_000)),
+            effective_gas_price: None,
+            contract_address: None,
+        }
+    }
+
+    #[test]
+    fn transactions_are_confirmed_after_the_chain_confirmations() {
+        let mut tx = tracked();
+        assert_eq!(tx.status, TxStatus::Pending);
+        assert_eq!(tx.nonce, U256::from(9));
+
+        assert!(!tx.update(Observation::Pending, DROP_AFTER, SUBMITTED_AT + 15));
+        assert_eq!(tx.last_seen_at, SUBMITTED_AT + 15);
+
+        let mined = |head| Observation::Mined {
+            receipt: mined_at(100),
+            head,
+        };
+        assert!(tx.update(mined(100), DROP_AFTER, SUBMITTED_AT + 30));
+        assert_eq!((tx.status, tx.confirmations), (TxStatus::Mined, 1));
+        assert_eq!(tx.updated_at, SUBMITTED_AT + 30);
+        assert!(!tx.update(mined(100), DROP_AFTER, SUBMITTED_AT + 45));
+        assert_eq!(tx.updated_at, SUBMITTED_AT + 30);
+
+        assert!(tx.update(mined(102), DROP_AFTER, SUBMITTED_AT + 60));
+        assert_eq!((tx.status, tx.confirmations), (TxStatus::Confirmed, 3));
+        assert!(tx.status.is_final());
+    }
+
+    #[test]
+    fn reorganized_transactions_are_pending_again() {
+        let mut tx = tracked();
+        tx.update(
+            Observation::Mined {
+                receipt: mined_at(100),
+                head: 100,
+            },
+            DROP_AFTER,
+            SUBMITTED_AT + 15,
+        );
+        assert!(tx.update(Observation::Pending, DROP_AFTER, SUBMITTED_AT + 30));
+        assert_eq!(tx.status, TxStatus::Pending);
+        assert_eq!(tx.receipt, None);
+        assert_eq!(tx.confirmations, 0);
+    }
+
+    #[test]
+    fn unknown_transactions_are_dropped_or_replaced() {
+        let unknown = Observation::Unknown { nonce_used: false };
+        let mut tx = tracked();
+        // Not yet propagated
+        assert!(!tx.update(unknown.clone(), DROP_AFTER, SUBMITTED_AT + 599));
+        assert_eq!(tx.status, TxStatus::Pending);
+        assert!(tx.update(unknown, DROP_AFTER, SUBMITTED_AT + 600));
+        assert_eq!(tx.status, TxStatus::Dropped);
+        assert!(tx.status.is_final());
+
+        let mut tx = tracked();
+        assert!(tx.update(
+            Observation::Unknown { nonce_used: true },
+            DROP_AFTER,
+            SUBMITTED_AT + 15
+        ));
+        assert_eq!(tx.status, TxStatus::Replaced);
+    }
+
+    #[test]
+    fn statuses_are_serialized_in_snake_case() {
+        let json = serde_json::to_value(tracked()).unwrap();
+        assert_eq!(json["status"], "pending");
+        assert_eq!(json["required_confirmations"], 3);
+        assert!(!TxStatus::Mined.is_final());
+    }
+
+    #[test]
+    fn hashes_are_parsed_with_or_without_prefix() {
+        let hash = H256::from_low_u64_be(0xabc);
+        assert_eq!(tracking::parse_hash(&format!("{:?}", hash)).unwrap(), hash);
+        assert_eq!(tracking::parse_hash(&hex::encode(hash)).unwrap(), hash);
+        assert!(tracking::parse_hash("0x1234").is_err());
+    }
+}
+
+#[cfg(test)]
+mod nonce_suites {
+    use std::time::Duration;
+
+    use uuid::Uuid;
+    use web3::types::{Address, Bytes, U256};
+
+    use crate::eth::intent;
+    use crate::eth::nonces::{self, NonceCounts, NonceManager, Reservation};
+    use crate::eth::tx::{Fees, UnsignedTx};
+    use crate::storage::db::{self, DB};
+
+    const NOW: u64 = 1_000;
+
+    fn reserved(nonce: u64, expires_at: u64) -> Reservation {
+        Reservation {
+            nonce: U256::from(nonce),
+            intent_id: format!("intent-{}", nonce),
+            expires_at,
+        }
+    }
+
+    fn counts(mined: u64, pending: u64) -> NonceCounts {
+        NonceCounts {
+            mined: U256::from(mined),
+            pending: U256::from(pending),
+        }
+    }
+
+    #[test]
+    fn reservations_end_in_the_pool_or_at_expiry() {
+        let reservations = vec![
+            reserved(4, NOW + 60),
+            reserved(5, NOW),
+            reserved(6, NOW + 60),
+        ];
+        assert_eq!(
+            nonces::reconcile(reservations, counts(3, 5), NOW),
+            vec![reserved(6, NOW + 60)]
+        );
+    }
+
+    #[test]
+    fn the_lowest_free_nonce_is_reserved() {
+        let reservations = vec![reserved(5, NOW), reserved(6, NOW), reserved(8, NOW)];
+        assert_eq!(nonces::next_free(&[], counts(5, 5)), U256::from(5));
+        assert_eq!(
+            nonces::next_free(&reservations, counts(5, 5)),
+            U256::from(7)
+        );
+        assert_eq!(
+            nonces::next_free(&reservations, counts(5, 9)),
+            U256::from(9)
+        );
+    }
+
+    #[test]
+    fn gaps_are_free_nonces_below_reserved_ones() {
+        let reservations = vec![reserved(5, NOW), reserved(8, NOW)];
+        assert_eq!(
+            nonces::gaps(&reservations, counts(4, 4)),
+            vec![U256::from(4), U256::from(6), U256::from(7)]
+        );
+        assert!(nonces::gaps(&reservations[..1], counts(5, 5)).is_empty());
+        assert!(nonces::gaps(&[], counts(5, 5)).is_empty());
+    }
+
+    #[test]
+    fn intents_prepared_back_to_back_get_consecutive_nonces() {
+        let path = std::env::temp_dir().join(format!("nonces-{}", Uuid::new_v4()));
+        let db = DB::Local(db::open(&path).unwrap());
+        let manager = NonceManager::default();
+        let from = Address::from_low_u64_be(2);
+        let create = |nonce| {
+            intent::create(
+                &db,
+                "alice",
+                from,
+                UnsignedTx {
+                    chain_id: 1,
+                    nonce,
+                    gas: U256::from(21_000),
+                    to: Some(Address::from_low_u64_be(1)),
+                    value: U256::one(),
+                    data: Bytes::default(),
+                    fees: Fees::Legacy {
+                        gas_price: U256::one(),
+                    },
+                },
+                Duration::from_secs(60),
+            )
+        };
+
+        let (first, gaps) = manager
+            .reserve(&db, "alice", 1, from, counts(3, 3), create)
+            .unwrap();
+        assert_eq!((first.tx.nonce, gaps), (U256::from(3), vec![]));
+        let (second, _) = manager
+            .reserve(&db, "alice", 1, from, counts(3, 3), create)
+            .unwrap();
+        assert_eq!(second.tx.nonce, U256::from(4));
+        // Other users and chains have their own nonces
+        let (other, _) = manager
+            .reserve(&db, "alice", 5, from, counts(3, 3), create)
+            .unwrap();
+        assert_eq!(other.tx.nonce, U256::from(3));
+        let (other, _) = manager
+            .reserve(&db, "bob", 1, from, counts(3, 3), create)
+            .unwrap();
+        assert_eq!(other.tx.nonce, U256::from(3));
+
+        intent::abandon(&db, "alice", &first.id).unwrap();
+        manager.release(&db, "alice", &first).unwrap();
+        let (third, gaps) = manager
+            .reserve(&db, "alice", 1, from, counts(3, 3), create)
+            .unwrap();
+        assert_eq!((third.tx.nonce, gaps), (U256::from(3), vec![]));
+
+        // Nonce 3 reached the pool
+        let (fourth, _) = manager
+            .reserve(&db, "alice", 1, from, counts(3, 4), create)
+            .unwrap();
+        assert_eq!(fourth.tx.nonce, U256::from(5));
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[cfg(test)]
+mod replace_suites {
+    use web3::types::{AccessListItem, Address, Bytes, U256};
+
+    use crate::eth::fees::FeeEstimate;
+    use crate::eth::replace::{self, Replacement};
+    use crate::eth::tx::{Fees, UnsignedTx};
+
+    fn gwei(n: u64) -> U256 {
+        U256::from(n) * U256::exp10(9)
+    }
+
+    fn eip1559_tx() -> UnsignedTx {
+        UnsignedTx {
+            chain_id: 1,
+            nonce: U256::from(7),
+            gas: U256::from(60_000),
+            to: Some(Address::from_low_u64_be(0x42)),
+            value: U256::from(1_000),
+            data: Bytes(vec![1, 2, 3]),
+            fees: Fees::Eip1559 {
+                max_fee_per_gas: gwei(30),
+                max_priority_fee_per_gas: gwei(2),
+                access_list: vec![AccessListItem {
+                    address: Address::from_low_u64_be(0x42),
+                    storage_keys: vec![],
+                }],
+            },
+        }
+    }
+
+    #[test]
+    fn prices_are_bumped_by_ten_percent_rounded_up() {
+        assert_eq!(replace::bump(U256::from(100)), U256::from(110));
+        assert_eq!(replace::bump(U256::from(101)), U256::from(112));
+        assert_eq!(replace::bump(U256::zero()), U256::zero());
+        assert_eq!(replace::bump(U256::MAX), U256::MAX);
+    }
+
+    #[test]
+    fn replacements_pay_the_bump_or_the_current_fees() {
+        let tx = eip1559_tx();
+        let low = FeeEstimate {
+            max_fee_per_gas: gwei(20),
+            max_priority_fee_per_gas: gwei(1),
+        };
+        match replace::bumped_fees(&tx.fees, Some(&low), gwei(10)) {
+            Fees::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+                ..
+            } => {
+                assert_eq!(max_fee_per_gas, gwei(33));
+                assert_eq!(max_priority_fee_per_gas, U256::from(2_200_000_000u64));
+            }
+            fees => panic!("{:?}", fees),
+        }
+
+        let high = FeeEstimate {
+            max_fee_per_gas: gwei(50),
+            max_priority_fee_per_gas: gwei(3),
+        };
+        match replace::bumped_fees(&tx.fees, Some(&high), gwei(10)) {
+            Fees::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+                ..
+            } => assert_eq!(
+                (max_fee_per_gas, max_priority_fee_per_gas),
+                (gwei(50), gwei(3))
+            ),
+            fees => panic!("{:?}", fees),
+        }
+
+        let legacy = Fees::Legacy {
+            gas_price: gwei(10),
+        };
+        assert_eq!(
+            replace::bumped_fees(&legacy, None, gwei(5)),
+            Fees::Legacy {
+                gas_price: gwei(11)
+            }
+        );
+        assert_eq!(
+            replace::bumped_fees(&legacy, None, gwei(15)),
+            Fees::Legacy {
+                gas_price: gwei(15)
+            }
+        );
+    }
+
+    #[test]
+    fn speed_ups_keep_the_call_and_cancels_send_nothing_to_the_sender() {
+        let tx = eip1559_tx();
+        let from = Address::from_low_u64_be(0x99);
+        let fees = replace::bumped_fees(&tx.fees, None, U256::zero());
+
+        let speed_up = replace::replace(&tx, from, Replacement::SpeedUp, fees.clone());
+        assert_eq!(
+            speed_up,
+            UnsignedTx {
+                fees: fees.clone(),
+                ..tx.clone()
+            }
+        );
+
+        let cancel = replace::replace(&tx, from, Replacement::Cancel, fees);
+        assert_eq!(cancel.nonce, tx.nonce);
+        assert_eq!(cancel.to, Some(from));
+        assert_eq!(cancel.value, U256::zero());
+        assert_eq!(cancel.data, Bytes::default());
+        assert_eq!(cancel.gas, U256::from(21_000));
+        assert!(cancel.validate().is_ok());
+        match cancel.fees {
+            Fees::Eip1559 { access_list, .. } => assert!(access_list.is_empty()),
+            fees => panic!("{:?}", fees),
+        }
+    }
+}
+
+#[cfg(test)]
+mod message_suites {
+    use std::str::FromStr;
+
+    use secp256k1::SecretKey;
+    use web3::signing::{keccak256, Key, SecretKeyRef};
+    use web3::types::{Address, H256, U256};
+
+    use crate::eth::eip712::TypedData;
+    use crate::eth::messages;
+    use crate::eth::tx::TxSignature;
+    use crate::policy::{MessageSummary, Policy};
+
+    /// The example of EIP-712.
+    const MAIL: &str = r#"{
+        "types": {
+            "EIP712Domain": [
+                {"name": "name", "type": "string"},
+                {"name": "version", "type": "string"},
+                {"name": "chainId", "type": "uint256"},
+                {"name": "verifyingContract", "type": "address"}
+            ],
+            "Person": [
+                {"name": "name", "type": "string"},
+                {"name": "wallet", "type": "address"}
+            ],
+            "Mail": [
+                {"name": "from", "type": "Person"},
+                {"name": "to", "type": "Person"},
+                {"name": "contents", "type": "string"}
+            ]
+        },
+        "primaryType": "Mail",
+        "domain": {
+            "name": "Ether Mail",
+            "version": "1",
+            "chainId": 1,
+            "verifyingContract": "0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC"
+        },
+        "message": {
+            "from": {"name": "Cow", "wallet": "0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826"},
+            "to": {"name": "Bob", "wallet": "0xbBbBBBBbbBBBbbbBbbBbbbbBBbBbbbbBbBbbBBbB"},
+            "contents": "Hello, Bob!"
+        }
+    }"#;
+
+    fn mail() -> TypedData {
+        serde_json::from_str(MAIL).unwrap()
+    }
+
+    #[test]
+    fn personal_messages_are_prefixed() {
+        assert_eq!(
+            messages::personal_digest(b"hello world"),
+            H256::from_str("d9eba16ed0ecae432b71fe008c98cc872bb4cc214d3220a36f365326cf807d68")
+                .unwrap()
+        );
+        assert_eq!(
+            messages::personal_summary(&[0xff, 0x00]),
+            MessageSummary::Personal {
+                text: None,
+                length: 2
+            }
+        );
+    }
+
+    #[test]
+    fn typed_data_digest_matches_the_eip() {
+        let mail = mail();
+        assert_eq!(
+            mail.encode_type("Mail").unwrap(),
+            "Mail(Person from,Person to,string contents)Person(string name,address wallet)"
+        );
+        assert_eq!(
+            mail.hash_struct("EIP712Domain", &mail.domain).unwrap(),
+            H256::from_str("f2cee375fa42b42143804025fc449deafd50cc031ca257e0b194a650a912090f")
+                .unwrap()
+        );
+        assert_eq!(
+            mail.digest().unwrap(),
+            H256::from_str("be609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2")
+                .unwrap()
+        );
+
+        // Without its type, the domain is hashed from the fields it has
+        let mut inferred = mail.clone();
+        inferred.types.remove("EIP712Domain");
+        assert_eq!(inferred.digest().unwrap(), mail.digest().unwrap());
+
+        assert_eq!(
+            mail.summary().unwrap(),
+            MessageSummary::TypedData {
+                primary_type: "Mail".to_string(),
+                name: Some("Ether Mail".to_string()),
+                version: Some("1".to_string()),
+                chain_id: Some(1),
+                verifying_contract: Some(
+                    Address::from_str("CcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC").unwrap()
+                ),
+            }
+        );
+    }
+
+    #[test]
+    fn typed_data_values_are_checked() {
+        let typed_data = |kind: &str, value: &str| -> TypedData {
+            serde_json::from_str(&format!(
+                r#"{{
+                    "types": {{"Value": [{{"name": "value", "type": "{}"}}]}},
+                    "primaryType": "Value",
+                    "domain": {{"name": "Test"}},
+                    "message": {{"value": {}}}
+                }}"#,
+                kind, value
+            ))
+            .unwrap()
+        };
+
+        assert!(typed_data("uint8", "255").digest().is_ok());
+        assert!(typed_data("uint8", "256").digest().is_err());
+        assert!(typed_data("uint256", r#""0x10""#).digest().is_ok());
+        assert!(typed_data("int8", "-128").digest().is_ok());
+        assert!(typed_data("int8", "128").digest().is_err());
+        assert!(typed_data("bytes4", r#""0x01020304""#).digest().is_ok());
+        assert!(typed_data("bytes4", r#""0x0102""#).digest().is_err());
+        assert!(typed_data("uint8[2]", "[1, 2]").digest().is_ok());
+        assert!(typed_data("uint8[2]", "[1]").digest().is_err());
+        assert!(typed_data("Missing", "{}").digest().is_err());
+
+        // Negative numbers are the same as JSON numbers or strings
+        assert_eq!(
+            typed_data("int256", "-1").digest().unwrap(),
+            typed_data("int256", r#""-0x1""#).digest().unwrap()
+        );
+    }
+
+    #[test]
+    fn signatures_are_65_bytes() {
+        let key = SecretKey::from_slice(&keccak256(b"cow")).unwrap();
+        let key = SecretKeyRef::new(&key);
+        let digest = mail().digest().unwrap();
+        let signature = key.sign_message(digest.as_bytes()).unwrap();
+        let signature = TxSignature {
+            r: U256::from(signature.r.as_bytes()),
+            s: U256::from(signature.s.as_bytes()),
+            recid: signature.v as u8,
+        };
+
+        assert_eq!(signature.recover(digest).unwrap(), key.address());
+        assert_eq!(
+            hex::encode(messages::signature_bytes(&signature).unwrap().0),
+            "4355c47d63924e8a72e509b65029052eb6c299d53a04e167c5775fd466751c9d\
+             07299936d304c153f6443dfa05f40ff007d72911b6f72307f996231605b91562\
+             1c"
+        );
+        assert!(messages::signature_bytes(&TxSignature {
+            recid: 2,
+            ..signature
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn messages_need_to_be_allowed_by_policies() {
+        let personal = messages::personal_summary(b"login");
+        let permit = mail().summary().unwrap();
+        assert!(Policy::default().evaluate_message(&personal).allowed);
+
+        let policy: Policy =
+            serde_json::from_str(r#"{"rules": [{"rule": "chain_ids", "ids": [1]}]}"#).unwrap();
+        assert!(!policy.evaluate_message(&personal).allowed);
+
+        let policy: Policy = serde_json::from_str(
+            r#"{"rules": [
+                {"rule": "allow_messages"},
+                {"rule": "chain_ids", "ids": [137]},
+                {"rule": "deny_destinations", "addresses": ["0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC"]}
+            ]}"#,
+        )
+        .unwrap();
+        assert!(policy.evaluate_message(&personal).allowed);
+        assert_eq!(policy.evaluate_message(&permit).violations.len(), 2);
+    }
+}
+
+#[cfg(test)]
+mod siwe_suites {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use rocket::http::{ContentType, Status};
+    use rocket::local::blocking::Client;
+    use rocket::State;
+    use secp256k1::SecretKey;
+    use serde_json::json;
+    use uuid::Uuid;
+    use web3::signing::{keccak256, Key, SecretKeyRef};
+    use web3::types::Address;
+
+    use crate::auth::guards::{AuthPayload, Unauthenticated};
+    use crate::auth::siwe::{self, SiweAuth, SiweMessage};
+    use crate::eth::messages;
+    use crate::server;
+    use crate::storage::db::{self, DB};
+    use crate::utils::requests::validate_auth_token;
+    use crate::utils::settings::{Profile, Settings, SiweSettings};
+    use crate::AppConfig;
+
+    /// The example of EIP-4361.
+    const EXAMPLE: &str = "service.invalid wants you to sign in with your Ethereum account:
+0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2
+
+I accept the ServiceOrg Terms of Service: https://service.invalid/tos
+
+URI: https://service.invalid/login
+Version: 1
+Chain ID: 1
+Nonce: 32891756
+Issued At: 2021-09-30T16:25:24Z
+Resources:
+- ipfs://bafybeiemxf5abjwjbikoz4mc3a3dla6ual3jsgpdr4cjr3oz3evfyavhwq/
+- https://example.com/my-web2-claim.json";
+
+    /// 2021-10-01T00:00:00Z
+    const OCTOBER_2021: u64 = 1_633_046_400;
+
+    pub(super) fn message(address: &str, nonce: &str, extra: &str) -> String {
+        format!(
+            "https://wallet.example.com wants you to sign in with your Ethereum account:\n{}\n\n\nURI: https://wallet.example.com/login\nVersion: 1\nChain ID: 1\nNonce: {}\nIssued At: 2021-09-30T16:25:24Z{}",
+            address, nonce, extra
+        )
+    }
+
+    #[test]
+    fn messages_are_parsed() {
+        let parsed: SiweMessage = EXAMPLE.parse().unwrap();
+        assert_eq!(parsed.domain, "service.invalid");
+        assert_eq!(
+            parsed.address,
+            Address::from_str("C02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").unwrap()
+        );
+        assert_eq!(
+            parsed.statement.as_deref(),
+            Some("I accept the ServiceOrg Terms of Service: https://service.invalid/tos")
+        );
+        assert_eq!(parsed.uri, "https://service.invalid/login");
+        assert_eq!(parsed.chain_id, 1);
+        assert_eq!(parsed.nonce, "32891756");
+        assert_eq!(parsed.issued_at.unix_timestamp(), 1_633_019_124);
+        assert_eq!(parsed.resources.len(), 2);
+
+        let address = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
+        let parsed: SiweMessage = message(address, "abcdefgh", "\nRequest ID: 7")
+            .parse()
+            .unwrap();
+        assert_eq!(parsed.domain, "wallet.example.com");
+        assert_eq!(parsed.statement, None);
+        assert_eq!(parsed.request_id.as_deref(), Some("7"));
+    }
+
+    #[test]
+    fn invalid_messages_are_rejected() {
+        let address = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
+        assert!(message(address, "abcdefgh", "")
+            .parse::<SiweMessage>()
+            .is_ok());
+        // Not checksummed
+        assert!(message(&address.to_lowercase(), "abcdefgh", "")
+            .parse::<SiweMessage>()
+            .is_err());
+        assert!(message(address, "short", "")
+            .parse::<SiweMessage>()
+            .is_err());
+        assert!(message(address, "abcdefgh", "\nUnknown: 1")
+            .parse::<SiweMessage>()
+            .is_err());
+        assert!(EXAMPLE
+            .replace("Version: 1", "Version: 2")
+            .parse::<SiweMessage>()
+            .is_err());
+        assert!(EXAMPLE
+            .replace("Issued At: 2021-09-30T16:25:24Z", "Issued At: yesterday")
+            .parse::<SiweMessage>()
+            .is_err());
+    }
+
+    #[test]
+    fn messages_are_checked_against_domain_and_time() {
+        let parsed: SiweMessage = EXAMPLE.parse().unwrap();
+        assert!(parsed.check("service.invalid", OCTOBER_2021).is_ok());
+        assert!(parsed.check("wallet.example.com", OCTOBER_2021).is_err());
+
+        let expiring: SiweMessage = EXAMPLE
+            .replace(
+                "Issued At: 2021-09-30T16:25:24Z",
+                "Issued At: 2021-09-30T16:25:24Z\nExpiration Time: 2021-10-01T00:00:00Z\nNot Before: 2021-09-30T18:00:00+02:00",
+            )
+            .parse()
+            .unwrap();
+        assert!(expiring.check("service.invalid", OCTOBER_2021 - 1).is_ok());
+        assert!(expiring.check("service.invalid", OCTOBER_2021).is_err());
+        assert!(expiring
+            .check("service.invalid", OCTOBER_2021 - 9 * 3600)
+            .is_err());
+    }
+
+    fn settings(nonce_key: Option<&str>) -> SiweSettings {
+        SiweSettings {
+            domain: Some("wallet.example.com".to_string()),
+            nonce_ttl_secs: 60,
+            session_ttl_secs: 3600,
+            nonce_key: nonce_key.map(String::from),
+        }
+    }
+
+    #[test]
+    fn nonces_are_signed_and_used_once() {
+        let path = std::env::temp_dir().join(format!("siwe-{}", Uuid::new_v4()));
+        let db = DB::Local(db::open(&path).unwrap());
+        let key = "ab".repeat(32);
+        let auth = SiweAuth::new(&settings(Some(&key))).unwrap();
+        let (nonce, expires_at) = auth.issue_nonce(Duration::from_secs(60), 1000);
+        assert_eq!(expires_at, 1060);
+        assert!(nonce.chars().all(|c| c.is_ascii_alphanumeric()));
+        // Another instance with the same key accepts it, once
+        let other = SiweAuth::new(&settings(Some(&key))).unwrap();
+        assert!(other.take_nonce(&db, &nonce, 1059).is_ok());
+        assert!(auth.take_nonce(&db, &nonce, 1059).is_err());
+
+        let (nonce, _) = auth.issue_nonce(Duration::from_secs(60), 1000);
+        assert!(auth.take_nonce(&db, &nonce, 1060).is_err());
+        assert!(auth.take_nonce(&db, "unknown1", 1000).is_err());
+        // A random key, or a later expiry, does not match the MAC
+        let random = SiweAuth::new(&settings(None)).unwrap();
+        assert!(random.take_nonce(&db, &nonce, 1000).is_err());
+        let extended = format!("{}{:016x}{}", &nonce[..16], 2000, &nonce[32..]);
+        assert!(auth.take_nonce(&db, &extended, 1000).is_err());
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn signed_messages_start_sessions() {
+        let path = std::env::temp_dir().join(format!("siwe-{}", Uuid::new_v4()));
+        let db = DB::Local(db::open(&path).unwrap());
+        let settings = settings(None);
+        let auth = SiweAuth::new(&settings).unwrap();
+        let key = SecretKey::from_slice(&keccak256(b"siwe")).unwrap();
+        let key = SecretKeyRef::new(&key);
+        let sign = |text: &str| {
+            let signature = key
+                .sign_message(messages::personal_digest(text.as_bytes()).as_bytes())
+                .unwrap();
+            let mut bytes = signature.r.as_bytes().to_vec();
+            bytes.extend_from_slice(signature.s.as_bytes());
+            bytes.push(27 + signature.v as u8);
+            bytes
+        };
+
+        let (nonce, _) = auth.issue_nonce(settings.nonce_ttl(), OCTOBER_2021);
+        let text = message(&siwe::checksum(key.address()), &nonce, "");
+        let parsed: SiweMessage = text.parse().unwrap();
+        let verified = auth
+            .verify(&settings, &parsed, &text, &sign(&text), OCTOBER_2021)
+            .unwrap();
+        assert_eq!(verified.address(), key.address());
+        let (token, session) = auth
+            .sign_in(&db, &settings, &verified, OCTOBER_2021)
+            .unwrap();
+        assert!(token.starts_with(siwe::SESSION_PREFIX));
+        assert_eq!(session.user_id, siwe::user_id(key.address()));
+        assert_eq!(session.expires_at, OCTOBER_2021 + 3600);
+        assert_eq!(
+            auth.session(&db, &token, OCTOBER_2021 + 1).unwrap(),
+            session
+        );
+        // The nonce is used up
+        assert!(auth
+            .sign_in(&db, &settings, &verified, OCTOBER_2021)
+            .is_err());
+
+        // Signed by another key, which leaves the nonce unused
+        let (nonce, _) = auth.issue_nonce(settings.nonce_ttl(), OCTOBER_2021);
+        let other = message("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2", &nonce, "");
+        let parsed: SiweMessage = other.parse().unwrap();
+        assert!(auth
+            .verify(&settings, &parsed, &other, &sign(&other), OCTOBER_2021)
+            .is_err());
+        assert!(auth.take_nonce(&db, &nonce, OCTOBER_2021).is_ok());
+
+        assert!(auth.session(&db, &token, OCTOBER_2021 + 3600).is_err());
+        assert!(auth.session(&db, &token, OCTOBER_2021 + 1).is_err());
+        assert!(auth.session(&db, "siwe.unknown", OCTOBER_2021).is_err());
+
+        // Used nonces and sessions are purged once expired
+        let (nonce, _) = auth.issue_nonce(settings.nonce_ttl(), OCTOBER_2021);
+        let text = message(&siwe::checksum(key.address()), &nonce, "");
+        let parsed: SiweMessage = text.parse().unwrap();
+        let verified = auth
+            .verify(&settings, &parsed, &text, &sign(&text), OCTOBER_2021)
+            .unwrap();
+        let (token, _) = auth
+            .sign_in(&db, &settings, &verified, OCTOBER_2021)
+            .unwrap();
+        assert_eq!(siwe::purge_expired(&db, OCTOBER_2021 + 59).unwrap(), 0);
+        assert_eq!(siwe::purge_expired(&db, OCTOBER_2021 + 60).unwrap(), 3);
+        assert!(auth.session(&db, &token, OCTOBER_2021 + 1).is_ok());
+        assert_eq!(siwe::purge_expired(&db, OCTOBER_2021 + 3600).unwrap(), 2);
+        assert!(auth.session(&db, &token, OCTOBER_2021 + 1).is_err());
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn unsigned_sign_ins_are_not_audited() {
+        let path = std::env::temp_dir().join(format!("siwe-audit-{}", Uuid::new_v4()));
+        let mut config = Settings::defaults(Profile::Test);
+        config.db.path = path.to_string_lossy().to_string();
+        config.features.eth_routes = false;
+        config.siwe = settings(None);
+        let client =
+            Client::tracked(server::build_server(config).unwrap()).expect("valid rocket instance");
+
+        let response = client.get("/auth/siwe/nonce").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let victim = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
+        let text = message(victim, body["nonce"].as_str().unwrap(), "");
+        let status = client
+            .post("/auth/siwe/verify")
+            .header(ContentType::JSON)
+            .body(
+                json!({ "message": text, "signature": format!("0x{}", "11".repeat(65)) })
+                    .to_string(),
+            )
+            .dispatch()
+            .status();
+        assert_eq!(status, Status::InternalServerError);
+
+        let state = client.rocket().state::<AppConfig>().unwrap();
+        let victim: Address = victim.parse().unwrap();
+        assert!(state
+            .audit
+            .user_events(&siwe::user_id(victim), None, 10)
+            .unwrap()
+            .is_empty());
+
+        drop(client);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    /// An HCMC that accepts every token.
+    fn hcmc() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 4096];
+                // Token checks are bodiless GETs
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                );
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn hcmc_tokens_cannot_act_as_wallet_users() {
+        let path = std::env::temp_dir().join(format!("siwe-hcmc-{}", Uuid::new_v4()));
+        let mut config = Settings::defaults(Profile::Test);
+        config.db.path = path.to_string_lossy().to_string();
+        config.features.eth_routes = false;
+        config.hcmc_host = hcmc();
+        config.siwe = settings(None);
+        let client =
+            Client::tracked(server::build_server(config).unwrap()).expect("valid rocket instance");
+        let state = State::get(client.rocket()).unwrap();
+        let payload = |user_id: String| AuthPayload {
+            token: "hcmc-token".to_string(),
+            user_id,
+            request_id: Uuid::new_v4().to_string(),
+        };
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            assert!(validate_auth_token(state, &payload("alice".to_string()))
+                .await
+                .is_ok());
+            let victim: Address = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
+                .parse()
+                .unwrap();
+            let err = validate_auth_token(state, &payload(siwe::user_id(victim)))
+                .await
+                .unwrap_err();
+            assert!(err.is::<Unauthenticated>());
+        });
+
+        drop(client);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
+
+#[cfg(test)]
+mod simulation_suites {
+    use web3::ethabi::{self, Token};
+    use web3::types::{Address, Bytes, H256, U256, U64};
+
+    use crate::eth::erc20;
+    use crate::eth::simulate::{self, AccountOverride, BalanceChange, StateOverrides};
+    use crate::eth::tx::{Fees, UnsignedTx};
+    use crate::policy::{Policy, SimulationOutcome, TxSummary};
+
+    fn address(n: u64) -> Address {
+        Address::from_low_u64_be(n)
+    }
+
+    fn tx(to: Option<Address>, value: u64, data: Bytes) -> UnsignedTx {
+        UnsignedTx {
+            chain_id: 1,
+            nonce: U256::zero(),
+            gas: U256::from(100_000),
+            to,
+            value: U256::from(value),
+            data,
+            fees: Fees::Eip1559 {
+                max_fee_per_gas: U256::from(30),
+                max_priority_fee_per_gas: U256::from(2),
+                access_list: Vec::new(),
+            },
+        }
+    }
+
+    fn transfer_from(from: Address, to: Address, amount: u64) -> Bytes {
+        let args = ethabi::encode(&[
+            Token::Address(from),
+            Token::Address(to),
+            Token::Uint(U256::from(amount)),
+        ]);
+        Bytes([&[0x23, 0xb8, 0x72, 0xdd][..], &args].concat())
+    }
+
+    #[test]
+    fn native_transfer_changes_both_balances() {
+        let changes =
+            simulate::balance_changes(address(1), &tx(Some(address(2)), 5, Bytes::default()));
+        assert_eq!(
+            changes,
+            vec![
+                BalanceChange {
+                    address: address(1),
+                    token: None,
+                    sent: U256::from(5),
+                    received: U256::zero(),
+                },
+                BalanceChange {
+                    address: address(2),
+                    token: None,
+                    sent: U256::zero(),
+                    received: U256::from(5),
+                },
+            ]
+        );
+        // Contract creation only shows what the sender sends
+        let changes = simulate::balance_changes(address(1), &tx(None, 5, Bytes::default()));
+        assert_eq!(changes.len(), 1);
+        assert!(
+            simulate::balance_changes(address(1), &tx(Some(address(2)), 0, Bytes::default()))
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn token_transfers_are_decoded() {
+        let token = address(9);
+        let data = erc20::transfer(address(2), U256::from(7));
+        let changes = simulate::balance_changes(address(1), &tx(Some(token), 0, data));
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].address, address(1));
+        assert_eq!(changes[0].token, Some(token));
+        assert_eq!(changes[0].sent, U256::from(7));
+        assert_eq!(changes[1].received, U256::from(7));
+
+        let data = transfer_from(address(3), address(2), 4);
+        let transfer = erc20::decode_transfer(&data.0).unwrap();
+        assert_eq!(transfer.from, Some(address(3)));
+        let changes = simulate::balance_changes(address(1), &tx(Some(token), 0, data));
+        assert_eq!(
+            changes
+                .iter()
+                .map(|change| (change.address, change.sent, change.received))
+                .collect::<Vec<_>>(),
+            vec![
+                (address(2), U256::zero(), U256::from(4)),
+                (address(3), U256::from(4), U256::zero()),
+            ]
+        );
+
+        assert!(erc20::decode_transfer(&[0x23, 0xb8]).is_none());
+        assert!(erc20::decode_transfer(&erc20::balance_of(address(1)).0).is_none());
+    }
+
+    #[test]
+    fn false_returns_are_failures() {
+        let output = |value| ethabi::encode(&[Token::Bool(value)]);
+        assert!(erc20::returned_false(&output(false)));
+        assert!(!erc20::returned_false(&output(true)));
+        // Tokens returning nothing are not failures
+        assert!(!erc20::returned_false(&[]));
+    }
+
+    #[test]
+    fn call_request_carries_fees() {
+        let tx = tx(Some(address(2)), 5, Bytes(vec![1, 2]));
+        assert_eq!(simulate::max_fee(&tx), U256::from(3_000_000));
+        let request = simulate::call_request(address(1), &tx);
+        assert_eq!(request.from, Some(address(1)));
+        assert_eq!(request.gas, Some(U256::from(100_000)));
+        assert_eq!(request.max_fee_per_gas, Some(U256::from(30)));
+        assert_eq!(request.transaction_type, Some(U64::from(2)));
+        assert_eq!(request.gas_price, None);
+    }
+
+    #[test]
+    fn state_overrides() {
+        let overrides: StateOverrides = serde_json::from_str(
+            r#"{"0x0000000000000000000000000000000000000001": {"balance": "0xde0b6b3a7640000"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(&overrides).unwrap(),
+            serde_json::json!({"0x0000000000000000000000000000000000000001": {"balance": "0xde0b6b3a7640000"}})
+        );
+        assert!(simulate::check_overrides(&overrides).is_ok());
+        assert!(serde_json::from_str::<StateOverrides>(
+            r#"{"0x0000000000000000000000000000000000000001": {"storage": {}}}"#
+        )
+        .is_err());
+
+        let slots = vec![(H256::zero(), H256::from_low_u64_be(1))]
+            .into_iter()
+            .collect();
+        let mut overrides = StateOverrides::new();
+        overrides.insert(
+            address(1),
+            AccountOverride {
+                state: Some(slots),
+                ..Default::default()
+            },
+        );
+        assert!(simulate::check_overrides(&overrides).is_ok());
+        overrides.get_mut(&address(1)).unwrap().state_diff = Some(Default::default());
+        assert!(simulate::check_overrides(&overrides).is_err());
+    }
+
+    #[test]
+    fn reverts_are_denied() {
+        let policy: Policy =
+            serde_json::from_str(r#"{"rules": [{"rule": "deny_reverts"}]}"#).unwrap();
+        let mut summary = TxSummary {
+            chain_id: 1,
+            to: Some(address(2)),
+            value: U256::zero(),
+            gas: U256::from(21_000),
+            token_transfer: None,
+            simulation: None,
+            hashed_by_server: true,
+        };
+        assert!(!policy.evaluate(Some(&summary), U256::zero()).allowed);
+        summary.simulation = Some(SimulationOutcome::Success);
+        assert!(policy.evaluate(Some(&summary), U256::zero()).allowed);
+        summary.simulation = Some(SimulationOutcome::Revert {
+            reason: "Insufficient balance".to_string(),
+        });
+        let decision = policy.evaluate(Some(&summary), U256::zero());
+        assert!(!decision.allowed);
+        assert_eq!(
+            decision.violations,
+            vec!["the transaction would revert: Insufficient balance".to_string()]
+        );
+    }
+}
@@ -1,8 +1,14 @@
+use std::time::Duration;
+
 use anyhow::{anyhow, Result};
+use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::RequestBuilder;
 use rocket::State;
 
-use crate::{auth::guards::AuthPayload, AppConfig};
+use crate::auth::guards::{AuthPayload, Unauthenticated};
+use crate::auth::siwe::{self, SESSION_PREFIX, USER_PREFIX};
+use crate::telemetry::request_id::REQUEST_ID_HEADER;
+use crate::AppConfig;
 
 pub struct HttpClient {
     c: reqwest::Client,
@@ -10,9 +16,18 @@ pub struct HttpClient {
 }
 
 impl HttpClient {
-    pub fn new(base_url: String) -> HttpClient {
+    /// `request_id` is forwarded to HCMC so both sides log the same id.
+    pub fn new(base_url: String, timeout: Duration, request_id: &str) -> HttpClient {
+        let mut headers = HeaderMap::new();
+        if let Ok(value) = HeaderValue::from_str(request_id) {
+            headers.insert(REQUEST_ID_HEADER, value);
+        }
         HttpClient {
-            c: reqwest::Client::new(),
+            c: reqwest::Client::builder()
+                .timeout(timeout)
+                .default_headers(headers)
+                .build()
+                .unwrap_or_default(),
             base_url,
         }
     }
@@ -26,24 +41,62 @@ pub async fn post(client: &HttpClient, path: &str) -> RequestBuilder {
     client.c.post(format!("{}{}", client.base_url, path))
 }
 
+/// Checks the token of `auth_payload` is valid for its user. Failures are
+/// [`Unauthenticated`] errors.
 pub async fn validate_auth_token(
     state: &State<AppConfig>,
     auth_payload: &AuthPayload,
 ) -> Result<()> {
-    let http_client = HttpClient::new(state.hcmc_api.clone());
-
-    let check_token_resp = get(&http_client, "/api/v1/storage/valid")
+    check_auth_token(state, auth_payload)
         .await
-        .bearer_auth(&auth_payload.token)
-        .send()
-        .await?;
+        .map_err(|e| Unauthenticated(format!("{:#}", e)).into())
+}
 
-    if !check_token_resp.status().is_success() {
+async fn check_auth_token(state: &State<AppConfig>, auth_payload: &AuthPayload) -> Result<()> {
+    // Sessions of Sign-In with Ethereum are the server's own
+    if auth_payload.token.starts_with(SESSION_PREFIX) {
+        let session = state
+            .siwe
+            .session(&state.db, &auth_payload.token, siwe::now()?)?;
+        if session.user_id != auth_payload.user_id {
+            return Err(anyhow!(
+                "The session is not of user {}",
+                auth_payload.user_id
+            ));
+        }
+        return Ok(());
+    }
+    // HCMC knows nothing of wallet users, any of its tokens would pass
+    if auth_payload.user_id.starts_with(USER_PREFIX) {
         return Err(anyhow!(
-            "Failed to validate user's token {:#?}",
-            check_token_resp.text().await?
+            "User {} signs in with Ethereum, send its session token",
+            auth_payload.user_id
         ));
     }
 
-    Ok(())
+    let http_client = HttpClient::new(
+        state.hcmc_api.clone(),
+        state.settings.timeouts.hcmc(),
+        &auth_payload.request_id,
+    );
+
+    state
+        .metrics
+        .observe_outbound("hcmc", "validate_token", async {
+            let check_token_resp = get(&http_client, "/api/v1/storage/valid")
+                .await
+                .bearer_auth(&auth_payload.token)
+                .send()
+                .await?;
+
+            if !check_token_resp.status().is_success() {
+                return Err(anyhow!(
+                    "Failed to validate user's token {:#?}",
+                    check_token_resp.text().await?
+                ));
+            }
+
+            Ok(())
+        })
+        .await
 }
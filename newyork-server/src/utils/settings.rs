@@ -1,7 +1,559 @@
-#[derive(Deserialize, Debug)]
-pub struct AppEnv {
+//! Layered server configuration.
+//!
+//! Values are resolved from, in increasing priority:
+//! 1. built-in defaults of the selected profile,
+//! 2. the `[default]`, `[<profile>]` and `[global]` tables of `Settings.toml`,
+//! 3. the legacy `.env.<profile>` file (`HCMC_HOST`, `ALCHEMY_API`, `BACKUP_*`, `RUST_LOG`),
+//! 4. `NYC_*` environment variables, nested with `__` (`NYC_TIMEOUTS__RPC_SECS=5`),
+//! 5. command line flags (`--profile prod`, `--config ./nyc.toml`, `--db.path=./db`).
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use reqwest::Url;
+use rocket::figment::providers::{Format, Serialized, Toml};
+use rocket::figment::value::Value;
+use rocket::figment::Figment;
+use serde::de;
+use tracing_subscriber::EnvFilter;
+
+use crate::storage::backup::BackupPolicy;
+
+const DEFAULT_CONFIG_FILE: &str = "Settings.toml";
+const ENV_PREFIX: &str = "NYC_";
+const PROFILE_ENV: &str = "NYC_PROFILE";
+const RPC_SCHEMES: &[&str] = &["ws", "wss", "http", "https"];
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    Dev,
+    Staging,
+    Prod,
+    Test,
+}
+
+impl Profile {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Profile::Dev => "dev",
+            Profile::Staging => "staging",
+            Profile::Prod => "prod",
+            Profile::Test => "test",
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dev" => Ok(Profile::Dev),
+            "staging" => Ok(Profile::Staging),
+            "prod" => Ok(Profile::Prod),
+            "test" => Ok(Profile::Test),
+            _ => Err(anyhow!(
+                "Unknown profile '{}', expected one of dev, staging, prod, test",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Settings {
+    pub profile: Profile,
     pub hcmc_host: String,
-    pub alchemy_api: String,
+    pub db: DbSettings,
+    pub paillier: PaillierSettings,
+    pub timeouts: TimeoutSettings,
+    pub eth: EthSettings,
+    pub backup: BackupSettings,
+    pub siwe: SiweSettings,
+    pub features: FeatureSettings,
+    pub log: LogSettings,
+    pub otel: OtelSettings,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct DbSettings {
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct PaillierSettings {
+    /// Master keys whose Paillier modulus is shorter than this are refused
+    pub min_modulus_bits: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TimeoutSettings {
+    pub hcmc_secs: u64,
+    pub rpc_secs: u64,
+}
+
+impl TimeoutSettings {
+    pub fn hcmc(&self) -> Duration {
+        Duration::from_secs(self.hcmc_secs)
+    }
+
+    pub fn rpc(&self) -> Duration {
+        Duration::from_secs(self.rpc_secs)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct EthSettings {
+    /// RPC of the default chain when no `chains` are listed
+    pub rpc_url: String,
+    /// Chain of the requests without a `chain_id`
+    pub default_chain_id: u64,
+    #[serde(default)]
+    pub chains: Vec<ChainSettings>,
+    /// How long the parameters returned by `/eth/tx/params` can be signed
+    pub intent_ttl_secs: u64,
+    /// Added to `eth_estimateGas` results, in percent
+    pub gas_margin_percent: u64,
+    /// How often pooled RPC endpoints are checked and reconnected
+    pub probe_interval_secs: u64,
+    /// How often the status of sent transactions is refreshed
+    pub tracking_interval_secs: u64,
+    /// After how long a transaction no node knows is dropped
+    pub drop_after_secs: u64,
+}
+
+impl EthSettings {
+    pub fn intent_ttl(&self) -> Duration {
+        Duration::from_secs(self.intent_ttl_secs)
+    }
+
+    pub fn probe_interval(&self) -> Duration {
+        Duration::from_secs(self.probe_interval_secs)
+    }
+
+    pub fn tracking_interval(&self) -> Duration {
+        Duration::from_secs(self.tracking_interval_secs)
+    }
+
+    pub fn drop_after(&self) -> Duration {
+        Duration::from_secs(self.drop_after_secs)
+    }
+
+    fn chain_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (i, chain) in self.chains.iter().enumerate() {
+            let key = format!("eth.chains[{}]", i);
+            if self.chains[..i]
+                .iter()
+                .any(|other| other.chain_id == chain.chain_id)
+            {
+                problems.push(format!("{} repeats chain id {}", key, chain.chain_id));
+            }
+            if chain.name.trim().is_empty() || chain.native_symbol.trim().is_empty() {
+                problems.push(format!("{}.name and native_symbol must be set", key));
+            }
+            if chain.rpc_urls.is_empty() {
+                problems.push(format!("{}.rpc_urls is empty", key));
+            }
+            for url in &chain.rpc_urls {
+                if let Err(e) = check_url(&format!("{}.rpc_urls", key), url, RPC_SCHEMES) {
+                    problems.push(e);
+                }
+            }
+            for url in &chain.broadcast_urls {
+                if let Err(e) = check_url(&format!("{}.broadcast_urls", key), url, RPC_SCHEMES) {
+                    problems.push(e);
+                }
+            }
+        }
+        if !self
+            .chains
+            .iter()
+            .any(|chain| chain.chain_id == self.default_chain_id)
+        {
+            problems.push(format!(
+                "eth.default_chain_id {} is not in eth.chains",
+                self.default_chain_id
+            ));
+        }
+        problems
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ChainSettings {
+    pub chain_id: u64,
+    pub name: String,
+    /// `ws(s)` or `http(s)`, preferred in order
+    pub rpc_urls: Vec<String>,
+    /// Also sent every transaction, e.g. private relays
+    #[serde(default)]
+    pub broadcast_urls: Vec<String>,
+    pub native_symbol: String,
+    /// Price transactions with a base fee rather than a gas price
+    pub eip1559: bool,
+    /// Blocks after which a transaction is final
+    pub confirmations: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct BackupSettings {
+    /// Scheduled backups are disabled unless this is set
+    pub dir: Option<String>,
+    pub interval_secs: u64,
+    pub keep: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct SiweSettings {
+    /// Host SIWE messages must be for, e.g. `wallet.example.com`. Sign-In
+    /// with Ethereum is disabled unless this is set
+    pub domain: Option<String>,
+    /// How long a nonce from `/auth/siwe/nonce` can be signed
+    pub nonce_ttl_secs: u64,
+    pub session_ttl_secs: u64,
+    /// Hex key of at least 32 bytes signing the nonces, the same on every
+    /// instance. A random key is used when unset
+    pub nonce_key: Option<String>,
+}
+
+impl SiweSettings {
+    pub fn nonce_ttl(&self) -> Duration {
+        Duration::from_secs(self.nonce_ttl_secs)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct FeatureSettings {
+    /// Mount the `/eth` routes
+    pub eth_routes: bool,
+    /// Fetch a missing master key from the HCMC vault instead of failing
+    pub vault_fallback: bool,
+    /// Only sign hashes of transactions prepared by `/eth/tx/params`. Turn
+    /// off to let clients send raw hashes to `sign_second`.
+    pub require_tx_intent: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct LogSettings {
+    pub format: LogFormat,
+    /// `tracing` filter directives, e.g. `info,server_lib::storage=debug`
+    pub filter: String,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            format: LogFormat::Text,
+            filter: "info".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct OtelSettings {
+    /// OTLP/gRPC collector, e.g. `http://127.0.0.1:4317`. Traces are not exported unless this is set
+    pub endpoint: Option<String>,
+    pub service_name: String,
+}
+
+impl Settings {
+    pub fn defaults(profile: Profile) -> Self {
+        let local = matches!(profile, Profile::Dev | Profile::Test);
+        Settings {
+            profile,
+            hcmc_host: if local {
+                "http://127.0.0.1:8000".to_string()
+            } else {
+                String::new()
+            },
+            db: DbSettings {
+                path: match profile {
+                    Profile::Test => "./db-test".to_string(),
+                    _ => "./db".to_string(),
+                },
+            },
+            paillier: PaillierSettings {
+                min_modulus_bits: 2048,
+            },
+            timeouts: TimeoutSettings {
+                hcmc_secs: 10,
+                rpc_secs: 15,
+            },
+            eth: EthSettings {
+                rpc_url: if local {
+                    "ws://127.0.0.1:8546".to_string()
+                } else {
+                    String::new()
+                },
+                // Geth --dev and Ganache
+                default_chain_id: if local { 1337 } else { 1 },
+                chains: Vec::new(),
+                intent_ttl_secs: 10 * 60,
+                gas_margin_percent: 20,
+                probe_interval_secs: 30,
+                tracking_interval_secs: 15,
+                drop_after_secs: 30 * 60,
+            },
+            backup: BackupSettings {
+                dir: match profile {
+                    Profile::Prod => Some("./backups".to_string()),
+                    _ => None,
+                },
+                interval_secs: 6 * 60 * 60,
+                keep: 28,
+            },
+            siwe: SiweSettings {
+                domain: None,
+                nonce_ttl_secs: 5 * 60,
+                session_ttl_secs: 24 * 60 * 60,
+                nonce_key: None,
+            },
+            features: FeatureSettings {
+                eth_routes: true,
+                vault_fallback: true,
+                require_tx_intent: true,
+            },
+            log: LogSettings {
+                format: if local {
+                    LogFormat::Text
+                } else {
+                    LogFormat::Json
+                },
+                ..LogSettings::default()
+            },
+            otel: OtelSettings {
+                endpoint: None,
+                service_name: "newyork-server".to_string(),
+            },
+        }
+    }
+
+    /// Resolves the settings from every layer. `args` are the command line
+    /// arguments without the program name.
+    pub fn load(args: &[String]) -> Result<Self> {
+        let profile = CliArgs::parse(args)?.profile(&std::env::vars().collect())?;
+        let legacy_env = format!(".env.{}", profile);
+        if let Err(e) = dotenv::from_filename(&legacy_env) {
+            if !e.not_found() {
+                return Err(anyhow!("Failed to read {} ({})", legacy_env, e));
+            }
+        }
+        Self::from_sources(args, &std::env::vars().collect())
+    }
+
+    /// Resolves the settings from `args` and the environment variables of
+    /// `env`, without reading the process environment.
+    pub fn from_sources(args: &[String], env: &BTreeMap<String, String>) -> Result<Self> {
+        let cli = CliArgs::parse(args)?;
+        let profile = cli.profile(env)?;
+        let config_file = cli
+            .config
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_FILE));
+
+        let mut figment = Figment::from(Serialized::defaults(Settings::defaults(profile)))
+            .merge(Toml::file(&config_file).nested());
+        for (key, value) in env {
+            if let Some(key) = legacy_env_key(key) {
+                figment = figment.merge(Serialized::global(key, parse_value(value)));
+            }
+        }
+        for (key, value) in env {
+            if let Some(key) = key.strip_prefix(ENV_PREFIX) {
+                let key = key.to_lowercase().replace("__", ".");
+                if key != "profile" {
+                    figment = figment.merge(Serialized::global(&key, parse_value(value)));
+                }
+            }
+        }
+        figment = figment.select(profile.as_str());
+        for (key, value) in cli.overrides {
+            figment = figment.merge(Serialized::global(&key, value));
+        }
+
+        let mut settings: Settings = figment
+            .extract()
+            .map_err(|e| anyhow!("Invalid configuration: {}", e))?;
+        settings.profile = profile;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Checks every value and reports all problems at once.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        if let Err(e) = check_url("hcmc_host", &self.hcmc_host, &["http", "https"]) {
+            problems.push(e);
+        }
+        if self.eth.chains.is_empty() {
+            if let Err(e) = check_url("eth.rpc_url", &self.eth.rpc_url, RPC_SCHEMES) {
+                problems.push(e);
+            }
+        } else {
+            problems.extend(self.eth.chain_problems());
+        }
+        if self.db.path.trim().is_empty() {
+            problems.push("db.path must not be empty".to_string());
+        }
+        if self.paillier.min_modulus_bits < 2048 {
+            problems.push("paillier.min_modulus_bits must be at least 2048".to_string());
+        }
+        if self.timeouts.hcmc_secs == 0 || self.timeouts.rpc_secs == 0 {
+            problems.push("timeouts must be greater than 0".to_string());
+        }
+        if self.eth.intent_ttl_secs == 0
+            || self.eth.probe_interval_secs == 0
+            || self.eth.tracking_interval_secs == 0
+        {
+            problems.push(
+                "eth.intent_ttl_secs, eth.probe_interval_secs and eth.tracking_interval_secs must be greater than 0"
+                    .to_string(),
+            );
+        }
+        if self.backup.dir.is_some() && (self.backup.interval_secs == 0 || self.backup.keep == 0) {
+            problems
+                .push("backup.interval_secs and backup.keep must be greater than 0".to_string());
+        }
+        if let Some(domain) = &self.siwe.domain {
+            if domain.trim().is_empty() {
+                problems.push("siwe.domain must not be empty when set".to_string());
+            }
+            if self.siwe.nonce_ttl_secs == 0 || self.siwe.session_ttl_secs == 0 {
+                problems.push(
+                    "siwe.nonce_ttl_secs and siwe.session_ttl_secs must be greater than 0"
+                        .to_string(),
+                );
+            }
+        }
+        if let Some(key) = &self.siwe.nonce_key {
+            if !matches!(hex::decode(key), Ok(key) if key.len() >= 32) {
+                problems.push("siwe.nonce_key must be at least 32 bytes in hex".to_string());
+            }
+        }
+        if let Some(endpoint) = &self.otel.endpoint {
+            if let Err(e) = check_url("otel.endpoint", endpoint, &["http", "https"]) {
+                problems.push(e);
+            }
+        }
+        if let Err(e) = EnvFilter::try_new(&self.log.filter) {
+            problems.push(format!(
+                "log.filter '{}' is invalid ({})",
+                self.log.filter, e
+            ));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Invalid configuration for profile '{}':\n  - {}",
+                self.profile,
+                problems.join("\n  - ")
+            ))
+        }
+    }
+
+    pub fn backup_policy(&self) -> Option<BackupPolicy> {
+        self.backup.dir.as_ref().map(|dir| BackupPolicy {
+            dir: PathBuf::from(dir),
+            interval: Duration::from_secs(self.backup.interval_secs),
+            keep: self.backup.keep,
+        })
+    }
+}
+
+fn check_url(key: &str, value: &str, schemes: &[&str]) -> std::result::Result<(), String> {
+    if value.is_empty() {
+        return Err(format!("{} is not set", key));
+    }
+    match Url::parse(value) {
+        Ok(url) if schemes.contains(&url.scheme()) => Ok(()),
+        Ok(_) => Err(format!("{} must use one of {:?}", key, schemes)),
+        Err(e) => Err(format!("{} '{}' is not a valid URL ({})", key, value, e)),
+    }
+}
+
+/// The setting a variable of the legacy `.env.<profile>` files sets.
+fn legacy_env_key(key: &str) -> Option<&'static str> {
+    match key.to_lowercase().as_str() {
+        "hcmc_host" => Some("hcmc_host"),
+        "alchemy_api" => Some("eth.rpc_url"),
+        "backup_dir" => Some("backup.dir"),
+        "backup_interval_secs" => Some("backup.interval_secs"),
+        "backup_keep" => Some("backup.keep"),
+        "rust_log" => Some("log.filter"),
+        _ => None,
+    }
+}
+
+fn parse_value(value: &str) -> Value {
+    value.parse().expect("parsing a Value is infallible")
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct CliArgs {
+    profile: Option<String>,
+    config: Option<PathBuf>,
+    overrides: Vec<(String, Value)>,
+}
+
+impl CliArgs {
+    /// Accepts `--key value` and `--key=value`; dashes in keys become
+    /// underscores and dots select nested tables.
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut cli = CliArgs::default();
+        let mut it = args.iter();
+        while let Some(arg) = it.next() {
+            let flag = arg
+                .strip_prefix("--")
+                .ok_or_else(|| anyhow!("Unexpected argument '{}'", arg))?;
+            let (key, value) = match flag.split_once('=') {
+                Some((key, value)) => (key.to_string(), value.to_string()),
+                None => (
+                    flag.to_string(),
+                    it.next()
+                        .ok_or_else(|| anyhow!("--{} needs a value", flag))?
+                        .clone(),
+                ),
+            };
+            let key = key.replace('-', "_");
+            match key.as_str() {
+                "profile" => cli.profile = Some(value),
+                "config" => cli.config = Some(PathBuf::from(value)),
+                _ => cli.overrides.push((key, parse_value(&value))),
+            }
+        }
+        Ok(cli)
+    }
+
+    /// The profile of `--profile`, else of `NYC_PROFILE` in `env`.
+    fn profile(&self, env: &BTreeMap<String, String>) -> Result<Profile> {
+        self.profile
+            .as_deref()
+            .or_else(|| env.get(PROFILE_ENV).map(String::as_str))
+            .unwrap_or("staging")
+            .parse()
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -11,15 +563,20 @@ pub struct TestEnv {
     pub test_pass: String,
 }
 
-pub fn get_app_env<T>(file_name: &str) -> T
+/// Loads `file_name` into the process environment, when it exists, and reads `T` from it.
+/// A missing file is not an error so variables can also come from the container environment.
+pub fn get_app_env<T>(file_name: &str) -> Result<T>
 where
     T: de::DeserializeOwned,
 {
-    dotenv::from_filename(file_name).unwrap_or_else(|_| panic!("Failed to read {}", file_name));
-    match envy::from_env::<T>() {
-        Ok(config) => config,
-        Err(e) => panic!("Couldn't read app env config ({})", e),
+    if let Err(e) = dotenv::from_filename(file_name) {
+        if !e.not_found() {
+            return Err(anyhow!("Failed to read {} ({})", file_name, e));
+        }
+        warn!(
+            "{} not found, reading configuration from the environment only",
+            file_name
+        );
     }
+    envy::from_env::<T>().map_err(|e| anyhow!("Couldn't read app env config ({})", e))
 }
-
-use serde::de;